@@ -0,0 +1,293 @@
+//! Benchmarks of the Noise channels and handshakes, run them with `cargo bench`.
+//!
+//! They use criterion, which keeps the results of the previous run under `target/criterion`
+//! and reports the change against it. Pass a filter to only run some of them, for instance
+//! `cargo bench -- handshake`.
+//! The heap allocations are counted too, to check the read path of the channels and of the
+//! transport doesn't do more than the one of the returned plaintext. This slightly slows down all the allocations of the benchmarks.
+
+use revault_net::{
+    noise::{
+        encrypted_msg_size, generate_keypair, CipherSuite, KKChannel, KKHandshakeActOne,
+        KKHandshakeActTwo, KKMessageActOne, KKResponder, NoiseEncryptedHeader,
+        NoiseEncryptedMessage, PublicKey, SecretKey, NOISE_MESSAGE_HEADER_SIZE, PROTOCOL_VERSIONS,
+    },
+    transport::KKTransport,
+};
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    convert::TryInto,
+    hint::black_box,
+    net::TcpListener,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+// The system allocator, counting the allocations
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// The number of heap allocations made by `f`
+fn allocations<R, F: FnOnce() -> R>(f: F) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    black_box(f());
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn initiate(
+    my_privkey: &SecretKey,
+    their_pubkey: &PublicKey,
+) -> (KKHandshakeActOne, KKMessageActOne) {
+    KKHandshakeActOne::initiator(
+        my_privkey,
+        their_pubkey,
+        PROTOCOL_VERSIONS,
+        CipherSuite::default(),
+    )
+    .unwrap()
+}
+
+fn respond(
+    my_privkey: &SecretKey,
+    their_possible_pubkeys: &[PublicKey],
+    msg_1: &KKMessageActOne,
+) -> KKHandshakeActOne {
+    KKHandshakeActOne::responder(
+        my_privkey,
+        their_possible_pubkeys,
+        msg_1,
+        PROTOCOL_VERSIONS,
+        CipherSuite::default(),
+    )
+    .unwrap()
+}
+
+fn channels() -> (KKChannel, KKChannel) {
+    let (initiator_pubkey, initiator_privkey) = generate_keypair();
+    let (responder_pubkey, responder_privkey) = generate_keypair();
+
+    let (cli_act_1, msg_1) = initiate(&initiator_privkey, &responder_pubkey);
+    let serv_act_1 = respond(&responder_privkey, &[initiator_pubkey], &msg_1);
+    let (serv_act_2, msg_2) = KKHandshakeActTwo::responder(serv_act_1).unwrap();
+    let cli_act_2 = KKHandshakeActTwo::initiator(cli_act_1, &msg_2).unwrap();
+
+    (
+        KKChannel::from_handshake(cli_act_2).unwrap(),
+        KKChannel::from_handshake(serv_act_2).unwrap(),
+    )
+}
+
+fn bench_messages(c: &mut Criterion) {
+    let mut group = c.benchmark_group("messages");
+    // The last one is close to the maximum plaintext size
+    for (name, size) in [("64B", 64), ("1KB", 1024), ("64KB", 65_000)] {
+        let msg = vec![0x42; size];
+        group.throughput(Throughput::Bytes(size as u64));
+
+        let (mut sender, _) = channels();
+        group.bench_function(BenchmarkId::new("encrypt_message", name), |b| {
+            b.iter(|| black_box(sender.encrypt_message(black_box(&msg)).unwrap()))
+        });
+
+        // The channels must stay in sync, so each message is decrypted right after being
+        // encrypted (outside of the measurement)
+        let (mut sender, mut receiver) = channels();
+        group.bench_function(
+            BenchmarkId::new("decrypt_header + decrypt_message", name),
+            |b| {
+                b.iter_batched(
+                    || {
+                        let mut encrypted_msg = sender.encrypt_message(&msg).unwrap().0;
+                        let body = encrypted_msg.split_off(NOISE_MESSAGE_HEADER_SIZE);
+                        (
+                            NoiseEncryptedHeader(encrypted_msg.try_into().unwrap()),
+                            NoiseEncryptedMessage(body),
+                        )
+                    },
+                    |(header, body)| {
+                        receiver.decrypt_header(&header).unwrap();
+                        receiver.decrypt_message(&body).unwrap()
+                    },
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_handshake(c: &mut Criterion) {
+    let (initiator_pubkey, initiator_privkey) = generate_keypair();
+    let (responder_pubkey, responder_privkey) = generate_keypair();
+
+    c.bench_function("KK handshake (initiator + responder)", |b| {
+        b.iter(|| {
+            let (cli_act_1, msg_1) = initiate(&initiator_privkey, &responder_pubkey);
+            let serv_act_1 = respond(&responder_privkey, &[initiator_pubkey], &msg_1);
+            let (serv_act_2, msg_2) = KKHandshakeActTwo::responder(serv_act_1).unwrap();
+            let cli_act_2 = KKHandshakeActTwo::initiator(cli_act_1, &msg_2).unwrap();
+            (
+                KKChannel::from_handshake(cli_act_2).unwrap(),
+                KKChannel::from_handshake(serv_act_2).unwrap(),
+            )
+        })
+    });
+}
+
+fn bench_responder(c: &mut Criterion) {
+    let (responder_pubkey, responder_privkey) = generate_keypair();
+    let (initiator_pubkey, initiator_privkey) = generate_keypair();
+
+    let mut group = c.benchmark_group("responder act one");
+    for n_keys in [1, 10, 50, 100] {
+        // The worst case: the initiator's key is the last candidate
+        let mut pubkeys: Vec<PublicKey> = (1..n_keys).map(|_| generate_keypair().0).collect();
+        pubkeys.push(initiator_pubkey);
+
+        group.bench_with_input(
+            BenchmarkId::new("KKHandshakeActOne", n_keys),
+            &pubkeys,
+            |b, pubkeys| {
+                b.iter_batched(
+                    || initiate(&initiator_privkey, &responder_pubkey).1,
+                    |msg_1| respond(&responder_privkey, pubkeys, &msg_1),
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+
+        let responder = KKResponder::new(
+            &responder_privkey,
+            &pubkeys,
+            PROTOCOL_VERSIONS,
+            &[],
+            CipherSuite::default(),
+        )
+        .unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("KKResponder", n_keys),
+            &responder,
+            |b, responder| {
+                b.iter_batched(
+                    || initiate(&initiator_privkey, &responder_pubkey).1,
+                    |msg_1| responder.respond(&msg_1).unwrap(),
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+// Decrypting used to make 3 allocations per message: one for each of the header and body
+// within snow's ChaChaPoly, plus the plaintext. Not a benchmark, but it needs the counting
+// allocator so it's run first among them.
+fn check_allocations(_: &mut Criterion) {
+    let (mut sender, mut receiver) = channels();
+    let msg = vec![0x42; 1024];
+    let mut encrypted_msg = vec![0u8; encrypted_msg_size(msg.len())];
+    let mut plaintext = Vec::new();
+
+    let mut encrypted = || {
+        assert_eq!(
+            allocations(|| sender
+                .encrypt_message_into(&msg, &mut encrypted_msg)
+                .unwrap()),
+            0
+        );
+        let body = encrypted_msg[NOISE_MESSAGE_HEADER_SIZE..].to_vec();
+        let header = NoiseEncryptedHeader(
+            encrypted_msg[..NOISE_MESSAGE_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        (header, NoiseEncryptedMessage(body))
+    };
+
+    // Only the plaintext is allocated, and not even it when reusing a buffer
+    let (header, body) = encrypted();
+    assert_eq!(
+        allocations(|| {
+            receiver.decrypt_header(&header).unwrap();
+            receiver.decrypt_message(&body).unwrap()
+        }),
+        1
+    );
+    let (header, body) = encrypted();
+    assert_eq!(
+        allocations(|| {
+            receiver.decrypt_header(&header).unwrap();
+            receiver
+                .decrypt_message_into(&body, &mut plaintext)
+                .unwrap()
+        }),
+        1
+    );
+    // The buffer is only allocated the first time
+    let (header, body) = encrypted();
+    assert_eq!(
+        allocations(|| {
+            receiver.decrypt_header(&header).unwrap();
+            receiver
+                .decrypt_message_into(&body, &mut plaintext)
+                .unwrap()
+        }),
+        0
+    );
+
+    // Same through the transport, once its read buffer is sized
+    let (mut sender, mut receiver) = transports();
+    let mut buf = Vec::new();
+    sender.write(&msg).unwrap();
+    receiver.read_into(&mut buf).unwrap();
+    sender.write(&msg).unwrap();
+    assert_eq!(allocations(|| receiver.read().unwrap()), 1);
+    sender.write(&msg).unwrap();
+    assert_eq!(allocations(|| receiver.read_into(&mut buf).unwrap()), 0);
+}
+
+// A pair of transports connected over loopback
+fn transports() -> (KKTransport, KKTransport) {
+    let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+        (generate_keypair(), generate_keypair());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server_thread = thread::spawn(move || {
+        KKTransport::accept(&listener, &server_privkey, &[client_pubkey]).unwrap()
+    });
+    let client = KKTransport::connect(addr, &client_privkey, &server_pubkey).unwrap();
+    (client, server_thread.join().unwrap())
+}
+
+criterion_group!(
+    benches,
+    check_allocations,
+    bench_messages,
+    bench_handshake,
+    bench_responder
+);
+criterion_main!(benches);
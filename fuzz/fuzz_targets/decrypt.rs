@@ -0,0 +1,25 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use revault_net::noise::*;
+use std::convert::TryInto;
+
+fuzz_target!(|data: &[u8]| {
+    let (mut kk_client, mut kk_server) = KKChannel::fuzzing_pair();
+
+    // Treat `data` as received from the peer. It's surely invalid, but be sure we don't crash
+    // while handling it.
+    if data.len() >= NOISE_MESSAGE_HEADER_SIZE {
+        let header = NoiseEncryptedHeader(data[..NOISE_MESSAGE_HEADER_SIZE].try_into().unwrap());
+        let _ = kk_client.decrypt_header(&header);
+        let _ = kk_server.decrypt_header(&header);
+    }
+
+    let (mut kk_client, mut kk_server) = KKChannel::fuzzing_pair();
+    let message = NoiseEncryptedMessage(data.to_vec());
+    let _ = kk_client.decrypt_message(&message);
+    let _ = kk_server.decrypt_message(&message);
+
+    let (mut kk_client, mut kk_server) = KKChannel::fuzzing_pair();
+    let _ = kk_client.decrypt_framed(data);
+    let _ = kk_server.decrypt_framed(data);
+});
@@ -1,6 +1,8 @@
 //! Revault_net error module
 
-use std::{error, fmt};
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error;
 
 #[derive(Debug)]
 pub enum NoiseError {
@@ -14,11 +16,34 @@ pub enum NoiseError {
     BadHandshake,
     /// Remote static public key mismatch from passed keys
     MissingStaticKey,
+    /// We ran out of nonces to encrypt messages with this key
+    NonceExhausted,
+    /// No protocol version in common with the peer, or invalid set of versions
+    UnsupportedVersion,
+    /// A key is not of the expected size
+    InvalidKey,
+    /// The output buffer is too small for the ciphertext
+    BufferTooSmall,
+    /// Handshake message is structurally not a KK one, eg the peer uses another pattern
+    HandshakePatternMismatch,
+    /// A message could not be decrypted (invalid MAC), it was corrupted or forged
+    Decrypt,
+    /// The Noise state machine was misused, eg reading a handshake message out of turn
+    InvalidState(snow::error::StateProblem),
+    /// The cipher suite is not available on this machine, eg AES-GCM without hardware support
+    UnsupportedCipherSuite,
+    /// A channel state to import is malformed, see
+    /// [crate::noise::KKChannel::import_secret_state]
+    InvalidExportedState,
 }
 
 impl From<snow::error::Error> for NoiseError {
     fn from(error: snow::error::Error) -> Self {
-        Self::Snow(error)
+        match error {
+            snow::error::Error::Decrypt => Self::Decrypt,
+            snow::error::Error::State(problem) => Self::InvalidState(problem),
+            e => Self::Snow(e),
+        }
     }
 }
 
@@ -33,40 +58,282 @@ impl fmt::Display for NoiseError {
                 f,
                 "Missing sender's static public key to respond to handshake"
             ),
+            Self::NonceExhausted => write!(f, "Nonce exhausted, the channel must be rekeyed"),
+            Self::UnsupportedVersion => write!(f, "No supported protocol version in common"),
+            Self::InvalidKey => write!(f, "Invalid key size"),
+            Self::BufferTooSmall => write!(f, "Output buffer too small"),
+            Self::Decrypt => write!(f, "Decryption failed, invalid MAC"),
+            Self::InvalidState(ref problem) => write!(f, "Invalid Noise state: {:?}", problem),
+            Self::UnsupportedCipherSuite => write!(f, "Cipher suite not available"),
+            Self::InvalidExportedState => write!(f, "Invalid exported channel state"),
+            Self::HandshakePatternMismatch => write!(
+                f,
+                "Handshake message is not a Noise KK one. Peer is using another pattern?"
+            ),
         }
     }
 }
 
-impl error::Error for NoiseError {}
+#[cfg(feature = "std")]
+impl error::Error for NoiseError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Self::Snow(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 /// An error enum for revault_net functionality
+#[cfg(feature = "std")]
 #[derive(Debug)]
 pub enum Error {
     /// Error while using noise API
     Noise(NoiseError),
+    /// Error while performing the noise handshake, eg the peer wasn't known to us
+    Handshake(NoiseError),
     /// Transport error
     Transport(std::io::Error),
+    /// The peer sent a message which isn't a valid transport frame
+    InvalidFrame,
+    /// The peer gracefully closed the connection, see
+    /// [crate::transport::KKTransport::shutdown]
+    #[doc(alias = "PeerClosed")]
+    ConnectionClosed,
+    /// The peer completed the handshake but was refused by our authentication callback
+    Rejected,
+    /// The peer sent a message with an unexpected sequence number, see
+    /// [crate::transport::TransportConfig::sequence_numbers]
+    ReplayDetected,
+    /// The peer closed the connection without notice, but between two messages
+    CleanEof,
+    /// The peer closed the connection in the middle of a message
+    TruncatedMessage,
+    /// We gave up on the handshake as it was cancelled, see
+    /// [crate::transport::KKTransport::accept_with_cancel]
+    Cancelled,
+    /// The peer sent more messages than we accept to keep in memory before they are read, see
+    /// [crate::transport::TransportConfig::read_buffer_size]
+    BufferFull,
+    /// The protocol version negotiated with the peer doesn't support this, eg a control
+    /// message, see [crate::transport::KKTransport::negotiated_version]
+    Unsupported,
 }
 
+#[cfg(feature = "std")]
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::Noise(ref e) => write!(f, "Noise Error: {}", e),
+            Error::Handshake(ref e) => write!(f, "Noise handshake failed: {}", e),
             Error::Transport(ref e) => write!(f, "Transport Error: {}", e),
+            Error::InvalidFrame => write!(f, "Invalid frame type"),
+            Error::ConnectionClosed => write!(f, "Connection closed by peer"),
+            Error::Rejected => write!(f, "Peer rejected"),
+            Error::ReplayDetected => write!(f, "Replayed or reordered message"),
+            Error::CleanEof => write!(f, "Connection closed by peer between messages"),
+            Error::TruncatedMessage => {
+                write!(f, "Connection closed by peer in the middle of a message")
+            }
+            Error::Cancelled => write!(f, "Handshake cancelled"),
+            Error::BufferFull => write!(f, "Read buffer full"),
+            Error::Unsupported => write!(f, "Not supported by the peer's protocol version"),
         }
     }
 }
 
-impl error::Error for Error {}
+#[cfg(feature = "std")]
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Noise(ref e) | Error::Handshake(ref e) => Some(e),
+            Error::Transport(ref e) => Some(e),
+            Error::InvalidFrame
+            | Error::ConnectionClosed
+            | Error::Rejected
+            | Error::ReplayDetected
+            | Error::CleanEof
+            | Error::TruncatedMessage
+            | Error::Cancelled
+            | Error::BufferFull
+            | Error::Unsupported => None,
+        }
+    }
+}
 
+#[cfg(feature = "std")]
+impl Error {
+    /// Whether this error originates from the underlying stream
+    pub fn is_io(&self) -> bool {
+        matches!(self, Error::Transport(_))
+    }
+
+    /// Whether the Noise handshake failed
+    pub fn is_handshake(&self) -> bool {
+        matches!(self, Error::Handshake(_))
+    }
+
+    /// Whether the peer attempted a handshake with a static key we don't know
+    pub fn is_unknown_peer(&self) -> bool {
+        matches!(self, Error::Handshake(NoiseError::MissingStaticKey))
+    }
+
+    /// Whether the peer gracefully closed the connection
+    pub fn is_closed(&self) -> bool {
+        matches!(self, Error::ConnectionClosed)
+    }
+
+    /// Whether the peer closed the connection without notice, cleanly or not
+    pub fn is_eof(&self) -> bool {
+        matches!(self, Error::CleanEof | Error::TruncatedMessage)
+    }
+
+    /// Whether a read or write timeout of the stream fired. Depending on the platform it's
+    /// reported as [std::io::ErrorKind::WouldBlock] or [std::io::ErrorKind::TimedOut].
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Error::Transport(e) if matches!(
+            e.kind(),
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+        ))
+    }
+}
+
+#[cfg(feature = "std")]
 impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Self {
         Self::Transport(error)
     }
 }
 
+#[cfg(feature = "std")]
 impl From<NoiseError> for Error {
     fn from(error: NoiseError) -> Self {
         Self::Noise(error)
     }
 }
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::{Error, NoiseError};
+    use std::error::Error as StdError;
+
+    #[test]
+    fn test_error_display() {
+        let noise_errors = [
+            NoiseError::Snow(snow::error::Error::Decrypt),
+            NoiseError::InvalidPlaintext,
+            NoiseError::InvalidCiphertext,
+            NoiseError::BadHandshake,
+            NoiseError::MissingStaticKey,
+            NoiseError::NonceExhausted,
+            NoiseError::UnsupportedVersion,
+            NoiseError::InvalidKey,
+            NoiseError::BufferTooSmall,
+            NoiseError::HandshakePatternMismatch,
+            NoiseError::Decrypt,
+            NoiseError::InvalidState(snow::error::StateProblem::NotTurnToRead),
+            NoiseError::UnsupportedCipherSuite,
+            NoiseError::InvalidExportedState,
+        ];
+        let mut messages: Vec<String> = noise_errors.iter().map(|e| e.to_string()).collect();
+        messages.sort();
+        messages.dedup();
+        assert_eq!(messages.len(), noise_errors.len());
+
+        assert_eq!(
+            Error::Handshake(NoiseError::MissingStaticKey).to_string(),
+            "Noise handshake failed: Missing sender's static public key to respond to handshake"
+        );
+        assert_eq!(
+            Error::Noise(NoiseError::NonceExhausted).to_string(),
+            "Noise Error: Nonce exhausted, the channel must be rekeyed"
+        );
+        for (err, msg) in [
+            (Error::InvalidFrame, "Invalid frame type"),
+            (Error::ConnectionClosed, "Connection closed by peer"),
+            (Error::Rejected, "Peer rejected"),
+            (Error::ReplayDetected, "Replayed or reordered message"),
+            (
+                Error::CleanEof,
+                "Connection closed by peer between messages",
+            ),
+            (
+                Error::TruncatedMessage,
+                "Connection closed by peer in the middle of a message",
+            ),
+            (Error::Cancelled, "Handshake cancelled"),
+            (Error::BufferFull, "Read buffer full"),
+            (
+                Error::Unsupported,
+                "Not supported by the peer's protocol version",
+            ),
+        ] {
+            assert_eq!(err.to_string(), msg);
+        }
+
+        // The chain goes all the way down to the underlying error
+        let err = Error::Transport(std::io::Error::new(
+            std::io::ErrorKind::ConnectionReset,
+            "reset by peer",
+        ));
+        assert_eq!(err.to_string(), "Transport Error: reset by peer");
+        let source = err.source().unwrap();
+        assert_eq!(
+            source.downcast_ref::<std::io::Error>().unwrap().kind(),
+            std::io::ErrorKind::ConnectionReset
+        );
+        let err = Error::Noise(NoiseError::Snow(snow::error::Error::Decrypt));
+        assert!(err
+            .source()
+            .unwrap()
+            .source()
+            .unwrap()
+            .downcast_ref::<snow::error::Error>()
+            .is_some());
+    }
+
+    #[test]
+    fn test_error_predicates() {
+        let err = Error::from(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        assert!(err.is_io());
+        assert!(!err.is_handshake() && !err.is_unknown_peer());
+        assert!(err.source().is_some());
+
+        let err = Error::Handshake(NoiseError::MissingStaticKey);
+        assert!(err.is_handshake() && err.is_unknown_peer());
+        assert!(!err.is_io());
+        assert!(err.source().unwrap().downcast_ref::<NoiseError>().is_some());
+
+        let err = Error::Handshake(NoiseError::BadHandshake);
+        assert!(err.is_handshake() && !err.is_unknown_peer());
+
+        let err = Error::from(NoiseError::MissingStaticKey);
+        assert!(!err.is_handshake() && !err.is_unknown_peer());
+
+        let err = Error::from(std::io::Error::from(std::io::ErrorKind::WouldBlock));
+        assert!(err.is_io() && err.is_timeout());
+        assert!(Error::from(std::io::Error::from(std::io::ErrorKind::TimedOut)).is_timeout());
+
+        let err = Error::ConnectionClosed;
+        assert!(err.is_closed() && !err.is_io());
+        assert!(!err.is_timeout());
+        assert!(err.source().is_none());
+    }
+
+    #[test]
+    fn test_error_send_sync() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<Error>();
+        assert_send_sync::<NoiseError>();
+
+        // Both can be boxed as a trait object, eg for `?` in an application
+        fn boxed() -> Result<(), Box<dyn StdError + Send + Sync>> {
+            Err(Error::from(NoiseError::InvalidKey))?;
+            Ok(())
+        }
+        let err = boxed().unwrap_err();
+        assert!(err.downcast_ref::<Error>().is_some());
+        let err: Box<dyn StdError + Send + Sync> = NoiseError::InvalidKey.into();
+        assert!(err.downcast_ref::<NoiseError>().is_some());
+    }
+}
@@ -0,0 +1,295 @@
+//! Noise static keys utilities
+//!
+//! Helpers to store and load the Noise static keys (eg in configuration files).
+
+use crate::{
+    bitcoin::hashes::hex::{FromHex, ToHex},
+    noise::{PublicKey, SecretKey, KEY_SIZE},
+};
+
+use std::{
+    cmp::Ordering,
+    fmt, fs,
+    hash::{Hash, Hasher},
+    io,
+    path::Path,
+    str::FromStr,
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// A Noise static public key, (de)serialized as a 64-characters lowercase hex string.
+/// It can be used as a map key, and is ordered by its bytes (eg to sort a list of peers).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoisePubKey(pub PublicKey);
+
+/// An error when parsing or loading a Noise static key
+#[derive(Debug)]
+pub enum KeyError {
+    /// The key file could not be read
+    Io(io::Error),
+    /// The key is not a valid hex string
+    InvalidHex(String),
+    /// The key is not of [KEY_SIZE] bytes
+    InvalidLength(usize),
+    /// The secret key file is readable by anyone (contains the file mode)
+    InsecurePermissions(u32),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Io(ref e) => write!(f, "Reading Noise key file: {}", e),
+            Self::InvalidHex(ref e) => write!(f, "Invalid hex for Noise key: {}", e),
+            Self::InvalidLength(len) => write!(
+                f,
+                "Invalid Noise key length: expected {} bytes, got {}",
+                KEY_SIZE, len
+            ),
+            Self::InsecurePermissions(mode) => write!(
+                f,
+                "Noise secret key file is world-readable (mode {:o})",
+                mode
+            ),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Self::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KeyError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+// Decode a hex string into key bytes
+fn key_bytes_from_hex(s: &str) -> Result<[u8; KEY_SIZE], KeyError> {
+    let bytes = Vec::<u8>::from_hex(s).map_err(|e| KeyError::InvalidHex(e.to_string()))?;
+    if bytes.len() != KEY_SIZE {
+        return Err(KeyError::InvalidLength(bytes.len()));
+    }
+
+    let mut key = [0u8; KEY_SIZE];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+// Read a hex-encoded key from this file, ignoring surrounding whitespaces
+fn key_bytes_from_file(path: &Path) -> Result<[u8; KEY_SIZE], KeyError> {
+    let content = fs::read_to_string(path)?;
+    key_bytes_from_hex(content.trim())
+}
+
+/// Load a Noise static public key stored as hex in this file
+pub fn public_key_from_file(path: impl AsRef<Path>) -> Result<PublicKey, KeyError> {
+    key_bytes_from_file(path.as_ref()).map(PublicKey)
+}
+
+/// Load a Noise static secret key stored as hex in this file. On Unix, the file must not
+/// be readable by everyone.
+pub fn secret_key_from_file(path: impl AsRef<Path>) -> Result<SecretKey, KeyError> {
+    let path = path.as_ref();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = fs::metadata(path)?.permissions().mode();
+        if mode & 0o004 != 0 {
+            return Err(KeyError::InsecurePermissions(mode & 0o777));
+        }
+    }
+
+    key_bytes_from_file(path).map(SecretKey)
+}
+
+impl FromStr for NoisePubKey {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        key_bytes_from_hex(s).map(|key| NoisePubKey(PublicKey(key)))
+    }
+}
+
+impl fmt::Display for NoisePubKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", (self.0).0.to_hex())
+    }
+}
+
+// Implemented on the bytes rather than deferring to sodiumoxide's key type, so that it
+// doesn't depend on its choices
+impl Hash for NoisePubKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0).0.hash(state)
+    }
+}
+
+impl PartialOrd for NoisePubKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NoisePubKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.0).0.cmp(&(other.0).0)
+    }
+}
+
+impl From<PublicKey> for NoisePubKey {
+    fn from(pubkey: PublicKey) -> Self {
+        NoisePubKey(pubkey)
+    }
+}
+
+impl From<NoisePubKey> for PublicKey {
+    fn from(pubkey: NoisePubKey) -> Self {
+        pubkey.0
+    }
+}
+
+impl Serialize for NoisePubKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for NoisePubKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        NoisePubKey::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{public_key_from_file, secret_key_from_file, KeyError, NoisePubKey};
+    use crate::noise::{generate_keypair, PublicKey};
+    use std::{collections::HashMap, fs, path::PathBuf};
+
+    const HEX_KEY: &str = "a5c3a2b4b5e1bd9f13e2ba6b4a2a1e3e4c0c1b6f1f2e3d4c5b6a798887766554";
+
+    // Write this content to a new file in the temporary directory
+    fn key_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "revault_net_{}_{}_{}",
+            std::process::id(),
+            name,
+            generate_keypair().0 .0[0]
+        ));
+        fs::write(&path, content).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn serde_noise_pubkey() {
+        let pubkey = NoisePubKey(generate_keypair().0);
+        let serialized = serde_json::to_string(&pubkey).unwrap();
+        assert_eq!(serialized.len(), 64 + 2);
+        assert_eq!(serialized, serialized.to_lowercase());
+        assert_eq!(
+            serde_json::from_str::<NoisePubKey>(&serialized).unwrap(),
+            pubkey
+        );
+
+        let hex_key = "\"a5c3a2b4b5e1bd9f13e2ba6b4a2a1e3e4c0c1b6f1f2e3d4c5b6a798887766554\"";
+        let pubkey: NoisePubKey = serde_json::from_str(hex_key).unwrap();
+        assert_eq!(serde_json::to_string(&pubkey).unwrap(), hex_key);
+
+        // 31 bytes
+        let err = serde_json::from_str::<NoisePubKey>(
+            "\"a5c3a2b4b5e1bd9f13e2ba6b4a2a1e3e4c0c1b6f1f2e3d4c5b6a7988877665\"",
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Invalid Noise key length: expected 32 bytes, got 31"));
+        // Not hex
+        serde_json::from_str::<NoisePubKey>(
+            "\"z5c3a2b4b5e1bd9f13e2ba6b4a2a1e3e4c0c1b6f1f2e3d4c5b6a798887766554\"",
+        )
+        .unwrap_err();
+        assert!(matches!(
+            "a5c3".parse::<NoisePubKey>().unwrap_err(),
+            KeyError::InvalidLength(2)
+        ));
+    }
+
+    #[test]
+    fn noise_pubkey_map_key() {
+        let keys: Vec<NoisePubKey> = (0..10).map(|_| generate_keypair().0.into()).collect();
+        let peers: HashMap<NoisePubKey, usize> =
+            keys.iter().enumerate().map(|(i, key)| (*key, i)).collect();
+        assert_eq!(peers.len(), 10);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(peers[key], i);
+        }
+        assert!(!peers.contains_key(&NoisePubKey(generate_keypair().0)));
+
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert!(sorted.windows(2).all(|w| (w[0].0).0 < (w[1].0).0));
+        assert!(NoisePubKey(PublicKey([0; 32])) < NoisePubKey(PublicKey([1; 32])));
+    }
+
+    #[test]
+    fn keys_from_file() {
+        let valid = key_file("valid", HEX_KEY);
+        let newline = key_file("newline", &format!("{}\n", HEX_KEY));
+        let short = key_file("short", &HEX_KEY[..62]);
+        let not_hex = key_file("not_hex", &HEX_KEY.replace('a', "g"));
+
+        let pubkey = public_key_from_file(&valid).unwrap();
+        assert_eq!(NoisePubKey(pubkey).to_string(), HEX_KEY);
+        assert_eq!(public_key_from_file(&newline).unwrap(), pubkey);
+        assert_eq!(secret_key_from_file(&valid).unwrap().0, pubkey.0);
+        assert_eq!(secret_key_from_file(&newline).unwrap().0, pubkey.0);
+
+        assert!(matches!(
+            public_key_from_file(&short).unwrap_err(),
+            KeyError::InvalidLength(31)
+        ));
+        assert!(matches!(
+            secret_key_from_file(&short).unwrap_err(),
+            KeyError::InvalidLength(31)
+        ));
+        assert!(matches!(
+            secret_key_from_file(&not_hex).unwrap_err(),
+            KeyError::InvalidHex(_)
+        ));
+        assert!(matches!(
+            public_key_from_file("/this/file/does/not/exist").unwrap_err(),
+            KeyError::Io(_)
+        ));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&valid, fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(matches!(
+                secret_key_from_file(&valid).unwrap_err(),
+                KeyError::InsecurePermissions(0o644)
+            ));
+            // The public key can be read by anyone
+            assert_eq!(public_key_from_file(&valid).unwrap(), pubkey);
+        }
+
+        for path in &[valid, newline, short, not_hex] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -4,15 +4,30 @@
 
 #![warn(missing_docs)]
 #![forbid(unsafe_code)]
+// The noise module only needs `core` and `alloc`. Note snow and sodiumoxide still need std, so
+// the crate can't be built for a target without it yet.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
+extern crate alloc;
+
+#[cfg(feature = "std")]
 pub mod message;
 
 pub mod noise;
 
+#[cfg(feature = "std")]
 pub mod transport;
 
+#[cfg(feature = "std")]
+pub mod keys;
+
+#[cfg(feature = "ws")]
+pub mod ws;
+
 mod error;
+#[cfg(feature = "std")]
 pub use error::Error;
 
+#[cfg(feature = "std")]
 pub use revault_tx::bitcoin;
 pub use sodiumoxide;
@@ -6,9 +6,20 @@
 
 use crate::error::NoiseError;
 
-use std::convert::TryInto;
+use alloc::{boxed::Box, collections::BTreeSet, format, string::String, vec, vec::Vec};
+use core::{
+    convert::{TryFrom, TryInto},
+    ops::{Deref, DerefMut},
+};
 
-use snow::{resolvers::SodiumResolver, Builder, HandshakeState, TransportState};
+use log::{debug, trace};
+use snow::{
+    params::{CipherChoice, DHChoice, HashChoice, NoiseParams},
+    resolvers::{CryptoResolver, SodiumResolver},
+    types::{Cipher, Dh, Hash, Random},
+    Builder, HandshakeState, StatelessTransportState,
+};
+use sodiumoxide::crypto::aead::{aes256gcm, chacha20poly1305_ietf};
 
 /// The static public key used to enact Noise authenticated and encrypted channels
 pub use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::PublicKey;
@@ -19,6 +30,8 @@ pub use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::SecretKey;
 pub const KEY_SIZE: usize = 32;
 /// Size of the poly1305 MAC
 pub const MAC_SIZE: usize = 16;
+/// Size of the SHA256 handshake hash
+pub const HASH_SIZE: usize = 32;
 /// Max message size pecified by Noise Protocol Framework
 pub const NOISE_MESSAGE_MAX_SIZE: usize = 65535;
 /// Two bytes are used for the message length prefix
@@ -28,79 +41,910 @@ pub const NOISE_MESSAGE_HEADER_SIZE: usize = LENGTH_PREFIX_SIZE + MAC_SIZE;
 /// Maximum size of a message before being encrypted; limited by Noise Protocol Framework
 pub const NOISE_PLAINTEXT_MAX_SIZE: usize =
     NOISE_MESSAGE_MAX_SIZE - NOISE_MESSAGE_HEADER_SIZE - MAC_SIZE;
+/// Size added by the encryption to each message: the encrypted length prefix and its MAC, and
+/// the MAC of the message itself
+pub const PER_MESSAGE_OVERHEAD: usize = NOISE_MESSAGE_HEADER_SIZE + MAC_SIZE;
 /// e, es, ss
 pub const KK_MSG_1_SIZE: usize = KEY_SIZE + HANDSHAKE_MESSAGE.len() + MAC_SIZE;
 /// e, ee, se
 pub const KK_MSG_2_SIZE: usize = KEY_SIZE + MAC_SIZE;
-/// Sent for versioning and identification during handshake
+/// e, ee, se, with the selected protocol version. Sent instead of a [KK_MSG_2_SIZE] message if
+/// the initiator offered other versions than the first one, see [HANDSHAKE_PREFIX].
+pub const KK_VERSIONED_MSG_2_SIZE: usize = KK_MSG_2_SIZE + VERSION_SIZE;
+/// e, es, s, ss, for the open enrollment, see [KKHandshakeActOne::responder_open]
+pub const IK_MSG_1_SIZE: usize =
+    KEY_SIZE + KEY_SIZE + MAC_SIZE + HANDSHAKE_MESSAGE.len() + MAC_SIZE;
+/// e
+pub const XX_MSG_1_SIZE: usize = KEY_SIZE;
+/// e, ee, s, es
+pub const XX_MSG_2_SIZE: usize = KEY_SIZE + KEY_SIZE + MAC_SIZE + MAC_SIZE;
+/// s, se
+pub const XX_MSG_3_SIZE: usize = KEY_SIZE + MAC_SIZE + HANDSHAKE_MESSAGE.len() + MAC_SIZE;
+/// Sent for versioning and identification during handshake. A KK initiator offering only the
+/// first protocol version sends it as is, like the versions of this library predating the
+/// version negotiation: their responders accept nothing else.
 pub const HANDSHAKE_MESSAGE: &[u8] = b"practical_revault_0";
+/// Sent for identification during the KK handshake by an initiator offering other versions
+/// than the first one, followed by the offered versions as a bitfield with its high bit set (so
+/// that it can't be mistaken for the last byte of the [HANDSHAKE_MESSAGE]). The responder then
+/// sends the version it selected in the second message, which is [KK_VERSIONED_MSG_2_SIZE]
+/// long. Otherwise the first version is implied and the second message is empty.
+pub const HANDSHAKE_PREFIX: &[u8] = b"practical_revault_";
+/// The offered protocol versions bitfield following the [HANDSHAKE_PREFIX], and the selected
+/// version in the second message, are each a single byte.
+pub const VERSION_SIZE: usize = 1;
+/// The highest protocol version that can be offered, since they are sent as a 7-bit bitfield
+pub const MAX_PROTOCOL_VERSION: u8 = 6;
+// Set in the offered versions bitfield, see [HANDSHAKE_PREFIX]
+const VERSIONS_FLAG: u8 = 0x80;
+/// The protocol versions supported by this implementation. Since version 1 the transport
+/// prefixes the plaintexts with a frame type, and exchanges a session token right after the
+/// handshake (see [crate::transport::KKTransport::session_token]).
+pub const PROTOCOL_VERSIONS: &[u8] = &[0, 1];
+/// The versions to offer to a responder predating the version negotiation. It only knows the
+/// first one, and closes the connection upon any other offer: the transport then connects
+/// again offering these, which is the same handshake as sending the [HANDSHAKE_MESSAGE].
+pub const LEGACY_VERSIONS: &[u8] = &[0];
+
+/// Generate a new Noise static keypair
+pub fn generate_keypair() -> (PublicKey, SecretKey) {
+    sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::gen_keypair()
+}
+
+/// Get the Noise static public key corresponding to this secret key
+pub fn public_from_secret(secret_key: &SecretKey) -> PublicKey {
+    secret_key.public_key()
+}
+
+/// Get a Noise static public key from its [KEY_SIZE] bytes
+pub fn public_key_from_bytes(bytes: &[u8]) -> Result<PublicKey, NoiseError> {
+    PublicKey::from_slice(bytes).ok_or(NoiseError::InvalidKey)
+}
+
+/// Get a Noise static secret key from its [KEY_SIZE] bytes
+pub fn secret_key_from_bytes(bytes: &[u8]) -> Result<SecretKey, NoiseError> {
+    SecretKey::from_slice(bytes).ok_or(NoiseError::InvalidKey)
+}
+
+/// The size of a peer identifier, see [peer_id]
+pub const PEER_ID_SIZE: usize = 8;
+
+/// Get a short identifier for this peer static key, to correlate logs or index a database: the
+/// hex-encoded first [PEER_ID_SIZE] bytes of the SHA-256 of the key.
+/// It is not secret and must not be used for authentication, only the full key identifies the
+/// peer.
+pub fn peer_id(key: &PublicKey) -> String {
+    let digest = sodiumoxide::crypto::hash::sha256::hash(&key.0);
+    digest.0[..PEER_ID_SIZE]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// A key of the right size may still not be usable, if it's a low order point: the
+// Diffie-Hellman with it would always give the same (zero) shared secret. Check it up front
+// rather than getting an opaque error from the handshake.
+fn check_public_key(key: &PublicKey) -> Result<(), NoiseError> {
+    use sodiumoxide::crypto::scalarmult::curve25519::{scalarmult, GroupElement, Scalar};
+
+    scalarmult(&Scalar([1u8; KEY_SIZE]), &GroupElement(key.0))
+        .map(|_| ())
+        .map_err(|_| NoiseError::InvalidKey)
+}
+
+/// Check a list of peer static keys before using it, eg as the possible keys of
+/// [KKHandshakeActOne::responder]. Returns [NoiseError::InvalidKey] if one of them is the
+/// all-zero key or another low order point, or if a key appears twice (which hints at a
+/// misconfiguration).
+pub fn validate_pubkeys(keys: &[PublicKey]) -> Result<(), NoiseError> {
+    let mut seen = BTreeSet::new();
+    for key in keys {
+        check_public_key(key)?;
+        if !seen.insert(key.0) {
+            return Err(NoiseError::InvalidKey);
+        }
+    }
+
+    Ok(())
+}
+
+/// Access the bytes of a Noise static key
+pub trait KeyBytes {
+    /// Get the [KEY_SIZE] bytes of this key
+    fn as_bytes(&self) -> &[u8; KEY_SIZE];
+}
+
+impl KeyBytes for PublicKey {
+    fn as_bytes(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+}
+
+impl KeyBytes for SecretKey {
+    fn as_bytes(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+}
+
+const KK_PARAMS: &str = "Noise_KK_25519_ChaChaPoly_SHA256";
+const KK_AESGCM_PARAMS: &str = "Noise_KK_25519_AESGCM_SHA256";
+const IK_PARAMS: &str = "Noise_IK_25519_ChaChaPoly_SHA256";
+const IK_AESGCM_PARAMS: &str = "Noise_IK_25519_AESGCM_SHA256";
+const XX_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+// Only used to get a blank transport state, see [blank_transport_state]
+const NN_PARAMS: &str = "Noise_NN_25519_ChaChaPoly_SHA256";
+
+/// The cipher and hash functions used by a KK channel. Both peers must use the same one,
+/// otherwise the handshake fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CipherSuite {
+    /// ChaCha20-Poly1305 and SHA256
+    #[default]
+    ChaChaPolySha256,
+    /// AES256-GCM and SHA256, faster on CPUs with hardware AES support. It's not available
+    /// on the others, see [CipherSuite::is_available].
+    AesGcmSha256,
+}
+
+impl CipherSuite {
+    /// Whether this cipher suite can be used on this machine
+    pub fn is_available(&self) -> bool {
+        match self {
+            CipherSuite::ChaChaPolySha256 => true,
+            CipherSuite::AesGcmSha256 => sodiumoxide::init().is_ok() && aes256gcm::is_available(),
+        }
+    }
+
+    fn kk_params(&self) -> Result<&'static str, NoiseError> {
+        if !self.is_available() {
+            return Err(NoiseError::UnsupportedCipherSuite);
+        }
+        Ok(match self {
+            CipherSuite::ChaChaPolySha256 => KK_PARAMS,
+            CipherSuite::AesGcmSha256 => KK_AESGCM_PARAMS,
+        })
+    }
+
+    fn ik_params(&self) -> Result<&'static str, NoiseError> {
+        if !self.is_available() {
+            return Err(NoiseError::UnsupportedCipherSuite);
+        }
+        Ok(match self {
+            CipherSuite::ChaChaPolySha256 => IK_PARAMS,
+            CipherSuite::AesGcmSha256 => IK_AESGCM_PARAMS,
+        })
+    }
+}
+
+// We only support libsodium as a crypto backend. snow 0.7's pure-Rust resolver can't be
+// used as its dependency tree requires a yanked crate (aes-soft 0.5.0), and we'd still link
+// libsodium for our key types.
+fn builder<'a>(params: &str) -> Builder<'a> {
+    builder_with_params(params.parse().expect("Valid params"))
+}
+
+fn builder_with_params<'a>(params: NoiseParams) -> Builder<'a> {
+    Builder::with_resolver(params, Box::new(Resolver))
+}
+
+// snow's libsodium resolver, plus libsodium's AES-GCM which it doesn't provide. We provide
+// ChaChaPoly too, as snow's allocates the output of each encryption and decryption.
+struct Resolver;
+
+impl CryptoResolver for Resolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        SodiumResolver.resolve_rng()
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        SodiumResolver.resolve_dh(choice)
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        SodiumResolver.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        match choice {
+            CipherChoice::AESGCM => {
+                let aes = aes256gcm::Aes256Gcm::new().ok()?;
+                Some(Box::new(AesGcm {
+                    aes,
+                    key: aes256gcm::Key([0; aes256gcm::KEYBYTES]),
+                }))
+            }
+            CipherChoice::ChaChaPoly => Some(Box::new(ChaChaPoly {
+                key: chacha20poly1305_ietf::Key([0; chacha20poly1305_ietf::KEYBYTES]),
+            })),
+        }
+    }
+}
+
+struct ChaChaPoly {
+    key: chacha20poly1305_ietf::Key,
+}
+
+impl ChaChaPoly {
+    // The Noise specification encodes the counter as little-endian for ChaChaPoly
+    fn nonce(nonce: u64) -> chacha20poly1305_ietf::Nonce {
+        let mut nonce_bytes = [0u8; chacha20poly1305_ietf::NONCEBYTES];
+        nonce_bytes[4..].copy_from_slice(&nonce.to_le_bytes());
+        chacha20poly1305_ietf::Nonce(nonce_bytes)
+    }
+}
+
+impl Cipher for ChaChaPoly {
+    fn name(&self) -> &'static str {
+        "ChaChaPoly"
+    }
+
+    fn set(&mut self, key: &[u8]) {
+        self.key = chacha20poly1305_ietf::Key::from_slice(&key[..chacha20poly1305_ietf::KEYBYTES])
+            .expect("Right size");
+    }
+
+    fn encrypt(&self, nonce: u64, authtext: &[u8], plaintext: &[u8], out: &mut [u8]) -> usize {
+        let (body, mac) = out[..plaintext.len() + MAC_SIZE].split_at_mut(plaintext.len());
+        body.copy_from_slice(plaintext);
+        let tag = chacha20poly1305_ietf::seal_detached(
+            body,
+            Some(authtext),
+            &ChaChaPoly::nonce(nonce),
+            &self.key,
+        );
+        mac.copy_from_slice(&tag.0);
+        plaintext.len() + MAC_SIZE
+    }
+
+    fn decrypt(
+        &self,
+        nonce: u64,
+        authtext: &[u8],
+        ciphertext: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, ()> {
+        let (body, mac) = ciphertext.split_at(ciphertext.len().checked_sub(MAC_SIZE).ok_or(())?);
+        let tag = chacha20poly1305_ietf::Tag::from_slice(mac).expect("Right size");
+        let plaintext = &mut out[..body.len()];
+        plaintext.copy_from_slice(body);
+        chacha20poly1305_ietf::open_detached(
+            plaintext,
+            Some(authtext),
+            &tag,
+            &ChaChaPoly::nonce(nonce),
+            &self.key,
+        )?;
+        Ok(body.len())
+    }
+}
+
+struct AesGcm {
+    // Proof that the CPU supports it
+    aes: aes256gcm::Aes256Gcm,
+    key: aes256gcm::Key,
+}
+
+impl AesGcm {
+    // The Noise specification encodes the counter as big-endian for AES-GCM
+    fn nonce(nonce: u64) -> aes256gcm::Nonce {
+        let mut nonce_bytes = [0u8; aes256gcm::NONCEBYTES];
+        nonce_bytes[4..].copy_from_slice(&nonce.to_be_bytes());
+        aes256gcm::Nonce(nonce_bytes)
+    }
+}
+
+impl Cipher for AesGcm {
+    fn name(&self) -> &'static str {
+        "AESGCM"
+    }
+
+    fn set(&mut self, key: &[u8]) {
+        self.key = aes256gcm::Key::from_slice(&key[..aes256gcm::KEYBYTES]).expect("Right size");
+    }
+
+    fn encrypt(&self, nonce: u64, authtext: &[u8], plaintext: &[u8], out: &mut [u8]) -> usize {
+        let (body, mac) = out[..plaintext.len() + MAC_SIZE].split_at_mut(plaintext.len());
+        body.copy_from_slice(plaintext);
+        let tag = self
+            .aes
+            .seal_detached(body, Some(authtext), &AesGcm::nonce(nonce), &self.key);
+        mac.copy_from_slice(&tag.0);
+        plaintext.len() + MAC_SIZE
+    }
+
+    fn decrypt(
+        &self,
+        nonce: u64,
+        authtext: &[u8],
+        ciphertext: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, ()> {
+        let (body, mac) = ciphertext.split_at(ciphertext.len().checked_sub(MAC_SIZE).ok_or(())?);
+        let tag = aes256gcm::Tag::from_slice(mac).expect("Right size");
+        let plaintext = &mut out[..body.len()];
+        plaintext.copy_from_slice(body);
+        self.aes.open_detached(
+            plaintext,
+            Some(authtext),
+            &tag,
+            &AesGcm::nonce(nonce),
+            &self.key,
+        )?;
+        Ok(body.len())
+    }
+}
+
+// Check the decrypted handshake payload starts with the expected magic bytes.
+// The version string is not secret, but it is attacker-controlled input in the handshake path
+// so we don't want the comparison to short-circuit on the first differing byte.
+fn is_handshake_message(payload: &[u8], expected: &[u8]) -> bool {
+    sodiumoxide::utils::memcmp(&payload[..expected.len()], expected)
+}
+
+// The payload of the first handshake message offering these versions, see [HANDSHAKE_PREFIX]
+fn offer_payload(versions: u8) -> Vec<u8> {
+    if versions == 1 {
+        return HANDSHAKE_MESSAGE.to_vec();
+    }
+    let mut payload = HANDSHAKE_PREFIX.to_vec();
+    payload.push(VERSIONS_FLAG | versions);
+    payload
+}
+
+// Check the first handshake message's payload and select the highest common protocol version.
+// Also returns whether the versions were offered explicitly, in which case the selected one
+// must be sent back.
+fn select_version(payload: &[u8], our_versions: u8) -> Result<(u8, bool), NoiseError> {
+    let (offered_versions, versioned) = if is_handshake_message(payload, HANDSHAKE_MESSAGE) {
+        (1, false)
+    } else if is_handshake_message(payload, HANDSHAKE_PREFIX)
+        && payload[HANDSHAKE_PREFIX.len()] & VERSIONS_FLAG != 0
+    {
+        (payload[HANDSHAKE_PREFIX.len()] & !VERSIONS_FLAG, true)
+    } else {
+        return Err(NoiseError::BadHandshake);
+    };
+    let common_versions = offered_versions & our_versions;
+    if common_versions == 0 {
+        return Err(NoiseError::UnsupportedVersion);
+    }
+    Ok(((7 - common_versions.leading_zeros()) as u8, versioned))
+}
+
+// Get the bitfield representation of a set of protocol versions
+fn versions_bitfield(versions: &[u8]) -> Result<u8, NoiseError> {
+    if versions.is_empty() {
+        return Err(NoiseError::UnsupportedVersion);
+    }
+
+    versions.iter().try_fold(0u8, |bitfield, version| {
+        if *version > MAX_PROTOCOL_VERSION {
+            return Err(NoiseError::UnsupportedVersion);
+        }
+        Ok(bitfield | (1 << version))
+    })
+}
 
 /// First round of the KK handshake
 #[derive(Debug)]
 pub struct KKHandshakeActOne {
     state: HandshakeState,
+    // Our static public key, as snow doesn't expose it
+    local_static: PublicKey,
+    // The cipher of the handshake, likewise
+    cipher: CipherChoice,
+    // Bitfield of the protocol versions offered by the initiator, or of the single version
+    // selected by the responder.
+    versions: u8,
+    // Whether the versions were offered explicitly, see [HANDSHAKE_PREFIX]
+    versioned: bool,
 }
 
 /// Message sent during the first round of the KK handshake (e, es, ss)
 pub struct KKMessageActOne(pub(crate) [u8; KK_MSG_1_SIZE]);
 
+impl KKMessageActOne {
+    /// Parse the first handshake message as read from the wire. A message of an unexpected
+    /// size is most likely from a peer using another handshake pattern.
+    /// It's safe to call on arbitrary input: any size other than the expected one is an error.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, NoiseError> {
+        bytes
+            .try_into()
+            .map(Self)
+            .map_err(|_| NoiseError::HandshakePatternMismatch)
+    }
+
+    /// Same as [KKMessageActOne::from_bytes]. A wrong size is reported as
+    /// [NoiseError::HandshakePatternMismatch] rather than [NoiseError::BadHandshake], which is
+    /// kept for a message of the right size that fails to decrypt.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, NoiseError> {
+        Self::from_bytes(bytes)
+    }
+
+    /// Get the first handshake message as sent on the wire
+    pub fn as_bytes(&self) -> &[u8; KK_MSG_1_SIZE] {
+        &self.0
+    }
+}
+
+/// Message sent during the first round of the IK handshake (e, es, s, ss), see
+/// [KKHandshakeActOne::initiator_open]
+pub struct IKMessageActOne(pub(crate) [u8; IK_MSG_1_SIZE]);
+
+impl IKMessageActOne {
+    /// Parse the first IK handshake message as read from the wire, see
+    /// [KKMessageActOne::from_bytes].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, NoiseError> {
+        bytes
+            .try_into()
+            .map(Self)
+            .map_err(|_| NoiseError::HandshakePatternMismatch)
+    }
+
+    /// Get the first IK handshake message as sent on the wire
+    pub fn as_bytes(&self) -> &[u8; IK_MSG_1_SIZE] {
+        &self.0
+    }
+}
+
 impl KKHandshakeActOne {
-    /// Start the first act of the handshake as an initiator (sharing e, es, ss)
+    /// Start the first act of the handshake as an initiator (sharing e, es, ss), offering the
+    /// given protocol `versions` (usually [PROTOCOL_VERSIONS]).
+    /// The `suite` must be the same on both ends, usually the default one.
+    /// Returns [NoiseError::InvalidKey] if their key is not a valid Curve25519 public key.
     pub fn initiator(
         my_privkey: &SecretKey,
         their_pubkey: &PublicKey,
+        versions: &[u8],
+        suite: CipherSuite,
     ) -> Result<(KKHandshakeActOne, KKMessageActOne), NoiseError> {
-        // Build the initial initiator state
-        let builder = Builder::with_resolver(
-            "Noise_KK_25519_ChaChaPoly_SHA256"
-                .parse()
-                .expect("Valid params"),
-            Box::new(SodiumResolver::default()),
+        KKHandshakeActOne::initiator_with_prologue(my_privkey, their_pubkey, versions, &[], suite)
+    }
+
+    /// Same as [KKHandshakeActOne::initiator], binding the `prologue` into the handshake. It
+    /// must be the same on both ends (eg a network magic), see
+    /// [KKHandshakeActOne::responder_with_prologue].
+    pub fn initiator_with_prologue(
+        my_privkey: &SecretKey,
+        their_pubkey: &PublicKey,
+        versions: &[u8],
+        prologue: &[u8],
+        suite: CipherSuite,
+    ) -> Result<(KKHandshakeActOne, KKMessageActOne), NoiseError> {
+        let mut msg = [0u8; KK_MSG_1_SIZE];
+        let params = suite.kk_params()?.parse().expect("Valid params");
+        let act_one = KKHandshakeActOne::initiate(
+            my_privkey,
+            their_pubkey,
+            versions,
+            prologue,
+            params,
+            None,
+            &mut msg,
+        )?;
+        Ok((act_one, KKMessageActOne(msg)))
+    }
+
+    /// Same as [KKHandshakeActOne::initiator], with a fixed ephemeral key so that the
+    /// messages are reproducible (eg for test vectors). **Never use it to communicate**, as
+    /// reusing an ephemeral key breaks the security of the handshake.
+    #[cfg(any(test, feature = "fuzzing"))]
+    pub fn initiator_with_ephemeral(
+        my_privkey: &SecretKey,
+        their_pubkey: &PublicKey,
+        ephemeral: &[u8; KEY_SIZE],
+        versions: &[u8],
+        prologue: &[u8],
+        suite: CipherSuite,
+    ) -> Result<(KKHandshakeActOne, KKMessageActOne), NoiseError> {
+        let mut msg = [0u8; KK_MSG_1_SIZE];
+        let params = suite.kk_params()?.parse().expect("Valid params");
+        let act_one = KKHandshakeActOne::initiate(
+            my_privkey,
+            their_pubkey,
+            versions,
+            prologue,
+            params,
+            Some(ephemeral),
+            &mut msg,
+        )?;
+        Ok((act_one, KKMessageActOne(msg)))
+    }
+
+    /// Start the first act of an IK handshake as an initiator (sharing e, es, s, ss), for a
+    /// responder which may not know our static key yet, see [KKHandshakeActOne::responder_open].
+    /// We still authenticate the responder. The second act and the resulting channel are the
+    /// same as for KK, the other parameters too (see [KKHandshakeActOne::initiator]).
+    pub fn initiator_open(
+        my_privkey: &SecretKey,
+        their_pubkey: &PublicKey,
+        versions: &[u8],
+        prologue: &[u8],
+        suite: CipherSuite,
+    ) -> Result<(KKHandshakeActOne, IKMessageActOne), NoiseError> {
+        let mut msg = [0u8; IK_MSG_1_SIZE];
+        let params = suite.ik_params()?.parse().expect("Valid params");
+        let act_one = KKHandshakeActOne::initiate(
+            my_privkey,
+            their_pubkey,
+            versions,
+            prologue,
+            params,
+            None,
+            &mut msg,
+        )?;
+        Ok((act_one, IKMessageActOne(msg)))
+    }
+
+    // Write the first message of the handshake to `msg`, which must have its exact size
+    fn initiate(
+        my_privkey: &SecretKey,
+        their_pubkey: &PublicKey,
+        versions: &[u8],
+        prologue: &[u8],
+        params: NoiseParams,
+        ephemeral: Option<&[u8; KEY_SIZE]>,
+        msg: &mut [u8],
+    ) -> Result<KKHandshakeActOne, NoiseError> {
+        let versions = versions_bitfield(versions)?;
+        check_public_key(their_pubkey)?;
+        let pattern = params.handshake.pattern;
+        trace!(
+            "Starting {:?} handshake act one as initiator with {}",
+            pattern,
+            peer_id(their_pubkey)
         );
-        let mut state = builder
+
+        // Build the initial initiator state
+        let cipher = params.cipher;
+        let mut builder = builder_with_params(params)
+            .prologue(prologue)
             .local_private_key(&my_privkey.0)
-            .remote_public_key(&their_pubkey.0)
-            .build_initiator()?;
+            .remote_public_key(&their_pubkey.0);
+        if let Some(ephemeral) = ephemeral {
+            builder = builder.fixed_ephemeral_key_for_testing_only(ephemeral);
+        }
+        let mut state = builder.build_initiator()?;
 
         // Write the first message
-        let mut msg = [0u8; KK_MSG_1_SIZE];
-        state.write_message(HANDSHAKE_MESSAGE, &mut msg)?;
+        let len = state.write_message(&offer_payload(versions), msg)?;
+        debug_assert_eq!(len, msg.len());
+        debug!(
+            "Wrote {:?} handshake act one to {}, offering versions {:#010b}",
+            pattern,
+            peer_id(their_pubkey),
+            versions
+        );
 
-        Ok((KKHandshakeActOne { state }, KKMessageActOne(msg)))
+        Ok(KKHandshakeActOne {
+            state,
+            local_static: public_from_secret(my_privkey),
+            cipher,
+            versions,
+            versioned: versions != 1,
+        })
     }
 
     /// Start the first act of the handshake as a responder (reading e, es, ss and doing wizardry with it)
+    /// The highest protocol version among our `versions` (usually [PROTOCOL_VERSIONS]) and the
+    /// ones offered by the initiator is selected.
+    /// The cipher `suite` must be the one used by the initiator.
+    /// Returns [NoiseError::InvalidKey] if one of the possible keys is not a valid Curve25519
+    /// public key. Duplicates are accepted, see [validate_pubkeys] to detect them beforehand.
     pub fn responder(
         my_privkey: &SecretKey,
         their_possible_pubkeys: &[PublicKey],
         message: &KKMessageActOne,
+        versions: &[u8],
+        suite: CipherSuite,
+    ) -> Result<KKHandshakeActOne, NoiseError> {
+        KKHandshakeActOne::responder_with_prologue(
+            my_privkey,
+            their_possible_pubkeys,
+            message,
+            versions,
+            &[],
+            suite,
+        )
+    }
+
+    /// Same as [KKHandshakeActOne::responder], for an initiator which bound this `prologue`
+    /// into the handshake, see [KKHandshakeActOne::initiator_with_prologue].
+    /// A message that no candidate key decrypts is reported as [NoiseError::MissingStaticKey]
+    /// whatever the prologue, so an unknown peer is always reported as such. As they can't be
+    /// told apart from it, so are a mismatch of the prologue or of the cipher `suite`.
+    pub fn responder_with_prologue(
+        my_privkey: &SecretKey,
+        their_possible_pubkeys: &[PublicKey],
+        message: &KKMessageActOne,
+        versions: &[u8],
+        prologue: &[u8],
+        suite: CipherSuite,
     ) -> Result<KKHandshakeActOne, NoiseError> {
-        // TODO: estimate how inefficient it is.
+        let our_versions = versions_bitfield(versions)?;
         for their_pubkey in their_possible_pubkeys {
-            // Build the initial responder state
-            let builder = Builder::with_resolver(
-                "Noise_KK_25519_ChaChaPoly_SHA256"
-                    .parse()
-                    .expect("Valid params"),
-                Box::new(SodiumResolver::default()),
-            );
-            let mut state = builder
-                .local_private_key(&my_privkey.0)
-                .remote_public_key(&their_pubkey.0)
-                .build_responder()?;
-
-            let mut msg = [0u8; KK_MSG_1_SIZE];
-            if state.read_message(&message.0, &mut msg).is_err() {
-                continue;
-            }
-            if &msg[..HANDSHAKE_MESSAGE.len()] != HANDSHAKE_MESSAGE {
-                return Err(NoiseError::BadHandshake);
+            check_public_key(their_pubkey)?;
+        }
+
+        KKHandshakeActOne::respond_any(
+            my_privkey,
+            their_possible_pubkeys,
+            message,
+            our_versions,
+            prologue,
+            &suite.kk_params()?.parse().expect("Valid params"),
+        )
+    }
+
+    /// Same as [KKHandshakeActOne::responder], for a link with a single known peer. As the
+    /// peer can't be unknown, a message that our key and theirs can't decrypt is reported as
+    /// [NoiseError::BadHandshake].
+    pub fn responder_single(
+        my_privkey: &SecretKey,
+        their_pubkey: &PublicKey,
+        message: &KKMessageActOne,
+        versions: &[u8],
+        prologue: &[u8],
+        suite: CipherSuite,
+    ) -> Result<KKHandshakeActOne, NoiseError> {
+        let our_versions = versions_bitfield(versions)?;
+        check_public_key(their_pubkey)?;
+
+        match KKHandshakeActOne::read_act_one(
+            my_privkey,
+            their_pubkey,
+            message,
+            our_versions,
+            prologue,
+            &suite.kk_params()?.parse().expect("Valid params"),
+            None,
+        )? {
+            Ok(act_one) => Ok(act_one),
+            Err(snow::error::Error::Decrypt) => Err(NoiseError::BadHandshake),
+            Err(_) => Err(NoiseError::HandshakePatternMismatch),
+        }
+    }
+
+    /// Same as [KKHandshakeActOne::responder_single], with a fixed ephemeral key. See
+    /// [KKHandshakeActOne::initiator_with_ephemeral], **never use it to communicate**.
+    #[cfg(any(test, feature = "fuzzing"))]
+    pub fn responder_with_ephemeral(
+        my_privkey: &SecretKey,
+        their_pubkey: &PublicKey,
+        message: &KKMessageActOne,
+        ephemeral: &[u8; KEY_SIZE],
+        versions: &[u8],
+        prologue: &[u8],
+        suite: CipherSuite,
+    ) -> Result<KKHandshakeActOne, NoiseError> {
+        match KKHandshakeActOne::read_act_one(
+            my_privkey,
+            their_pubkey,
+            message,
+            versions_bitfield(versions)?,
+            prologue,
+            &suite.kk_params()?.parse().expect("Valid params"),
+            Some(ephemeral),
+        )? {
+            Ok(act_one) => Ok(act_one),
+            Err(snow::error::Error::Decrypt) => Err(NoiseError::BadHandshake),
+            Err(_) => Err(NoiseError::HandshakePatternMismatch),
+        }
+    }
+
+    // Try to read the first message as coming from each of the (already checked) candidates
+    fn respond_any(
+        my_privkey: &SecretKey,
+        their_possible_pubkeys: &[PublicKey],
+        message: &KKMessageActOne,
+        our_versions: u8,
+        prologue: &[u8],
+        params: &NoiseParams,
+    ) -> Result<KKHandshakeActOne, NoiseError> {
+        // Whether the message could be decrypted with a candidate key up to the MAC check. If it
+        // can't even be processed (eg the ephemeral is not a valid point) the peer isn't
+        // speaking KK at all.
+        let mut mac_failed = false;
+        trace!(
+            "Reading KK handshake act one as responder, with {} candidate keys",
+            their_possible_pubkeys.len()
+        );
+
+        for (i, their_pubkey) in their_possible_pubkeys.iter().enumerate() {
+            match KKHandshakeActOne::read_act_one(
+                my_privkey,
+                their_pubkey,
+                message,
+                our_versions,
+                prologue,
+                params,
+                None,
+            )? {
+                Ok(act_one) => {
+                    debug!(
+                        "Read KK handshake act one from {}, candidate key {} out of {}",
+                        peer_id(their_pubkey),
+                        i,
+                        their_possible_pubkeys.len()
+                    );
+                    return Ok(act_one);
+                }
+                Err(snow::error::Error::Decrypt) => mac_failed = true,
+                Err(_) => continue,
             }
+        }
+
+        let error = if their_possible_pubkeys.is_empty() {
+            NoiseError::MissingStaticKey
+        } else if !mac_failed {
+            NoiseError::HandshakePatternMismatch
+        } else {
+            NoiseError::MissingStaticKey
+        };
+        debug!(
+            "No candidate key out of {} matched KK handshake act one: {}",
+            their_possible_pubkeys.len(),
+            error
+        );
+        Err(error)
+    }
+
+    // Try to read the first message as coming from this peer. The inner error is the one
+    // from processing the message, which may just mean we tried the wrong key.
+    fn read_act_one(
+        my_privkey: &SecretKey,
+        their_pubkey: &PublicKey,
+        message: &KKMessageActOne,
+        our_versions: u8,
+        prologue: &[u8],
+        params: &NoiseParams,
+        ephemeral: Option<&[u8; KEY_SIZE]>,
+    ) -> Result<Result<KKHandshakeActOne, snow::error::Error>, NoiseError> {
+        // Build the initial responder state
+        let mut builder = builder_with_params(params.clone())
+            .prologue(prologue)
+            .local_private_key(&my_privkey.0)
+            .remote_public_key(&their_pubkey.0);
+        if let Some(ephemeral) = ephemeral {
+            builder = builder.fixed_ephemeral_key_for_testing_only(ephemeral);
+        }
+        let mut state = builder.build_responder()?;
+
+        let mut msg = [0u8; KK_MSG_1_SIZE];
+        if let Err(e) = state.read_message(&message.0, &mut msg) {
+            return Ok(Err(e));
+        }
+        let (version, versioned) = select_version(&msg, our_versions)?;
+
+        Ok(Ok(KKHandshakeActOne {
+            state,
+            local_static: public_from_secret(my_privkey),
+            cipher: params.cipher,
+            versions: 1 << version,
+            versioned,
+        }))
+    }
+
+    /// Start the first act of an IK handshake as a responder (reading e, es, s, ss), accepting
+    /// any initiator and returning its static key (open enrollment). See
+    /// [KKHandshakeActOne::responder] for the other parameters.
+    /// **This disables the authentication of the initiator**: anyone can complete this
+    /// handshake. Nothing received on the resulting channel must be trusted before the caller
+    /// authorized the returned key, eg out-of-band.
+    /// A message that our key can't decrypt is reported as [NoiseError::BadHandshake].
+    pub fn responder_open(
+        my_privkey: &SecretKey,
+        message: &IKMessageActOne,
+        versions: &[u8],
+        prologue: &[u8],
+        suite: CipherSuite,
+    ) -> Result<(KKHandshakeActOne, PublicKey), NoiseError> {
+        let our_versions = versions_bitfield(versions)?;
+        let params: NoiseParams = suite.ik_params()?.parse().expect("Valid params");
+        let cipher = params.cipher;
+        let mut state = builder_with_params(params)
+            .prologue(prologue)
+            .local_private_key(&my_privkey.0)
+            .build_responder()?;
+
+        let mut msg = [0u8; IK_MSG_1_SIZE];
+        match state.read_message(&message.0, &mut msg) {
+            Ok(_) => {}
+            Err(snow::error::Error::Decrypt) => return Err(NoiseError::BadHandshake),
+            Err(_) => return Err(NoiseError::HandshakePatternMismatch),
+        }
+        let (version, versioned) = select_version(&msg, our_versions)?;
+        let act_one = KKHandshakeActOne {
+            state,
+            local_static: public_from_secret(my_privkey),
+            cipher,
+            versions: 1 << version,
+            versioned,
+        };
+        let their_pubkey = act_one.peer_static().ok_or(NoiseError::MissingStaticKey)?;
+        check_public_key(&their_pubkey)?;
+        debug!(
+            "Read IK handshake act one from unauthenticated peer {}",
+            peer_id(&their_pubkey)
+        );
+
+        Ok((act_one, their_pubkey))
+    }
+
+    /// Get the static public key of the peer. For a responder, this is the one among the
+    /// possible keys that the initiator used, so it can be checked before completing the
+    /// handshake.
+    pub fn peer_static(&self) -> Option<PublicKey> {
+        self.state
+            .get_remote_static()
+            .and_then(PublicKey::from_slice)
+    }
+
+    /// The size of the second message of this handshake: [KK_VERSIONED_MSG_2_SIZE] if the
+    /// initiator offered other protocol versions than the first one, [KK_MSG_2_SIZE] otherwise.
+    pub fn act_two_size(&self) -> usize {
+        if self.versioned {
+            KK_VERSIONED_MSG_2_SIZE
+        } else {
+            KK_MSG_2_SIZE
+        }
+    }
+}
+
+/// A responder for a fixed set of keys, to accept many KK handshakes without repeating their
+/// setup each time: the candidate keys are checked and the Noise parameters parsed once.
+/// Note that snow doesn't let us reuse the static-static Diffie-Hellman, so the first message
+/// still costs two of them per candidate key.
+#[derive(Debug, Clone)]
+pub struct KKResponder {
+    my_privkey: SecretKey,
+    their_possible_pubkeys: Vec<PublicKey>,
+    our_versions: u8,
+    prologue: Vec<u8>,
+    params: NoiseParams,
+}
 
-            return Ok(KKHandshakeActOne { state });
+impl KKResponder {
+    /// Set up a responder with our private key and the possible public keys of the
+    /// initiators, see [KKHandshakeActOne::responder] for the other parameters.
+    /// Returns [NoiseError::InvalidKey] if one of the possible keys is not a valid Curve25519
+    /// public key.
+    pub fn new(
+        my_privkey: &SecretKey,
+        their_possible_pubkeys: &[PublicKey],
+        versions: &[u8],
+        prologue: &[u8],
+        suite: CipherSuite,
+    ) -> Result<KKResponder, NoiseError> {
+        let our_versions = versions_bitfield(versions)?;
+        for their_pubkey in their_possible_pubkeys {
+            check_public_key(their_pubkey)?;
         }
 
-        Err(NoiseError::MissingStaticKey)
+        Ok(KKResponder {
+            my_privkey: my_privkey.clone(),
+            their_possible_pubkeys: their_possible_pubkeys.to_vec(),
+            our_versions,
+            prologue: prologue.to_vec(),
+            params: suite.kk_params()?.parse().expect("Valid params"),
+        })
+    }
+
+    /// Start the first act of the handshake, same as [KKHandshakeActOne::responder].
+    pub fn respond(&self, message: &KKMessageActOne) -> Result<KKHandshakeActOne, NoiseError> {
+        KKHandshakeActOne::respond_any(
+            &self.my_privkey,
+            &self.their_possible_pubkeys,
+            message,
+            self.our_versions,
+            &self.prologue,
+            &self.params,
+        )
+    }
+
+    /// The possible public keys of the initiators
+    pub fn possible_pubkeys(&self) -> &[PublicKey] {
+        &self.their_possible_pubkeys
     }
 }
 
@@ -109,10 +953,39 @@ impl KKHandshakeActOne {
 pub struct KKHandshakeActTwo {
     /// Inner snow Noise KK handshake state
     state: HandshakeState,
+    /// The negotiated protocol version
+    version: u8,
+    /// Our static public key
+    local_static: PublicKey,
+    /// The cipher negotiated in the first act
+    cipher: CipherChoice,
 }
 
-/// Content of the message from the final round of the KK handshake (e, ee, se)
-pub struct KKMessageActTwo(pub(crate) [u8; KK_MSG_2_SIZE]);
+/// Content of the message from the final round of the KK handshake (e, ee, se). Its size
+/// depends on the first message, see [KKHandshakeActOne::act_two_size].
+pub struct KKMessageActTwo(pub(crate) Vec<u8>);
+
+impl KKMessageActTwo {
+    /// Parse the final handshake message as read from the wire. A message of an unexpected
+    /// size is most likely from a peer using another handshake pattern.
+    /// It's safe to call on arbitrary input: any size other than the expected ones is an error.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, NoiseError> {
+        if bytes.len() != KK_MSG_2_SIZE && bytes.len() != KK_VERSIONED_MSG_2_SIZE {
+            return Err(NoiseError::HandshakePatternMismatch);
+        }
+        Ok(Self(bytes.to_vec()))
+    }
+
+    /// Same as [KKMessageActTwo::from_bytes], see [KKMessageActOne::from_slice].
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, NoiseError> {
+        Self::from_bytes(bytes)
+    }
+
+    /// Get the final handshake message as sent on the wire
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
 
 impl KKHandshakeActTwo {
     /// Start the second act of the handshake as a responder (read e, ee, se)
@@ -120,12 +993,26 @@ impl KKHandshakeActTwo {
         mut handshake: KKHandshakeActOne,
         message: &KKMessageActTwo,
     ) -> Result<KKHandshakeActTwo, NoiseError> {
-        // In handshake mode we don't actually care about the message
-        let mut _m = [0u8; KK_MSG_2_SIZE];
-        handshake.state.read_message(&message.0, &mut _m)?;
+        // The payload is the protocol version selected by the responder, if we offered others
+        // than the first one
+        trace!("Reading KK handshake act two as initiator");
+        if message.0.len() != handshake.act_two_size() {
+            return Err(NoiseError::HandshakePatternMismatch);
+        }
+        let mut msg = [0u8; KK_VERSIONED_MSG_2_SIZE];
+        handshake.state.read_message(&message.0, &mut msg)?;
+        let version = if handshake.versioned { msg[0] } else { 0 };
+        if version > MAX_PROTOCOL_VERSION || handshake.versions & (1 << version) == 0 {
+            debug!("Responder selected unsupported version {}", version);
+            return Err(NoiseError::UnsupportedVersion);
+        }
+        debug!("Read KK handshake act two, negotiated version {}", version);
 
         Ok(KKHandshakeActTwo {
             state: handshake.state,
+            version,
+            local_static: handshake.local_static,
+            cipher: handshake.cipher,
         })
     }
 
@@ -133,160 +1020,1959 @@ impl KKHandshakeActTwo {
     pub fn responder(
         mut handshake: KKHandshakeActOne,
     ) -> Result<(KKHandshakeActTwo, KKMessageActTwo), NoiseError> {
-        let mut msg = [0u8; KK_MSG_2_SIZE];
-        handshake.state.write_message(&[], &mut msg)?;
+        trace!("Writing KK handshake act two as responder");
+        let version = handshake.versions.trailing_zeros() as u8;
+        let payload: &[u8] = if handshake.versioned { &[version] } else { &[] };
+        let mut msg = vec![0u8; handshake.act_two_size()];
+        handshake.state.write_message(payload, &mut msg)?;
+        debug!("Wrote KK handshake act two, negotiated version {}", version);
 
         Ok((
             KKHandshakeActTwo {
                 state: handshake.state,
+                version,
+                local_static: handshake.local_static,
+                cipher: handshake.cipher,
             },
             KKMessageActTwo(msg),
         ))
     }
 }
 
-/// A cypertext encrypted with [encrypt_message] containing the length prefix of
-/// a plaintext message.
-#[derive(Debug, Clone, Copy)]
-pub struct NoiseEncryptedHeader(pub [u8; LENGTH_PREFIX_SIZE + MAC_SIZE]);
-
-/// A cyphertext encrypted with [encrypt_message] containing the body of a Noise
-/// message.
-#[derive(Debug)]
-pub struct NoiseEncryptedMessage(pub Vec<u8>);
-
-/// A wrapper over Snow's transport state for a KK Noise communication channel.
+/// First round of the XX handshake
 #[derive(Debug)]
-pub struct KKChannel {
-    transport_state: TransportState,
+pub struct XXHandshakeActOne {
+    state: HandshakeState,
+    local_static: PublicKey,
 }
 
-fn encrypted_msg_size(plaintext_size: usize) -> usize {
-    // Length prefix + MAC    ||   Message + MAC
-    NOISE_MESSAGE_HEADER_SIZE + plaintext_size + MAC_SIZE
-}
+/// Message sent during the first round of the XX handshake (e)
+pub struct XXMessageActOne(pub(crate) [u8; XX_MSG_1_SIZE]);
 
-impl KKChannel {
-    /// Constructs the KK Noise channel from a final stage KK handshake
-    pub fn from_handshake(state: KKHandshakeActTwo) -> Result<KKChannel, NoiseError> {
-        let transport_state = state.state.into_transport_mode()?;
+impl XXHandshakeActOne {
+    /// Start the first act of the handshake as an initiator (sharing e). Unlike KK, we don't
+    /// need to know the responder's static key beforehand.
+    pub fn initiator(
+        my_privkey: &SecretKey,
+    ) -> Result<(XXHandshakeActOne, XXMessageActOne), NoiseError> {
+        let mut state = builder(XX_PARAMS)
+            .local_private_key(&my_privkey.0)
+            .build_initiator()?;
+
+        // Snow wants room for a MAC even though the payload isn't encrypted yet
+        let mut buf = [0u8; XX_MSG_1_SIZE + MAC_SIZE];
+        let len = state.write_message(&[], &mut buf)?;
+        debug_assert_eq!(len, XX_MSG_1_SIZE);
+        let mut msg = [0u8; XX_MSG_1_SIZE];
+        msg.copy_from_slice(&buf[..XX_MSG_1_SIZE]);
 
-        Ok(KKChannel { transport_state })
+        Ok((
+            XXHandshakeActOne {
+                state,
+                local_static: public_from_secret(my_privkey),
+            },
+            XXMessageActOne(msg),
+        ))
     }
 
-    /// Use the channel to encrypt a message shorter than [NOISE_PLAINTEXT_MAX_SIZE].
+    /// Start the first act of the handshake as a responder (reading e)
+    pub fn responder(
+        my_privkey: &SecretKey,
+        message: &XXMessageActOne,
+    ) -> Result<XXHandshakeActOne, NoiseError> {
+        let mut state = builder(XX_PARAMS)
+            .local_private_key(&my_privkey.0)
+            .build_responder()?;
+
+        let mut _m = [0u8; XX_MSG_1_SIZE];
+        state.read_message(&message.0, &mut _m)?;
+
+        Ok(XXHandshakeActOne {
+            state,
+            local_static: public_from_secret(my_privkey),
+        })
+    }
+}
+
+/// Second round of the XX handshake
+#[derive(Debug)]
+pub struct XXHandshakeActTwo {
+    state: HandshakeState,
+    local_static: PublicKey,
+}
+
+/// Message sent during the second round of the XX handshake (e, ee, s, es)
+pub struct XXMessageActTwo(pub(crate) [u8; XX_MSG_2_SIZE]);
+
+impl XXHandshakeActTwo {
+    /// Continue the handshake as an initiator (reading e, ee, s, es). This is where we learn
+    /// the responder's static key.
+    pub fn initiator(
+        mut handshake: XXHandshakeActOne,
+        message: &XXMessageActTwo,
+    ) -> Result<XXHandshakeActTwo, NoiseError> {
+        let mut _m = [0u8; XX_MSG_2_SIZE];
+        handshake.state.read_message(&message.0, &mut _m)?;
+
+        Ok(XXHandshakeActTwo {
+            state: handshake.state,
+            local_static: handshake.local_static,
+        })
+    }
+
+    /// Continue the handshake as a responder (writing e, ee, s, es)
+    pub fn responder(
+        mut handshake: XXHandshakeActOne,
+    ) -> Result<(XXHandshakeActTwo, XXMessageActTwo), NoiseError> {
+        let mut msg = [0u8; XX_MSG_2_SIZE];
+        handshake.state.write_message(&[], &mut msg)?;
+
+        Ok((
+            XXHandshakeActTwo {
+                state: handshake.state,
+                local_static: handshake.local_static,
+            },
+            XXMessageActTwo(msg),
+        ))
+    }
+}
+
+/// Final round of the XX handshake
+#[derive(Debug)]
+pub struct XXHandshakeActThree {
+    state: HandshakeState,
+    local_static: PublicKey,
+}
+
+/// Message sent during the final round of the XX handshake (s, se)
+pub struct XXMessageActThree(pub(crate) [u8; XX_MSG_3_SIZE]);
+
+impl XXHandshakeActThree {
+    /// Finish the handshake as an initiator (writing s, se)
+    pub fn initiator(
+        mut handshake: XXHandshakeActTwo,
+    ) -> Result<(XXHandshakeActThree, XXMessageActThree), NoiseError> {
+        let mut msg = [0u8; XX_MSG_3_SIZE];
+        handshake.state.write_message(HANDSHAKE_MESSAGE, &mut msg)?;
+
+        Ok((
+            XXHandshakeActThree {
+                state: handshake.state,
+                local_static: handshake.local_static,
+            },
+            XXMessageActThree(msg),
+        ))
+    }
+
+    /// Finish the handshake as a responder (reading s, se). This is where we learn the
+    /// initiator's static key.
+    pub fn responder(
+        mut handshake: XXHandshakeActTwo,
+        message: &XXMessageActThree,
+    ) -> Result<XXHandshakeActThree, NoiseError> {
+        let mut msg = [0u8; XX_MSG_3_SIZE];
+        handshake.state.read_message(&message.0, &mut msg)?;
+        if !is_handshake_message(&msg, HANDSHAKE_MESSAGE) {
+            return Err(NoiseError::BadHandshake);
+        }
+
+        Ok(XXHandshakeActThree {
+            state: handshake.state,
+            local_static: handshake.local_static,
+        })
+    }
+}
+
+/// A cypertext encrypted with [KKChannel::encrypt_message] containing the length prefix of
+/// a plaintext message.
+#[derive(Debug, Clone, Copy)]
+pub struct NoiseEncryptedHeader(pub [u8; LENGTH_PREFIX_SIZE + MAC_SIZE]);
+
+/// A cyphertext encrypted with [KKChannel::encrypt_message] containing the body of a Noise
+/// message. Prefer building it with [NoiseEncryptedMessage::new], which checks its size.
+#[derive(Debug)]
+pub struct NoiseEncryptedMessage(pub Vec<u8>);
+
+impl From<[u8; NOISE_MESSAGE_HEADER_SIZE]> for NoiseEncryptedHeader {
+    fn from(bytes: [u8; NOISE_MESSAGE_HEADER_SIZE]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl TryFrom<&[u8]> for NoiseEncryptedHeader {
+    type Error = NoiseError;
+
+    /// Returns [NoiseError::InvalidCiphertext] unless it's exactly
+    /// [NOISE_MESSAGE_HEADER_SIZE] bytes long.
+    fn try_from(bytes: &[u8]) -> Result<Self, NoiseError> {
+        bytes
+            .try_into()
+            .map(Self)
+            .map_err(|_| NoiseError::InvalidCiphertext)
+    }
+}
+
+impl AsRef<[u8]> for NoiseEncryptedHeader {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Deref for NoiseEncryptedHeader {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl NoiseEncryptedMessage {
+    /// Wrap a message body as read from the wire. Returns [NoiseError::InvalidCiphertext] if
+    /// it's too short to contain a MAC, or larger than [NOISE_MESSAGE_MAX_SIZE].
+    pub fn new(bytes: Vec<u8>) -> Result<Self, NoiseError> {
+        check_body_size(bytes.len())?;
+        Ok(Self(bytes))
+    }
+}
+
+impl TryFrom<Vec<u8>> for NoiseEncryptedMessage {
+    type Error = NoiseError;
+
+    /// Same as [NoiseEncryptedMessage::new]
+    fn try_from(bytes: Vec<u8>) -> Result<Self, NoiseError> {
+        Self::new(bytes)
+    }
+}
+
+impl From<NoiseEncryptedMessage> for Vec<u8> {
+    fn from(msg: NoiseEncryptedMessage) -> Self {
+        msg.0
+    }
+}
+
+impl AsRef<[u8]> for NoiseEncryptedMessage {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Deref for NoiseEncryptedMessage {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl IntoIterator for NoiseEncryptedMessage {
+    type Item = u8;
+    type IntoIter = vec::IntoIter<u8>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a NoiseEncryptedMessage {
+    type Item = &'a u8;
+    type IntoIter = core::slice::Iter<'a, u8>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+// A message body always contains a MAC, and can't be larger than what Noise allows
+fn check_body_size(size: usize) -> Result<(), NoiseError> {
+    if !(MAC_SIZE..=NOISE_MESSAGE_MAX_SIZE).contains(&size) {
+        return Err(NoiseError::InvalidCiphertext);
+    }
+    Ok(())
+}
+
+/// A wrapper over Snow's transport state for a KK Noise communication channel.
+#[derive(Debug)]
+pub struct KKChannel {
+    // We keep track of the nonces ourselves, so they can be exported
+    transport_state: StatelessTransportState,
+    sending_nonce: u64,
+    receiving_nonce: u64,
+    // We refuse to encrypt a message past this nonce. Only lowered in tests.
+    nonce_limit: u64,
+    // snow doesn't expose the current keys, so to export them we keep a copy we rotate along.
+    // Only the current ones: the previous keys must not be recoverable after a rekey.
+    current_keys: SplitKeys,
+    cipher: CipherChoice,
+    handshake_hash: [u8; HASH_SIZE],
+    version: u8,
+    local_static: PublicKey,
+    remote_static: PublicKey,
+    // We refuse to read a message body larger than this
+    max_message_size: usize,
+}
+
+// The keys of the initiator and responder directions, they must not end up in logs
+struct SplitKeys([u8; CIPHER_KEY_SIZE], [u8; CIPHER_KEY_SIZE]);
+
+impl core::fmt::Debug for SplitKeys {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "SplitKeys(..)")
+    }
+}
+
+impl Drop for SplitKeys {
+    fn drop(&mut self) {
+        sodiumoxide::utils::memzero(&mut self.0);
+        sodiumoxide::utils::memzero(&mut self.1);
+    }
+}
+
+// The key following this one, as per the REKEY function of section 4.2 of the Noise
+// specification. This is what snow computes on rekey_outgoing and rekey_incoming.
+fn next_key(cipher: CipherChoice, key: &[u8; CIPHER_KEY_SIZE]) -> [u8; CIPHER_KEY_SIZE] {
+    let mut cipher = Resolver
+        .resolve_cipher(&cipher)
+        .expect("The channel was established with it");
+    cipher.set(key);
+    let mut ciphertext = [0u8; CIPHER_KEY_SIZE + MAC_SIZE];
+    cipher.encrypt(u64::MAX, &[], &[0; CIPHER_KEY_SIZE], &mut ciphertext);
+    let next_key = ciphertext[..CIPHER_KEY_SIZE]
+        .try_into()
+        .expect("Right size");
+    sodiumoxide::utils::memzero(&mut ciphertext);
+    next_key
+}
+
+const CIPHER_KEY_SIZE: usize = 32;
+// The version of the format of [KKChannel::export_secret_state]
+const EXPORT_FORMAT_VERSION: u8 = 1;
+// Format version, role, cipher and protocol version, the two current keys, the nonces, the
+// handshake hash and the two static keys.
+const EXPORTED_STATE_SIZE: usize = 4 + 2 * CIPHER_KEY_SIZE + 2 * 8 + HASH_SIZE + 2 * KEY_SIZE;
+
+// A transport state with the given cipher and role, to be set the actual keys. snow can only
+// create one out of a handshake, so we perform a throwaway one.
+fn blank_transport_state(
+    cipher: CipherChoice,
+    initiator: bool,
+) -> Result<StatelessTransportState, NoiseError> {
+    let mut params: NoiseParams = NN_PARAMS.parse().expect("Valid params");
+    params.cipher = cipher;
+    let mut init_state = builder_with_params(params.clone()).build_initiator()?;
+    let mut resp_state = builder_with_params(params).build_responder()?;
+
+    let mut msg = [0u8; KEY_SIZE + MAC_SIZE];
+    let len = init_state.write_message(&[], &mut msg)?;
+    resp_state.read_message(&msg[..len], &mut [])?;
+    let len = resp_state.write_message(&[], &mut msg)?;
+    init_state.read_message(&msg[..len], &mut [])?;
+
+    let state = if initiator { init_state } else { resp_state };
+    Ok(state.into_stateless_transport_mode()?)
+}
+
+/// The size of the encrypted message for a plaintext of this size, as written on the wire.
+///
+/// ```
+/// use revault_net::noise::{encrypted_msg_size, PER_MESSAGE_OVERHEAD};
+///
+/// // Length prefix + MAC    ||   Message + MAC
+/// assert_eq!(encrypted_msg_size(100), 2 + 16 + 100 + 16);
+/// assert_eq!(encrypted_msg_size(100), 100 + PER_MESSAGE_OVERHEAD);
+/// ```
+pub fn encrypted_msg_size(plaintext_size: usize) -> usize {
+    plaintext_size + PER_MESSAGE_OVERHEAD
+}
+
+/// The size of the encrypted body to read after a header, from the length returned by
+/// [KKChannel::decrypt_header]. The length in the header already includes the MAC of the body,
+/// so this is the very same value: use it rather than adding [MAC_SIZE] again.
+///
+/// ```
+/// use revault_net::noise::{body_len_from_header, encrypted_msg_size, NOISE_MESSAGE_HEADER_SIZE};
+///
+/// // The header of a 100 bytes plaintext announces the plaintext and its MAC
+/// assert_eq!(body_len_from_header(100 + 16), 116);
+/// assert_eq!(
+///     body_len_from_header(116),
+///     encrypted_msg_size(100) - NOISE_MESSAGE_HEADER_SIZE
+/// );
+/// ```
+pub fn body_len_from_header(header_len: u16) -> usize {
+    header_len as usize
+}
+
+impl KKChannel {
+    /// Constructs the KK Noise channel from a final stage KK handshake
+    pub fn from_handshake(state: KKHandshakeActTwo) -> Result<KKChannel, NoiseError> {
+        KKChannel::new(state.state, state.version, state.local_static, state.cipher)
+    }
+
+    /// A pair of initiator and responder channels, resulting from a handshake with fixed
+    /// static and ephemeral keys so they are always in the same state. This is only meant to
+    /// be fed arbitrary input by a fuzzer, **never use it to communicate**.
+    #[cfg(any(test, feature = "fuzzing"))]
+    pub fn fuzzing_pair() -> (KKChannel, KKChannel) {
+        let (init_privkey, resp_privkey) = (SecretKey([1; KEY_SIZE]), SecretKey([2; KEY_SIZE]));
+        let (init_ephemeral, resp_ephemeral) = ([3; KEY_SIZE], [4; KEY_SIZE]);
+
+        let (init_act_1, msg_1) = KKHandshakeActOne::initiator_with_ephemeral(
+            &init_privkey,
+            &public_from_secret(&resp_privkey),
+            &init_ephemeral,
+            PROTOCOL_VERSIONS,
+            &[],
+            CipherSuite::default(),
+        )
+        .expect("Valid keys");
+        let resp_act_1 = KKHandshakeActOne::responder_with_ephemeral(
+            &resp_privkey,
+            &public_from_secret(&init_privkey),
+            &msg_1,
+            &resp_ephemeral,
+            PROTOCOL_VERSIONS,
+            &[],
+            CipherSuite::default(),
+        )
+        .expect("Valid message");
+        let (resp_act_2, msg_2) = KKHandshakeActTwo::responder(resp_act_1).expect("Valid state");
+        let init_act_2 = KKHandshakeActTwo::initiator(init_act_1, &msg_2).expect("Valid message");
+
+        (
+            KKChannel::from_handshake(init_act_2).expect("Completed handshake"),
+            KKChannel::from_handshake(resp_act_2).expect("Completed handshake"),
+        )
+    }
+
+    fn new(
+        mut state: HandshakeState,
+        version: u8,
+        local_static: PublicKey,
+        cipher: CipherChoice,
+    ) -> Result<KKChannel, NoiseError> {
+        let handshake_hash = state
+            .get_handshake_hash()
+            .try_into()
+            .expect("We use SHA256");
+        let remote_static = PublicKey(
+            state
+                .get_remote_static()
+                .expect(
+                    "We could not have settled the KK channel without their key. \
+                     And if we could, better to crash now!",
+                )
+                .try_into()
+                .expect("Our keys aren't 32 bytes anymore?"),
+        );
+        let (initiator_key, responder_key) = state.dangerously_get_raw_split();
+        let transport_state = state.into_stateless_transport_mode()?;
+
+        Ok(KKChannel {
+            transport_state,
+            sending_nonce: 0,
+            receiving_nonce: 0,
+            // The maximum nonce value is reserved by the Noise specification for rekeying
+            nonce_limit: u64::MAX,
+            current_keys: SplitKeys(initiator_key, responder_key),
+            cipher,
+            handshake_hash,
+            version,
+            local_static,
+            remote_static,
+            max_message_size: NOISE_MESSAGE_MAX_SIZE,
+        })
+    }
+
+    /// Export the state of this channel, its keys and nonces along with what it was
+    /// established with, to restore it with [KKChannel::import_secret_state] (eg after
+    /// restarting the process) without a new handshake. The settings, such as
+    /// [KKChannel::set_max_message_size], are not part of it.
+    ///
+    /// **The exported state contains the keys of the session**: anyone getting hold of it can
+    /// decrypt the messages of this session and forge new ones. It must be stored encrypted,
+    /// and deleted once imported. Only the current keys are part of it, so the messages
+    /// exchanged before the last rekey of each direction can't be decrypted with it.
+    /// This consumes the channel, as using it after exporting would reuse the nonces once the
+    /// state is imported, which breaks the encryption. For the same reason a state must never
+    /// be imported twice.
+    pub fn export_secret_state(self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(EXPORTED_STATE_SIZE);
+        state.push(EXPORT_FORMAT_VERSION);
+        state.push(self.is_initiator() as u8);
+        state.push(match self.cipher {
+            CipherChoice::ChaChaPoly => 0,
+            CipherChoice::AESGCM => 1,
+        });
+        state.push(self.version);
+        state.extend_from_slice(&self.current_keys.0);
+        state.extend_from_slice(&self.current_keys.1);
+        state.extend_from_slice(&self.sending_nonce.to_be_bytes());
+        state.extend_from_slice(&self.receiving_nonce.to_be_bytes());
+        state.extend_from_slice(&self.handshake_hash);
+        state.extend_from_slice(&self.local_static.0);
+        state.extend_from_slice(&self.remote_static.0);
+
+        state
+    }
+
+    /// Restore a channel exported with [KKChannel::export_secret_state]. It can then be used
+    /// as if it never stopped, the peer doesn't notice anything.
+    /// Returns [NoiseError::InvalidExportedState] if the state is malformed, and
+    /// [NoiseError::UnsupportedCipherSuite] if its cipher is not available on this machine.
+    pub fn import_secret_state(state: &[u8]) -> Result<KKChannel, NoiseError> {
+        if state.len() != EXPORTED_STATE_SIZE || state[0] != EXPORT_FORMAT_VERSION {
+            return Err(NoiseError::InvalidExportedState);
+        }
+        let is_initiator = match state[1] {
+            0 => false,
+            1 => true,
+            _ => return Err(NoiseError::InvalidExportedState),
+        };
+        let cipher = match state[2] {
+            0 => CipherChoice::ChaChaPoly,
+            1 if CipherSuite::AesGcmSha256.is_available() => CipherChoice::AESGCM,
+            1 => return Err(NoiseError::UnsupportedCipherSuite),
+            _ => return Err(NoiseError::InvalidExportedState),
+        };
+        let version = state[3];
+
+        let mut rest = &state[4..];
+        let mut take = |len: usize| {
+            let (taken, remaining) = rest.split_at(len);
+            rest = remaining;
+            taken
+        };
+        let current_keys = SplitKeys(
+            take(CIPHER_KEY_SIZE).try_into().expect("Checked the size"),
+            take(CIPHER_KEY_SIZE).try_into().expect("Checked the size"),
+        );
+        let sending_nonce = u64::from_be_bytes(take(8).try_into().expect("Checked the size"));
+        let receiving_nonce = u64::from_be_bytes(take(8).try_into().expect("Checked the size"));
+        let handshake_hash = take(HASH_SIZE).try_into().expect("Checked the size");
+        let local_static = public_key_from_bytes(take(KEY_SIZE))?;
+        let remote_static = public_key_from_bytes(take(KEY_SIZE))?;
+
+        let mut transport_state = blank_transport_state(cipher, is_initiator)?;
+        transport_state.rekey_manually(Some(&current_keys.0), Some(&current_keys.1));
+
+        Ok(KKChannel {
+            transport_state,
+            sending_nonce,
+            receiving_nonce,
+            nonce_limit: u64::MAX,
+            current_keys,
+            cipher,
+            handshake_hash,
+            version,
+            local_static,
+            remote_static,
+            max_message_size: NOISE_MESSAGE_MAX_SIZE,
+        })
+    }
+
+    /// Use the channel to encrypt a message shorter than [NOISE_PLAINTEXT_MAX_SIZE].
     /// Pre-fixes the message with a 2-bytes big-endian length field MAC'ed on its own to permit
     /// incremental reads.
     /// On success, returns the ciphertext.
+    /// Each message uses two nonces (one for the header, one for the body). Returns
+    /// [NoiseError::NonceExhausted] if there are not enough left, in which case the channel
+    /// must be rekeyed or a new one must be established.
     pub fn encrypt_message(&mut self, message: &[u8]) -> Result<NoiseEncryptedMessage, NoiseError> {
         if message.len() > NOISE_PLAINTEXT_MAX_SIZE {
             return Err(NoiseError::InvalidPlaintext);
         }
         let mut output = vec![0u8; encrypted_msg_size(message.len())];
+        self.encrypt_message_into(message, &mut output)?;
+
+        Ok(NoiseEncryptedMessage(output))
+    }
+
+    /// Same as [KKChannel::encrypt_message], but returns the encrypted header and body
+    /// separately, eg to send them as distinct writes.
+    pub fn encrypt_framed(
+        &mut self,
+        message: &[u8],
+    ) -> Result<(NoiseEncryptedHeader, NoiseEncryptedMessage), NoiseError> {
+        let mut body = self.encrypt_message(message)?.0;
+        let header = body
+            .drain(..NOISE_MESSAGE_HEADER_SIZE)
+            .collect::<Vec<u8>>()
+            .try_into()
+            .expect("The ciphertext always contains a header");
+
+        Ok((NoiseEncryptedHeader(header), NoiseEncryptedMessage(body)))
+    }
+
+    /// Same as [KKChannel::encrypt_message], but writes the ciphertext into the given buffer.
+    /// Returns [NoiseError::BufferTooSmall] if it's smaller than [encrypted_msg_size].
+    /// On success, returns the size of the ciphertext.
+    pub fn encrypt_message_into(
+        &mut self,
+        message: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, NoiseError> {
+        if message.len() > NOISE_PLAINTEXT_MAX_SIZE {
+            return Err(NoiseError::InvalidPlaintext);
+        }
+        let size = encrypted_msg_size(message.len());
+        if out.len() < size {
+            return Err(NoiseError::BufferTooSmall);
+        }
+        if self.sending_nonce() >= self.nonce_limit - 1 {
+            return Err(NoiseError::NonceExhausted);
+        }
 
         let message_len: u16 = (MAC_SIZE + message.len())
             .try_into()
             .expect("We just checked it was < NOISE_PLAINTEXT_MAX_SIZE");
-        let prefix = message_len.to_be_bytes().to_vec();
-        self.transport_state
-            .write_message(&prefix, &mut output[..NOISE_MESSAGE_HEADER_SIZE])?;
+        let prefix = message_len.to_be_bytes();
+        self.transport_state.write_message(
+            self.sending_nonce,
+            &prefix,
+            &mut out[..NOISE_MESSAGE_HEADER_SIZE],
+        )?;
+        self.sending_nonce += 1;
 
-        self.transport_state
-            .write_message(&message, &mut output[NOISE_MESSAGE_HEADER_SIZE..])?;
+        self.transport_state.write_message(
+            self.sending_nonce,
+            message,
+            &mut out[NOISE_MESSAGE_HEADER_SIZE..size],
+        )?;
+        self.sending_nonce += 1;
 
-        Ok(NoiseEncryptedMessage(output))
+        Ok(size)
     }
 
-    /// Get the size of the message following this header
+    /// Get the size of the message following this header. It includes the MAC of the message,
+    /// see [body_len_from_header].
+    /// Returns [NoiseError::InvalidCiphertext] if the size is too small to contain a MAC, or
+    /// larger than the maximum set by [KKChannel::set_max_message_size].
     pub fn decrypt_header(&mut self, header: &NoiseEncryptedHeader) -> Result<u16, NoiseError> {
-        let mut buf = [0u8; NOISE_MESSAGE_HEADER_SIZE];
-        self.transport_state.read_message(&header.0, &mut buf)?;
+        let mut len_be = [0u8; LENGTH_PREFIX_SIZE];
+        self.read_message(&header.0, &mut len_be)?;
+        let len = u16::from_be_bytes(len_be);
+        if (len as usize) < MAC_SIZE || len as usize > self.max_message_size {
+            return Err(NoiseError::InvalidCiphertext);
+        }
 
-        let len_be: [u8; 2] = buf[..NOISE_MESSAGE_HEADER_SIZE - MAC_SIZE]
-            .try_into()
-            .expect("NOISE_MESSAGE_HEADER_SIZE - MAC_SIZE == LENGTH_PREFIX_SIZE");
-        Ok(u16::from_be_bytes(len_be))
+        Ok(len)
+    }
+
+    /// Set the maximum size of a message body (plaintext and MAC) we accept to read, in order
+    /// to bound the memory a peer can make us allocate. Defaults to (and can't be more than)
+    /// [NOISE_MESSAGE_MAX_SIZE].
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size.min(NOISE_MESSAGE_MAX_SIZE);
+    }
+
+    /// Get the maximum size of a message body we accept to read
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
     }
 
-    /// Get plaintext bytes from a Noise-encrypted message
+    /// Get plaintext bytes from a Noise-encrypted message body, ie what follows the header
+    /// decrypted with [KKChannel::decrypt_header]. The body always contains a MAC, so an empty
+    /// plaintext is encrypted to a [MAC_SIZE] body and decrypts back to an empty `Vec`.
+    /// Returns [NoiseError::InvalidCiphertext] for a body shorter than [MAC_SIZE], such as an
+    /// empty one.
     pub fn decrypt_message(
         &mut self,
         message: &NoiseEncryptedMessage,
     ) -> Result<Vec<u8>, NoiseError> {
-        // TODO: could be in NoiseEncryptedMessage's constructor?
-        if message.0.len() > NOISE_MESSAGE_MAX_SIZE {
+        let mut plaintext = Vec::new();
+        self.decrypt_message_into(message, &mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    /// Get plaintext bytes from a Noise-encrypted message into the given buffer, reusing its
+    /// allocation. The buffer is cleared beforehand.
+    /// Returns the size of the plaintext.
+    pub fn decrypt_message_into(
+        &mut self,
+        message: &NoiseEncryptedMessage,
+        out: &mut Vec<u8>,
+    ) -> Result<usize, NoiseError> {
+        self.decrypt_into(&message.0, out)?;
+        Ok(out.len())
+    }
+
+    /// Get plaintext bytes from a whole Noise-encrypted message, header and body, as
+    /// created by [KKChannel::encrypt_message].
+    /// Returns [NoiseError::InvalidCiphertext] if the size declared by the header doesn't match
+    /// the size of the body.
+    pub fn decrypt_framed(&mut self, full: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        if full.len() < NOISE_MESSAGE_HEADER_SIZE {
             return Err(NoiseError::InvalidCiphertext);
         }
-        if message.0.len() < MAC_SIZE {
+        let (header, body) = full.split_at(NOISE_MESSAGE_HEADER_SIZE);
+        let header = NoiseEncryptedHeader(header.try_into().expect("Just split it"));
+        if body_len_from_header(self.decrypt_header(&header)?) != body.len() {
             return Err(NoiseError::InvalidCiphertext);
         }
-        let mut plaintext = vec![0u8; message.0.len()];
 
-        self.transport_state
-            .read_message(&message.0, &mut plaintext)?;
+        let mut plaintext = Vec::new();
+        self.decrypt_into(body, &mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    // Decrypt a message body into the given buffer
+    pub(crate) fn decrypt_into(
+        &mut self,
+        message: &[u8],
+        plaintext: &mut Vec<u8>,
+    ) -> Result<(), NoiseError> {
+        // Checked again as the field of NoiseEncryptedMessage is public, and the transport
+        // decrypts from its read buffer without building one
+        check_body_size(message.len())?;
+        // The plaintext is the ciphertext without its MAC
+        let plaintext_len = message.len() - MAC_SIZE;
+        plaintext.clear();
+        plaintext.resize(plaintext_len, 0);
+
+        match self.read_message(message, plaintext) {
+            Ok(len) => {
+                plaintext.truncate(len);
+                Ok(())
+            }
+            Err(e) => {
+                plaintext.clear();
+                Err(e)
+            }
+        }
+    }
+
+    // Decrypt a header or a body with the next nonce. As snow's stateful transport did, the
+    // nonce is used up even if the decryption fails.
+    fn read_message(&mut self, message: &[u8], out: &mut [u8]) -> Result<usize, NoiseError> {
+        let res = self
+            .transport_state
+            .read_message(self.receiving_nonce, message, out);
+        self.receiving_nonce += 1;
+        Ok(res?)
+    }
+
+    /// Get the nonce that will be used to encrypt the next message sent, ie the number of
+    /// headers and bodies we encrypted so far. Note that it isn't reset after a rekey.
+    pub fn sending_nonce(&self) -> u64 {
+        self.sending_nonce
+    }
+
+    /// Get the nonce that will be used to decrypt the next message received, ie the number of
+    /// headers and bodies we decrypted so far. Note that it isn't reset after a rekey.
+    pub fn receiving_nonce(&self) -> u64 {
+        self.receiving_nonce
+    }
+
+    /// Rotate the key used to encrypt the messages we send, as per section 11.3 of the Noise
+    /// specification. The peer must call [KKChannel::rekey_incoming] after decrypting the last
+    /// message we encrypted with the previous key.
+    pub fn rekey_outgoing(&mut self) {
+        self.rekey_direction(self.is_initiator());
+    }
+
+    /// Rotate the key used to decrypt the messages we receive. See [KKChannel::rekey_outgoing].
+    pub fn rekey_incoming(&mut self) {
+        self.rekey_direction(!self.is_initiator());
+    }
+
+    // Rotate the key of the initiator to responder direction, or the other one. The previous
+    // key is overwritten.
+    fn rekey_direction(&mut self, initiator_direction: bool) {
+        let key = if initiator_direction {
+            &mut self.current_keys.0
+        } else {
+            &mut self.current_keys.1
+        };
+        *key = next_key(self.cipher, key);
+        if initiator_direction {
+            self.transport_state.rekey_initiator_manually(key);
+        } else {
+            self.transport_state.rekey_responder_manually(key);
+        }
+    }
+
+    /// Get the protocol version negotiated during the handshake
+    pub fn negotiated_version(&self) -> u8 {
+        self.version
+    }
+
+    /// Whether we initiated the handshake this channel was created from
+    pub fn is_initiator(&self) -> bool {
+        self.transport_state.is_initiator()
+    }
+
+    /// Get the hash of the handshake this channel was established with. Both ends of the
+    /// channel agree on it, and it's unique to this session so it can be used for channel
+    /// binding (section 11.2 of the Noise specification).
+    /// It is captured once the handshake completed, as it changes with every handshake message
+    /// until then.
+    pub fn handshake_hash(&self) -> [u8; HASH_SIZE] {
+        self.handshake_hash
+    }
+
+    /// Get the static public key of the peer
+    pub fn remote_static(&self) -> PublicKey {
+        self.remote_static
+    }
+
+    /// Get our static public key this channel was established with, eg to tell which of our
+    /// identities a peer connected to
+    pub fn local_static(&self) -> PublicKey {
+        self.local_static
+    }
+
+    /// Get a short identifier of the peer for logging, see [peer_id]
+    pub fn peer_id(&self) -> String {
+        peer_id(&self.remote_static())
+    }
+}
+
+/// A wrapper over Snow's transport state for a XX Noise communication channel.
+///
+/// The framing is the same as for [KKChannel], which it dereferences to. Since the remote
+/// static key is learned during the handshake, callers will likely want to pin the
+/// [KKChannel::remote_static] of the first session and use the KK pattern afterward.
+#[derive(Debug)]
+pub struct XXChannel(KKChannel);
+
+impl XXChannel {
+    /// Constructs the XX Noise channel from a final stage XX handshake
+    pub fn from_handshake(state: XXHandshakeActThree) -> Result<XXChannel, NoiseError> {
+        // The XX handshake isn't versioned, it's always the first version
+        Ok(XXChannel(KKChannel::new(
+            state.state,
+            0,
+            state.local_static,
+            CipherChoice::ChaChaPoly,
+        )?))
+    }
+}
+
+impl Deref for XXChannel {
+    type Target = KKChannel;
+
+    fn deref(&self) -> &KKChannel {
+        &self.0
+    }
+}
+
+impl DerefMut for XXChannel {
+    fn deref_mut(&mut self) -> &mut KKChannel {
+        &mut self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::NoiseError;
+    use crate::noise::{
+        body_len_from_header, builder, encrypted_msg_size, generate_keypair, peer_id,
+        public_from_secret, public_key_from_bytes, secret_key_from_bytes, validate_pubkeys,
+        CipherSuite, IKMessageActOne, KKChannel, KKHandshakeActOne, KKHandshakeActTwo,
+        KKMessageActOne, KKMessageActTwo, KKResponder, KeyBytes, NoiseEncryptedHeader,
+        NoiseEncryptedMessage, PublicKey, Resolver, SecretKey, XXChannel, XXHandshakeActOne,
+        XXHandshakeActThree, XXHandshakeActTwo, XXMessageActThree, CIPHER_KEY_SIZE,
+        EXPORT_FORMAT_VERSION, HANDSHAKE_MESSAGE, KEY_SIZE, KK_MSG_1_SIZE, KK_MSG_2_SIZE,
+        KK_PARAMS, KK_VERSIONED_MSG_2_SIZE, LEGACY_VERSIONS, MAC_SIZE, MAX_PROTOCOL_VERSION,
+        NOISE_MESSAGE_HEADER_SIZE, NOISE_MESSAGE_MAX_SIZE, NOISE_PLAINTEXT_MAX_SIZE, PEER_ID_SIZE,
+        PER_MESSAGE_OVERHEAD, PROTOCOL_VERSIONS, XX_MSG_3_SIZE,
+    };
+    use snow::{
+        params::CipherChoice,
+        resolvers::{CryptoResolver, SodiumResolver},
+        Builder, HandshakeState,
+    };
+    use std::convert::{TryFrom, TryInto};
+
+    #[test]
+    fn test_key_from_bytes() {
+        let (pubkey, privkey) = generate_keypair();
+        assert_eq!(public_key_from_bytes(pubkey.as_bytes()).unwrap(), pubkey);
+        assert_eq!(
+            secret_key_from_bytes(privkey.as_bytes())
+                .unwrap()
+                .as_bytes(),
+            privkey.as_bytes()
+        );
+
+        for len in &[31, 33, 0] {
+            let bytes = vec![1u8; *len];
+            assert!(matches!(
+                public_key_from_bytes(&bytes),
+                Err(NoiseError::InvalidKey)
+            ));
+            assert!(matches!(
+                secret_key_from_bytes(&bytes),
+                Err(NoiseError::InvalidKey)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_validate_pubkeys() {
+        let keys: Vec<PublicKey> = (0..3).map(|_| generate_keypair().0).collect();
+        validate_pubkeys(&keys).unwrap();
+        validate_pubkeys(&[]).unwrap();
+
+        let zero_key = public_key_from_bytes(&[0u8; KEY_SIZE]).unwrap();
+        assert!(matches!(
+            validate_pubkeys(&[keys[0], zero_key]),
+            Err(NoiseError::InvalidKey)
+        ));
+        // A point of order 4
+        let mut low_order = [0u8; KEY_SIZE];
+        low_order[0] = 1;
+        let low_order = public_key_from_bytes(&low_order).unwrap();
+        assert!(matches!(
+            validate_pubkeys(&[low_order]),
+            Err(NoiseError::InvalidKey)
+        ));
+
+        assert!(matches!(
+            validate_pubkeys(&[keys[0], keys[1], keys[2], keys[1]]),
+            Err(NoiseError::InvalidKey)
+        ));
+    }
+
+    #[test]
+    fn test_peer_static() {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+        let (other_pubkey, _) = generate_keypair();
+
+        let (cli_act_1, msg_1) = KKHandshakeActOne::initiator(
+            &initiator_privkey,
+            &responder_pubkey,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+        assert_eq!(cli_act_1.peer_static(), Some(responder_pubkey));
+
+        let serv_act_1 = KKHandshakeActOne::responder(
+            &responder_privkey,
+            &[other_pubkey, initiator_pubkey],
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+        assert_eq!(serv_act_1.peer_static(), Some(initiator_pubkey));
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+
+        // Wrong size
+        assert!(matches!(
+            public_key_from_bytes(&[1u8; KEY_SIZE - 1]),
+            Err(NoiseError::InvalidKey)
+        ));
+        // Right size, but a low order point
+        let zero_key = public_key_from_bytes(&[0u8; KEY_SIZE]).unwrap();
+
+        assert!(matches!(
+            KKHandshakeActOne::initiator(
+                &initiator_privkey,
+                &zero_key,
+                PROTOCOL_VERSIONS,
+                CipherSuite::default()
+            ),
+            Err(NoiseError::InvalidKey)
+        ));
+        let (_, msg_1) = KKHandshakeActOne::initiator(
+            &initiator_privkey,
+            &responder_pubkey,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+        match KKHandshakeActOne::responder(
+            &responder_privkey,
+            &[zero_key, initiator_pubkey],
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        ) {
+            Err(NoiseError::InvalidKey) => {}
+            e => panic!("Unexpected result: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_encrypt_into() {
+        // Same keys, so we can compare the ciphertexts
+        let keys = (generate_keypair(), generate_keypair());
+        let (mut client_a, mut server_a) = deterministic_kk_channels(&keys);
+        let (mut client_b, mut server_b) = deterministic_kk_channels(&keys);
+
+        let mut buf = [0u8; 2048];
+        for msg in &[&b""[..], &b"Test message"[..], &[42u8; 1024][..]] {
+            let encrypted_msg = client_a.encrypt_message(msg).unwrap();
+            let size = client_b.encrypt_message_into(msg, &mut buf).unwrap();
+            assert_eq!(size, encrypted_msg_size(msg.len()));
+            assert_eq!(&buf[..size], &encrypted_msg.0[..]);
+
+            for (receiver, ciphertext) in &mut [
+                (&mut server_a, &encrypted_msg.0[..]),
+                (&mut server_b, &buf[..size]),
+            ] {
+                let header = NoiseEncryptedHeader(
+                    ciphertext[..NOISE_MESSAGE_HEADER_SIZE].try_into().unwrap(),
+                );
+                receiver.decrypt_header(&header).unwrap();
+                let body = NoiseEncryptedMessage(ciphertext[NOISE_MESSAGE_HEADER_SIZE..].to_vec());
+                assert_eq!(&receiver.decrypt_message(&body).unwrap(), msg);
+            }
+        }
+
+        let msg = b"Some message";
+        let mut buf = vec![0u8; encrypted_msg_size(msg.len()) - 1];
+        assert!(matches!(
+            client_b.encrypt_message_into(msg, &mut buf),
+            Err(NoiseError::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn test_per_message_overhead() {
+        let (mut client_channel, _) = kk_channels();
+
+        assert_eq!(PER_MESSAGE_OVERHEAD, 2 + 2 * MAC_SIZE);
+        for size in &[0, 1, 1024, NOISE_PLAINTEXT_MAX_SIZE] {
+            let encrypted_msg = client_channel.encrypt_message(&vec![1u8; *size]).unwrap();
+            assert_eq!(encrypted_msg.0.len(), encrypted_msg_size(*size));
+            assert_eq!(encrypted_msg.0.len(), size + PER_MESSAGE_OVERHEAD);
+        }
+        assert_eq!(
+            encrypted_msg_size(NOISE_PLAINTEXT_MAX_SIZE),
+            NOISE_MESSAGE_MAX_SIZE
+        );
+    }
+
+    #[test]
+    fn test_keypair() {
+        let (pubkey, privkey) = generate_keypair();
+        assert_eq!(public_from_secret(&privkey), pubkey);
+        assert_ne!(generate_keypair().0, pubkey);
+    }
+
+    #[test]
+    fn test_bidirectional_roundtrip() {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+
+        // client
+        let (cli_act_1, msg_1) = KKHandshakeActOne::initiator(
+            &initiator_privkey,
+            &responder_pubkey,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+
+        // server
+        let serv_act_1 = KKHandshakeActOne::responder(
+            &responder_privkey,
+            &[initiator_pubkey],
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let (serv_act_2, msg_2) = KKHandshakeActTwo::responder(serv_act_1).unwrap();
+        let mut server_channel = KKChannel::from_handshake(serv_act_2).unwrap();
+
+        // client
+        let cli_act_2 = KKHandshakeActTwo::initiator(cli_act_1, &msg_2).unwrap();
+        let mut client_channel = KKChannel::from_handshake(cli_act_2).unwrap();
+
+        // client encrypts message for server
+        let msg = "Hello".as_bytes();
+        let encrypted_msg = client_channel.encrypt_message(msg).unwrap();
+        assert_eq!(
+            encrypted_msg.0.len(),
+            msg.len() + NOISE_MESSAGE_HEADER_SIZE + MAC_SIZE
+        );
+        let (header, body) = (
+            &encrypted_msg.0[..NOISE_MESSAGE_HEADER_SIZE],
+            &encrypted_msg.0[NOISE_MESSAGE_HEADER_SIZE..],
+        );
+        eprintln!("{:x?}", header);
+        let msg_len = server_channel
+            .decrypt_header(&NoiseEncryptedHeader(header.try_into().unwrap()))
+            .unwrap();
+        assert_eq!(msg_len as usize, msg.len() + MAC_SIZE);
+        let decrypted_msg = server_channel
+            .decrypt_message(&NoiseEncryptedMessage(body.to_vec()))
+            .unwrap();
+        assert_eq!(msg.to_vec(), decrypted_msg);
+
+        // server encrypts message for client
+        let msg = "Goodbye".as_bytes();
+        let encrypted_msg = server_channel.encrypt_message(msg).unwrap();
+        let (header, body) = (
+            &encrypted_msg.0[..NOISE_MESSAGE_HEADER_SIZE],
+            &encrypted_msg.0[NOISE_MESSAGE_HEADER_SIZE..],
+        );
+        let msg_len = client_channel
+            .decrypt_header(&NoiseEncryptedHeader(header.try_into().unwrap()))
+            .unwrap();
+        assert_eq!(msg_len as usize, msg.len() + MAC_SIZE);
+        let decrypted_msg = client_channel
+            .decrypt_message(&NoiseEncryptedMessage(body.to_vec()))
+            .unwrap();
+        assert_eq!(msg.to_vec(), decrypted_msg);
+    }
+
+    #[test]
+    fn test_message_size_limit() {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+
+        // client
+        let (_, msg_1) = KKHandshakeActOne::initiator(
+            &initiator_privkey,
+            &responder_pubkey,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+
+        // server
+        let serv_act_1 = KKHandshakeActOne::responder(
+            &responder_privkey,
+            &[initiator_pubkey],
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let (serv_act_2, _msg_2) = KKHandshakeActTwo::responder(serv_act_1).unwrap();
+        let mut server_channel = KKChannel::from_handshake(serv_act_2).unwrap();
+
+        // Hit the limit
+        let msg = [0u8; NOISE_PLAINTEXT_MAX_SIZE];
+        server_channel
+            .encrypt_message(&msg)
+            .expect("Maximum allowed");
+
+        // Fail if msg too large
+        let msg = [0u8; NOISE_MESSAGE_MAX_SIZE - NOISE_MESSAGE_HEADER_SIZE + 1];
+        server_channel
+            .encrypt_message(&msg)
+            .expect_err("Limit exceeded");
+
+        // We can encrypt an empty message
+        let msg = b"";
+        server_channel
+            .encrypt_message(msg)
+            .expect("Empty message is fine to encrypt");
+
+        // A body can't be empty as it always contains a MAC, see test_empty_message
+        assert!(matches!(
+            server_channel.decrypt_message(&NoiseEncryptedMessage(msg.to_vec())),
+            Err(NoiseError::InvalidCiphertext)
+        ));
+    }
+
+    #[test]
+    fn test_empty_message() {
+        let (mut client_channel, mut server_channel) = KKChannel::fuzzing_pair();
+
+        let encrypted_msg = client_channel.encrypt_message(b"").unwrap();
+        assert_eq!(encrypted_msg.0.len(), NOISE_MESSAGE_HEADER_SIZE + MAC_SIZE);
+        let (header, body) = encrypted_msg.0.split_at(NOISE_MESSAGE_HEADER_SIZE);
+        let header = NoiseEncryptedHeader(header.try_into().unwrap());
+        let body_len = server_channel.decrypt_header(&header).unwrap();
+        assert_eq!(body_len_from_header(body_len), MAC_SIZE);
+        let plaintext = server_channel
+            .decrypt_message(&NoiseEncryptedMessage(body.to_vec()))
+            .unwrap();
+        assert_eq!(plaintext, Vec::<u8>::new());
+
+        // Same through the framed API
+        let (header, body) = server_channel.encrypt_framed(b"").unwrap();
+        assert_eq!(
+            client_channel.decrypt_header(&header).unwrap() as usize,
+            MAC_SIZE
+        );
+        assert!(client_channel.decrypt_message(&body).unwrap().is_empty());
+        let encrypted_msg = server_channel.encrypt_message(b"").unwrap();
+        assert!(client_channel
+            .decrypt_framed(&encrypted_msg.0)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_bad_messages() {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+
+        // KK handshake fails if messages are badly formed.
+        // Without a valid cli_act_2 nor serv_act_2, no KKChannel can be constructed.
+        let (cli_act_1, _) = KKHandshakeActOne::initiator(
+            &initiator_privkey,
+            &responder_pubkey,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .expect("The first act is valid.");
+
+        let bad_msg = KKMessageActOne([1u8; KK_MSG_1_SIZE]);
+        KKHandshakeActOne::responder(
+            &responder_privkey,
+            &[initiator_pubkey],
+            &bad_msg,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .expect_err("This one is invalid as bad_msg cannot be decrypted.");
+
+        let bad_msg = KKMessageActTwo(vec![1u8; KK_MSG_2_SIZE]);
+        KKHandshakeActTwo::initiator(cli_act_1, &bad_msg).expect_err("So is this one.");
+    }
+
+    #[test]
+    fn test_header_bad_length() {
+        let (mut client_channel, mut server_channel) = kk_channels();
+
+        // A header declaring an empty body (which can't even contain the MAC)
+        let mut header = [0u8; NOISE_MESSAGE_HEADER_SIZE];
+        client_channel
+            .transport_state
+            .write_message(0, &0u16.to_be_bytes(), &mut header)
+            .unwrap();
+        client_channel.sending_nonce += 1;
+        assert!(matches!(
+            server_channel.decrypt_header(&NoiseEncryptedHeader(header)),
+            Err(NoiseError::InvalidCiphertext)
+        ));
+
+        // A body larger than what we accept
+        server_channel.set_max_message_size(100);
+        assert_eq!(server_channel.max_message_size(), 100);
+        let encrypted_msg = client_channel.encrypt_message(&[0u8; 100]).unwrap();
+        let header = NoiseEncryptedHeader(
+            encrypted_msg.0[..NOISE_MESSAGE_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        assert!(matches!(
+            server_channel.decrypt_header(&header),
+            Err(NoiseError::InvalidCiphertext)
+        ));
+
+        // But smaller ones are fine (the channel is unusable after a failed read)
+        let (mut client_channel, mut server_channel) = kk_channels();
+        server_channel.set_max_message_size(100);
+        assert_eq!(
+            roundtrip(&mut client_channel, &mut server_channel, &[0u8; 84]),
+            vec![0u8; 84]
+        );
+        server_channel.set_max_message_size(NOISE_MESSAGE_MAX_SIZE + 1);
+        assert_eq!(server_channel.max_message_size(), NOISE_MESSAGE_MAX_SIZE);
+    }
+
+    #[test]
+    fn test_decrypt_framed() {
+        let (mut client_channel, mut server_channel) = kk_channels();
+
+        let encrypted_msg = client_channel.encrypt_message(b"framed").unwrap();
+        assert_eq!(
+            server_channel.decrypt_framed(&encrypted_msg.0).unwrap(),
+            b"framed".to_vec()
+        );
+
+        // The declared size doesn't match the actual body
+        let encrypted_msg = client_channel.encrypt_message(b"framed").unwrap();
+        assert!(matches!(
+            server_channel.decrypt_framed(&encrypted_msg.0[..encrypted_msg.0.len() - 1]),
+            Err(NoiseError::InvalidCiphertext)
+        ));
+        assert!(matches!(
+            server_channel.decrypt_framed(&[0u8; NOISE_MESSAGE_HEADER_SIZE - 1]),
+            Err(NoiseError::InvalidCiphertext)
+        ));
+    }
+
+    #[test]
+    fn test_encrypt_framed() {
+        let (mut client_channel, mut server_channel) = kk_channels();
+
+        let (header, body) = client_channel.encrypt_framed(b"framed").unwrap();
+        assert_eq!(
+            server_channel.decrypt_header(&header).unwrap() as usize,
+            body.0.len()
+        );
+        assert_eq!(
+            server_channel.decrypt_message(&body).unwrap(),
+            b"framed".to_vec()
+        );
+
+        client_channel
+            .encrypt_framed(&[0u8; NOISE_PLAINTEXT_MAX_SIZE + 1])
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_responder_single() {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+        let (other_pubkey, _) = generate_keypair();
+
+        let (cli_act_1, msg_1) = KKHandshakeActOne::initiator(
+            &initiator_privkey,
+            &responder_pubkey,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+
+        // Not the key of the peer. It's not unknown, the handshake is just not valid.
+        assert!(matches!(
+            KKHandshakeActOne::responder_single(
+                &responder_privkey,
+                &other_pubkey,
+                &msg_1,
+                PROTOCOL_VERSIONS,
+                &[],
+                CipherSuite::default()
+            ),
+            Err(NoiseError::BadHandshake)
+        ));
+
+        let serv_act_1 = KKHandshakeActOne::responder_single(
+            &responder_privkey,
+            &initiator_pubkey,
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            &[],
+            CipherSuite::default(),
+        )
+        .unwrap();
+        assert_eq!(serv_act_1.peer_static(), Some(initiator_pubkey));
+        let (serv_act_2, msg_2) = KKHandshakeActTwo::responder(serv_act_1).unwrap();
+        let cli_act_2 = KKHandshakeActTwo::initiator(cli_act_1, &msg_2).unwrap();
+        let mut client_channel = KKChannel::from_handshake(cli_act_2).unwrap();
+        let mut server_channel = KKChannel::from_handshake(serv_act_2).unwrap();
+        assert_eq!(
+            roundtrip(&mut client_channel, &mut server_channel, b"single"),
+            b"single".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_body_len_from_header() {
+        let (mut client_channel, mut server_channel) = kk_channels();
+
+        // The length in the header is the size of the body on the wire, MAC included
+        for size in &[0, 1, 100, NOISE_PLAINTEXT_MAX_SIZE] {
+            let encrypted_msg = client_channel.encrypt_message(&vec![0u8; *size]).unwrap();
+            let header = NoiseEncryptedHeader(
+                encrypted_msg.0[..NOISE_MESSAGE_HEADER_SIZE]
+                    .try_into()
+                    .unwrap(),
+            );
+            let header_len = server_channel.decrypt_header(&header).unwrap();
+            assert_eq!(header_len as usize, size + MAC_SIZE);
+            assert_eq!(
+                body_len_from_header(header_len),
+                encrypted_msg.0.len() - NOISE_MESSAGE_HEADER_SIZE
+            );
+            let body = NoiseEncryptedMessage(encrypted_msg.0[NOISE_MESSAGE_HEADER_SIZE..].to_vec());
+            assert_eq!(server_channel.decrypt_message(&body).unwrap().len(), *size);
+        }
+    }
+
+    #[test]
+    fn test_fuzzing_pair() {
+        let (mut client_channel, mut server_channel) = KKChannel::fuzzing_pair();
+        assert_eq!(
+            roundtrip(&mut client_channel, &mut server_channel, b"fuzz"),
+            b"fuzz".to_vec()
+        );
+
+        // Always in the same state
+        let (mut other_client_channel, _) = KKChannel::fuzzing_pair();
+        assert_eq!(
+            client_channel.handshake_hash(),
+            other_client_channel.handshake_hash()
+        );
+        let encrypted_msg = other_client_channel.encrypt_message(b"fuzz").unwrap();
+        assert_eq!(
+            encrypted_msg.0,
+            KKChannel::fuzzing_pair()
+                .0
+                .encrypt_message(b"fuzz")
+                .unwrap()
+                .0
+        );
+    }
+
+    #[test]
+    fn test_decrypt_any_size() {
+        let (_, mut server_channel) = kk_channels();
+
+        // The boundaries, and random sizes in between
+        let mut sizes = vec![
+            0,
+            1,
+            MAC_SIZE - 1,
+            MAC_SIZE,
+            MAC_SIZE + 1,
+            NOISE_MESSAGE_MAX_SIZE - 1,
+            NOISE_MESSAGE_MAX_SIZE,
+            NOISE_MESSAGE_MAX_SIZE + 1,
+        ];
+        sizes.extend((0..200).map(|_| {
+            sodiumoxide::randombytes::randombytes_uniform(NOISE_MESSAGE_MAX_SIZE as u32 + 1)
+                as usize
+        }));
+
+        for size in sizes {
+            // Garbage never decrypts, but must not panic
+            let garbage = NoiseEncryptedMessage(sodiumoxide::randombytes::randombytes(size));
+            assert!(server_channel.decrypt_message(&garbage).is_err());
+
+            // An actual message of this size decrypts to the exact plaintext
+            if size >= MAC_SIZE && size - MAC_SIZE <= NOISE_PLAINTEXT_MAX_SIZE {
+                let (mut client_channel, mut server_channel) = kk_channels();
+                let plaintext = sodiumoxide::randombytes::randombytes(size - MAC_SIZE);
+                assert_eq!(
+                    roundtrip(&mut client_channel, &mut server_channel, &plaintext),
+                    plaintext
+                );
+            }
+        }
+
+        // Reusing a larger buffer leaves no garbage in it, whether decryption succeeds or not
+        let (mut client_channel, mut server_channel) = kk_channels();
+        let mut buf = vec![0xff; 100];
+        let encrypted_msg = client_channel.encrypt_message(b"small").unwrap();
+        let body = NoiseEncryptedMessage(encrypted_msg.0[NOISE_MESSAGE_HEADER_SIZE..].to_vec());
+        kk_channels()
+            .1
+            .decrypt_message_into(&body, &mut buf)
+            .unwrap_err();
+        assert!(buf.is_empty());
+        buf.resize(100, 0xff);
+        let header = NoiseEncryptedHeader(
+            encrypted_msg.0[..NOISE_MESSAGE_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        server_channel.decrypt_header(&header).unwrap();
+        assert_eq!(
+            server_channel
+                .decrypt_message_into(&body, &mut buf)
+                .unwrap(),
+            5
+        );
+        assert_eq!(buf, b"small".to_vec());
+    }
+
+    #[test]
+    fn test_cipher_suites() {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+        let handshake = |init_suite, resp_suite| {
+            let (cli_act_1, msg_1) = KKHandshakeActOne::initiator(
+                &initiator_privkey,
+                &responder_pubkey,
+                PROTOCOL_VERSIONS,
+                init_suite,
+            )?;
+            let serv_act_1 = KKHandshakeActOne::responder(
+                &responder_privkey,
+                &[initiator_pubkey],
+                &msg_1,
+                PROTOCOL_VERSIONS,
+                resp_suite,
+            )?;
+            let (serv_act_2, msg_2) = KKHandshakeActTwo::responder(serv_act_1)?;
+            let cli_act_2 = KKHandshakeActTwo::initiator(cli_act_1, &msg_2)?;
+            Ok::<_, NoiseError>((
+                KKChannel::from_handshake(cli_act_2)?,
+                KKChannel::from_handshake(serv_act_2)?,
+            ))
+        };
+
+        let suites = [CipherSuite::ChaChaPolySha256, CipherSuite::AesGcmSha256];
+        assert_eq!(CipherSuite::default(), CipherSuite::ChaChaPolySha256);
+        assert!(CipherSuite::ChaChaPolySha256.is_available());
+        for suite in suites.iter().filter(|s| s.is_available()) {
+            let (mut client_channel, mut server_channel) = handshake(*suite, *suite).unwrap();
+            assert_eq!(
+                roundtrip(&mut client_channel, &mut server_channel, b"suite"),
+                b"suite".to_vec()
+            );
+            assert_eq!(
+                roundtrip(&mut server_channel, &mut client_channel, &[1; 1000]),
+                vec![1; 1000]
+            );
+        }
+
+        if CipherSuite::AesGcmSha256.is_available() {
+            // A mismatch can't be told apart from an unknown peer
+            assert!(matches!(
+                handshake(CipherSuite::AesGcmSha256, CipherSuite::ChaChaPolySha256),
+                Err(NoiseError::MissingStaticKey)
+            ));
+            assert!(matches!(
+                handshake(CipherSuite::ChaChaPolySha256, CipherSuite::AesGcmSha256),
+                Err(NoiseError::MissingStaticKey)
+            ));
+        } else {
+            assert!(matches!(
+                handshake(CipherSuite::AesGcmSha256, CipherSuite::AesGcmSha256),
+                Err(NoiseError::UnsupportedCipherSuite)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_kk_responder() {
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+        let initiators: Vec<(PublicKey, SecretKey)> = (0..20).map(|_| generate_keypair()).collect();
+        let pubkeys: Vec<PublicKey> = initiators.iter().map(|(pubkey, _)| *pubkey).collect();
+
+        let zero_key = public_key_from_bytes(&[0u8; KEY_SIZE]).unwrap();
+        assert!(matches!(
+            KKResponder::new(
+                &responder_privkey,
+                &[pubkeys[0], zero_key],
+                PROTOCOL_VERSIONS,
+                &[],
+                CipherSuite::default()
+            ),
+            Err(NoiseError::InvalidKey)
+        ));
+
+        let responder = KKResponder::new(
+            &responder_privkey,
+            &pubkeys,
+            PROTOCOL_VERSIONS,
+            &[],
+            CipherSuite::default(),
+        )
+        .unwrap();
+        assert_eq!(responder.possible_pubkeys(), &pubkeys[..]);
+
+        // The same responder is used for many handshakes
+        for (initiator_pubkey, initiator_privkey) in initiators.iter().rev() {
+            let (cli_act_1, msg_1) = KKHandshakeActOne::initiator(
+                initiator_privkey,
+                &responder_pubkey,
+                PROTOCOL_VERSIONS,
+                CipherSuite::default(),
+            )
+            .unwrap();
+            let serv_act_1 = responder.respond(&msg_1).unwrap();
+            assert_eq!(serv_act_1.peer_static(), Some(*initiator_pubkey));
+            let (serv_act_2, msg_2) = KKHandshakeActTwo::responder(serv_act_1).unwrap();
+            let cli_act_2 = KKHandshakeActTwo::initiator(cli_act_1, &msg_2).unwrap();
+            let mut client_channel = KKChannel::from_handshake(cli_act_2).unwrap();
+            let mut server_channel = KKChannel::from_handshake(serv_act_2).unwrap();
+            assert_eq!(
+                roundtrip(&mut client_channel, &mut server_channel, b"cached"),
+                b"cached".to_vec()
+            );
+        }
+
+        let (_, unknown_privkey) = generate_keypair();
+        let (_, msg_1) = KKHandshakeActOne::initiator(
+            &unknown_privkey,
+            &responder_pubkey,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            responder.respond(&msg_1),
+            Err(NoiseError::MissingStaticKey)
+        ));
+    }
+
+    #[test]
+    fn test_peer_id() {
+        let (pubkey, _) = generate_keypair();
+        let (other_pubkey, _) = generate_keypair();
+        assert_eq!(peer_id(&pubkey), peer_id(&pubkey));
+        assert_ne!(peer_id(&pubkey), peer_id(&other_pubkey));
+        assert_eq!(peer_id(&pubkey).len(), PEER_ID_SIZE * 2);
+
+        // It must not change across versions
+        let key = public_key_from_bytes(&[1u8; KEY_SIZE]).unwrap();
+        assert_eq!(peer_id(&key), "72cd6e8422c407fb");
+
+        let (client_channel, server_channel) = KKChannel::fuzzing_pair();
+        assert_eq!(
+            client_channel.peer_id(),
+            peer_id(&client_channel.remote_static())
+        );
+        assert_ne!(client_channel.peer_id(), server_channel.peer_id());
+    }
+
+    #[test]
+    fn test_local_static() {
+        let (client_channel, server_channel) = KKChannel::fuzzing_pair();
+        assert_eq!(
+            client_channel.local_static(),
+            server_channel.remote_static()
+        );
+        assert_eq!(
+            server_channel.local_static(),
+            client_channel.remote_static()
+        );
+        assert_eq!(
+            client_channel.local_static(),
+            public_from_secret(&SecretKey([1; KEY_SIZE]))
+        );
+    }
+
+    // Records the log messages of the current thread, as the tests run concurrently
+    struct CapturingLogger;
+
+    thread_local! {
+        static LOGS: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGS.with(|logs| logs.borrow_mut().push(record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_handshake_logs() {
+        if log::set_logger(&CapturingLogger).is_ok() {
+            log::set_max_level(log::LevelFilter::Trace);
+        }
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+        let candidates = [generate_keypair().0, generate_keypair().0, initiator_pubkey];
+
+        let (cli_act_1, msg_1) = KKHandshakeActOne::initiator(
+            &initiator_privkey,
+            &responder_pubkey,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let serv_act_1 = KKHandshakeActOne::responder(
+            &responder_privkey,
+            &candidates,
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let (_, msg_2) = KKHandshakeActTwo::responder(serv_act_1).unwrap();
+        KKHandshakeActTwo::initiator(cli_act_1, &msg_2).unwrap();
+        KKHandshakeActOne::responder(
+            &responder_privkey,
+            &candidates[..2],
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap_err();
+
+        let logs = LOGS.with(|logs| logs.borrow().clone());
+        let expected = [
+            format!(
+                "Starting KK handshake act one as initiator with {}",
+                peer_id(&responder_pubkey)
+            ),
+            format!(
+                "Wrote KK handshake act one to {}, offering versions 0b00000011",
+                peer_id(&responder_pubkey)
+            ),
+            "Reading KK handshake act one as responder, with 3 candidate keys".to_string(),
+            format!(
+                "Read KK handshake act one from {}, candidate key 2 out of 3",
+                peer_id(&initiator_pubkey)
+            ),
+            "Writing KK handshake act two as responder".to_string(),
+            "Wrote KK handshake act two, negotiated version 1".to_string(),
+            "Reading KK handshake act two as initiator".to_string(),
+            "Read KK handshake act two, negotiated version 1".to_string(),
+            "Reading KK handshake act one as responder, with 2 candidate keys".to_string(),
+            format!(
+                "No candidate key out of 2 matched KK handshake act one: {}",
+                NoiseError::MissingStaticKey
+            ),
+        ];
+        assert_eq!(logs, expected);
+
+        // The secret keys are never logged, in any form
+        for privkey in [&initiator_privkey, &responder_privkey] {
+            let hex: String = privkey.0.iter().map(|b| format!("{:02x}", b)).collect();
+            assert!(logs.iter().all(|l| !l.contains(&hex[..16])));
+        }
+    }
+
+    #[test]
+    fn test_export_secret_state() {
+        let (mut client_channel, mut server_channel) = kk_channels();
+        let roundtrip = |sender: &mut KKChannel, receiver: &mut KKChannel, msg: &[u8]| {
+            let encrypted_msg = sender.encrypt_message(msg).unwrap();
+            assert_eq!(receiver.decrypt_framed(&encrypted_msg.0).unwrap(), msg);
+        };
+        roundtrip(&mut client_channel, &mut server_channel, b"before");
+        server_channel.rekey_outgoing();
+        client_channel.rekey_incoming();
+        roundtrip(&mut server_channel, &mut client_channel, b"rekeyed");
+
+        // Encrypted before the export, decrypted after the import
+        let in_flight = client_channel.encrypt_message(b"in flight").unwrap();
+        let (handshake_hash, sending_nonce, receiving_nonce) = (
+            server_channel.handshake_hash(),
+            server_channel.sending_nonce(),
+            server_channel.receiving_nonce(),
+        );
+        let (local_static, remote_static) = (
+            server_channel.local_static(),
+            server_channel.remote_static(),
+        );
+        let state = server_channel.export_secret_state();
+        let mut server_channel = KKChannel::import_secret_state(&state).unwrap();
+        assert!(!server_channel.is_initiator());
+        assert_eq!(server_channel.handshake_hash(), handshake_hash);
+        assert_eq!(server_channel.sending_nonce(), sending_nonce);
+        assert_eq!(server_channel.receiving_nonce(), receiving_nonce);
+        assert_eq!(server_channel.local_static(), local_static);
+        assert_eq!(server_channel.remote_static(), remote_static);
+        assert_eq!(
+            server_channel.decrypt_framed(&in_flight.0).unwrap(),
+            b"in flight"
+        );
+        roundtrip(&mut server_channel, &mut client_channel, b"after");
+
+        // Same for the initiator, and rekeying still works afterwards
+        let client_channel = client_channel.export_secret_state();
+        let mut client_channel = KKChannel::import_secret_state(&client_channel).unwrap();
+        assert!(client_channel.is_initiator());
+        roundtrip(&mut client_channel, &mut server_channel, b"after");
+        client_channel.rekey_outgoing();
+        server_channel.rekey_incoming();
+        roundtrip(&mut client_channel, &mut server_channel, b"rekeyed again");
+        roundtrip(&mut server_channel, &mut client_channel, b"rekeyed again");
+
+        let state = server_channel.export_secret_state();
+        for invalid_state in [
+            &state[..state.len() - 1],
+            &[&[EXPORT_FORMAT_VERSION + 1][..], &state[1..]].concat(),
+        ] {
+            assert!(matches!(
+                KKChannel::import_secret_state(invalid_state),
+                Err(NoiseError::InvalidExportedState)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_export_after_rekey() {
+        let (mut client_channel, mut server_channel) = kk_channels();
+        let initial_keys = [client_channel.current_keys.0, client_channel.current_keys.1];
+
+        // Our rekeying derives the same keys as snow's
+        client_channel.rekey_outgoing();
+        client_channel.rekey_incoming();
+        server_channel.transport_state.rekey_incoming();
+        server_channel.transport_state.rekey_outgoing();
+        let msg = b"rekeyed";
+        assert_eq!(
+            roundtrip(&mut client_channel, &mut server_channel, msg),
+            msg.to_vec()
+        );
+        assert_eq!(
+            roundtrip(&mut server_channel, &mut client_channel, msg),
+            msg.to_vec()
+        );
+
+        // The keys of before the rekey are gone, the exported state can't decrypt past traffic
+        let state = client_channel.export_secret_state();
+        for key in initial_keys.iter() {
+            assert!(!state.windows(CIPHER_KEY_SIZE).any(|w| w == key));
+        }
+        let mut client_channel = KKChannel::import_secret_state(&state).unwrap();
+        assert_eq!(
+            roundtrip(&mut client_channel, &mut server_channel, b"imported"),
+            b"imported".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_chachapoly_compat() {
+        // Our in-place ChaChaPoly is interchangeable with snow's
+        let (mut ours, mut snows) = (
+            Resolver.resolve_cipher(&CipherChoice::ChaChaPoly).unwrap(),
+            SodiumResolver
+                .resolve_cipher(&CipherChoice::ChaChaPoly)
+                .unwrap(),
+        );
+        ours.set(&[7; 32]);
+        snows.set(&[7; 32]);
 
-        // We read the MAC, but caller doesn't care about it
-        // FIXME: add a test for invalid MAC getting refused
-        plaintext.truncate(plaintext.len() - MAC_SIZE);
-        Ok(plaintext)
+        let (plaintext, authtext) = (b"Hello", b"Authenticated");
+        let (mut ciphertext, mut snow_ciphertext) = ([0u8; 5 + MAC_SIZE], [0u8; 5 + MAC_SIZE]);
+        assert_eq!(ours.encrypt(42, authtext, plaintext, &mut ciphertext), 21);
+        snows.encrypt(42, authtext, plaintext, &mut snow_ciphertext);
+        assert_eq!(ciphertext, snow_ciphertext);
+        let mut out = [0u8; 5];
+        assert_eq!(snows.decrypt(42, authtext, &ciphertext, &mut out), Ok(5));
+        assert_eq!(ours.decrypt(42, authtext, &ciphertext, &mut out), Ok(5));
+        assert_eq!(&out, plaintext);
+
+        // A wrong nonce, authtext or MAC, or no MAC at all, fails
+        ours.decrypt(43, authtext, &ciphertext, &mut out)
+            .unwrap_err();
+        ours.decrypt(42, b"", &ciphertext, &mut out).unwrap_err();
+        ciphertext[5] ^= 1;
+        ours.decrypt(42, authtext, &ciphertext, &mut out)
+            .unwrap_err();
+        ours.decrypt(42, authtext, &ciphertext[..MAC_SIZE - 1], &mut out)
+            .unwrap_err();
+
+        // Rekeying goes through encrypt
+        ours.rekey();
+        snows.rekey();
+        ours.encrypt(0, &[], plaintext, &mut ciphertext);
+        snows.encrypt(0, &[], plaintext, &mut snow_ciphertext);
+        assert_eq!(ciphertext, snow_ciphertext);
     }
 
-    /// Get the static public key of the peer
-    pub fn remote_static(&self) -> PublicKey {
-        PublicKey(
-            self.transport_state
-                .get_remote_static()
-                .expect(
-                    "We could not have settled the KK channel without their key. \
-                     And if we could, better to crash now!",
-                )
+    #[test]
+    fn test_encrypted_message_new() {
+        for size in [0, MAC_SIZE - 1, NOISE_MESSAGE_MAX_SIZE + 1] {
+            assert!(matches!(
+                NoiseEncryptedMessage::new(vec![0; size]),
+                Err(NoiseError::InvalidCiphertext)
+            ));
+        }
+        for size in [MAC_SIZE, 100, NOISE_MESSAGE_MAX_SIZE] {
+            assert_eq!(
+                NoiseEncryptedMessage::new(vec![0; size]).unwrap().0.len(),
+                size
+            );
+        }
+
+        // A valid one decrypts as usual
+        let (mut client_channel, mut server_channel) = kk_channels();
+        let encrypted_msg = client_channel.encrypt_message(b"Hello").unwrap();
+        let header = NoiseEncryptedHeader(
+            encrypted_msg.0[..NOISE_MESSAGE_HEADER_SIZE]
                 .try_into()
-                .expect("Our keys aren't 32 bytes anymore?"),
-        )
+                .unwrap(),
+        );
+        let body =
+            NoiseEncryptedMessage::new(encrypted_msg.0[NOISE_MESSAGE_HEADER_SIZE..].to_vec())
+                .unwrap();
+        server_channel.decrypt_header(&header).unwrap();
+        assert_eq!(server_channel.decrypt_message(&body).unwrap(), b"Hello");
     }
-}
-
-#[cfg(test)]
-pub mod tests {
-    use crate::noise::{
-        KKChannel, KKHandshakeActOne, KKHandshakeActTwo, KKMessageActOne, KKMessageActTwo,
-        NoiseEncryptedHeader, NoiseEncryptedMessage, KK_MSG_1_SIZE, KK_MSG_2_SIZE, MAC_SIZE,
-        NOISE_MESSAGE_HEADER_SIZE, NOISE_MESSAGE_MAX_SIZE, NOISE_PLAINTEXT_MAX_SIZE,
-    };
-    use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::gen_keypair;
-    use std::convert::TryInto;
 
     #[test]
-    fn test_bidirectional_roundtrip() {
-        let (initiator_pubkey, initiator_privkey) = gen_keypair();
-        let (responder_pubkey, responder_privkey) = gen_keypair();
+    fn test_open_enrollment() {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
 
-        // client
-        let (cli_act_1, msg_1) =
-            KKHandshakeActOne::initiator(&initiator_privkey, &responder_pubkey).unwrap();
+        // The responder doesn't know the initiator, and learns its key from the first message
+        let (cli_act_1, msg_1) = KKHandshakeActOne::initiator_open(
+            &initiator_privkey,
+            &responder_pubkey,
+            PROTOCOL_VERSIONS,
+            &[],
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let msg_1 = IKMessageActOne::from_bytes(msg_1.as_bytes()).unwrap();
+        let (serv_act_1, their_pubkey) = KKHandshakeActOne::responder_open(
+            &responder_privkey,
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            &[],
+            CipherSuite::default(),
+        )
+        .unwrap();
+        assert_eq!(their_pubkey, initiator_pubkey);
+        assert_eq!(serv_act_1.peer_static(), Some(initiator_pubkey));
 
-        // server
-        let serv_act_1 =
-            KKHandshakeActOne::responder(&responder_privkey, &[initiator_pubkey], &msg_1).unwrap();
+        // The rest is a KK handshake
         let (serv_act_2, msg_2) = KKHandshakeActTwo::responder(serv_act_1).unwrap();
-        let mut server_channel = KKChannel::from_handshake(serv_act_2).unwrap();
-
-        // client
         let cli_act_2 = KKHandshakeActTwo::initiator(cli_act_1, &msg_2).unwrap();
         let mut client_channel = KKChannel::from_handshake(cli_act_2).unwrap();
-
-        // client encrypts message for server
-        let msg = "Hello".as_bytes();
-        let encrypted_msg = client_channel.encrypt_message(&msg).unwrap();
+        let mut server_channel = KKChannel::from_handshake(serv_act_2).unwrap();
+        assert_eq!(client_channel.remote_static(), responder_pubkey);
+        assert_eq!(server_channel.remote_static(), initiator_pubkey);
+        let msg = client_channel.encrypt_message(b"Enroll me").unwrap();
         assert_eq!(
-            encrypted_msg.0.len(),
-            msg.len() + NOISE_MESSAGE_HEADER_SIZE + MAC_SIZE
+            server_channel.decrypt_framed(&msg.0).unwrap(),
+            b"Enroll me".to_vec()
         );
+
+        // The initiator still authenticates the responder
+        let (_, msg_1) = KKHandshakeActOne::initiator_open(
+            &initiator_privkey,
+            &generate_keypair().0,
+            PROTOCOL_VERSIONS,
+            &[],
+            CipherSuite::default(),
+        )
+        .unwrap();
+        match KKHandshakeActOne::responder_open(
+            &responder_privkey,
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            &[],
+            CipherSuite::default(),
+        ) {
+            Err(NoiseError::BadHandshake) => {}
+            e => panic!("Unexpected result: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_xx_roundtrip() {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+
+        // The initiator does not need to know the responder's key beforehand
+        let (cli_act_1, msg_1) = XXHandshakeActOne::initiator(&initiator_privkey).unwrap();
+        let serv_act_1 = XXHandshakeActOne::responder(&responder_privkey, &msg_1).unwrap();
+        let (serv_act_2, msg_2) = XXHandshakeActTwo::responder(serv_act_1).unwrap();
+        let cli_act_2 = XXHandshakeActTwo::initiator(cli_act_1, &msg_2).unwrap();
+        let (cli_act_3, msg_3) = XXHandshakeActThree::initiator(cli_act_2).unwrap();
+        let serv_act_3 = XXHandshakeActThree::responder(serv_act_2, &msg_3).unwrap();
+
+        let mut client_channel = XXChannel::from_handshake(cli_act_3).unwrap();
+        let mut server_channel = XXChannel::from_handshake(serv_act_3).unwrap();
+
+        // Both learned the other's static key
+        assert_eq!(client_channel.remote_static(), responder_pubkey);
+        assert_eq!(server_channel.remote_static(), initiator_pubkey);
+        assert_eq!(client_channel.local_static(), initiator_pubkey);
+        assert_eq!(server_channel.local_static(), responder_pubkey);
+        assert!(client_channel.is_initiator() && !server_channel.is_initiator());
+
+        // And the usual framing works on the resulting channel
+        let msg = "Hello".as_bytes();
+        let encrypted_msg = client_channel.encrypt_message(msg).unwrap();
         let (header, body) = (
             &encrypted_msg.0[..NOISE_MESSAGE_HEADER_SIZE],
             &encrypted_msg.0[NOISE_MESSAGE_HEADER_SIZE..],
         );
-        eprintln!("{:x?}", header);
         let msg_len = server_channel
             .decrypt_header(&NoiseEncryptedHeader(header.try_into().unwrap()))
             .unwrap();
@@ -295,78 +2981,654 @@ pub mod tests {
             .decrypt_message(&NoiseEncryptedMessage(body.to_vec()))
             .unwrap();
         assert_eq!(msg.to_vec(), decrypted_msg);
+    }
 
-        // server encrypts message for client
-        let msg = "Goodbye".as_bytes();
-        let encrypted_msg = server_channel.encrypt_message(&msg).unwrap();
-        let (header, body) = (
-            &encrypted_msg.0[..NOISE_MESSAGE_HEADER_SIZE],
-            &encrypted_msg.0[NOISE_MESSAGE_HEADER_SIZE..],
+    #[test]
+    fn test_xx_bad_messages() {
+        let (_, initiator_privkey) = generate_keypair();
+        let (_, responder_privkey) = generate_keypair();
+
+        let (cli_act_1, msg_1) = XXHandshakeActOne::initiator(&initiator_privkey).unwrap();
+        let serv_act_1 = XXHandshakeActOne::responder(&responder_privkey, &msg_1).unwrap();
+        let (serv_act_2, msg_2) = XXHandshakeActTwo::responder(serv_act_1).unwrap();
+        XXHandshakeActTwo::initiator(cli_act_1, &msg_2).unwrap();
+
+        let bad_msg = XXMessageActThree([1u8; XX_MSG_3_SIZE]);
+        XXHandshakeActThree::responder(serv_act_2, &bad_msg)
+            .expect_err("bad_msg cannot be decrypted.");
+    }
+
+    fn kk_channels() -> (KKChannel, KKChannel) {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+
+        let (cli_act_1, msg_1) = KKHandshakeActOne::initiator(
+            &initiator_privkey,
+            &responder_pubkey,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let serv_act_1 = KKHandshakeActOne::responder(
+            &responder_privkey,
+            &[initiator_pubkey],
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let (serv_act_2, msg_2) = KKHandshakeActTwo::responder(serv_act_1).unwrap();
+        let server_channel = KKChannel::from_handshake(serv_act_2).unwrap();
+        let cli_act_2 = KKHandshakeActTwo::initiator(cli_act_1, &msg_2).unwrap();
+        let client_channel = KKChannel::from_handshake(cli_act_2).unwrap();
+
+        (client_channel, server_channel)
+    }
+
+    // Create KK channels with fixed ephemeral keys, for the ciphertexts to be reproducible
+    fn deterministic_kk_channels(
+        keys: &((PublicKey, SecretKey), (PublicKey, SecretKey)),
+    ) -> (KKChannel, KKChannel) {
+        let ((initiator_pubkey, initiator_privkey), (responder_pubkey, responder_privkey)) = keys;
+
+        let (cli_act_1, msg_1) = KKHandshakeActOne::initiator_with_ephemeral(
+            initiator_privkey,
+            responder_pubkey,
+            &[1u8; 32],
+            PROTOCOL_VERSIONS,
+            &[],
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let serv_act_1 = KKHandshakeActOne::responder_with_ephemeral(
+            responder_privkey,
+            initiator_pubkey,
+            &msg_1,
+            &[2u8; 32],
+            PROTOCOL_VERSIONS,
+            &[],
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let (serv_act_2, msg_2) = KKHandshakeActTwo::responder(serv_act_1).unwrap();
+        let cli_act_2 = KKHandshakeActTwo::initiator(cli_act_1, &msg_2).unwrap();
+
+        (
+            KKChannel::from_handshake(cli_act_2).unwrap(),
+            KKChannel::from_handshake(serv_act_2).unwrap(),
+        )
+    }
+
+    fn roundtrip(sender: &mut KKChannel, receiver: &mut KKChannel, msg: &[u8]) -> Vec<u8> {
+        let encrypted_msg = sender.encrypt_message(msg).unwrap();
+        let header = NoiseEncryptedHeader(
+            encrypted_msg.0[..NOISE_MESSAGE_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
         );
-        let msg_len = client_channel
-            .decrypt_header(&NoiseEncryptedHeader(header.try_into().unwrap()))
+        let body = NoiseEncryptedMessage(encrypted_msg.0[NOISE_MESSAGE_HEADER_SIZE..].to_vec());
+        receiver.decrypt_header(&header).unwrap();
+        receiver.decrypt_message(&body).unwrap()
+    }
+
+    #[test]
+    fn test_rekey() {
+        let (mut client_channel, mut server_channel) = kk_channels();
+        let msg = b"Before rekey";
+        assert_eq!(
+            roundtrip(&mut client_channel, &mut server_channel, msg),
+            msg.to_vec()
+        );
+
+        // Rotate the client -> server direction only
+        client_channel.rekey_outgoing();
+        server_channel.rekey_incoming();
+        let msg = b"After rekey";
+        assert_eq!(
+            roundtrip(&mut client_channel, &mut server_channel, msg),
+            msg.to_vec()
+        );
+        assert_eq!(
+            roundtrip(&mut server_channel, &mut client_channel, msg),
+            msg.to_vec()
+        );
+
+        // Rotating only one side breaks the channel
+        server_channel.rekey_outgoing();
+        let encrypted_msg = server_channel.encrypt_message(msg).unwrap();
+        let header = NoiseEncryptedHeader(
+            encrypted_msg.0[..NOISE_MESSAGE_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        client_channel
+            .decrypt_header(&header)
+            .expect_err("Client didn't rekey");
+    }
+
+    #[test]
+    fn test_decrypt_errors() {
+        // A corrupted header
+        let (mut client_channel, mut server_channel) = kk_channels();
+        let mut encrypted_msg = client_channel.encrypt_message(b"Hello").unwrap();
+        encrypted_msg.0[0] ^= 1;
+        let header = NoiseEncryptedHeader(
+            encrypted_msg.0[..NOISE_MESSAGE_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        match server_channel.decrypt_header(&header) {
+            Err(NoiseError::Decrypt) => {}
+            e => panic!("Unexpected result: {:?}", e),
+        }
+
+        // A corrupted body
+        let (mut client_channel, mut server_channel) = kk_channels();
+        let mut encrypted_msg = client_channel.encrypt_message(b"Hello").unwrap();
+        let header = NoiseEncryptedHeader(
+            encrypted_msg.0[..NOISE_MESSAGE_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        server_channel.decrypt_header(&header).unwrap();
+        *encrypted_msg.0.last_mut().unwrap() ^= 1;
+        let body = NoiseEncryptedMessage(encrypted_msg.0[NOISE_MESSAGE_HEADER_SIZE..].to_vec());
+        match server_channel.decrypt_message(&body) {
+            Err(NoiseError::Decrypt) => {}
+            e => panic!("Unexpected result: {:?}", e),
+        }
+
+        // Misusing the state machine
+        let (_, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, _) = generate_keypair();
+        let mut state = builder(KK_PARAMS)
+            .local_private_key(&initiator_privkey.0)
+            .remote_public_key(&responder_pubkey.0)
+            .build_initiator()
             .unwrap();
-        assert_eq!(msg_len as usize, msg.len() + MAC_SIZE);
-        let decrypted_msg = client_channel
-            .decrypt_message(&NoiseEncryptedMessage(body.to_vec()))
+        let err = state
+            .read_message(&[0u8; KK_MSG_2_SIZE], &mut [])
+            .unwrap_err();
+        match NoiseError::from(err) {
+            NoiseError::InvalidState(snow::error::StateProblem::NotTurnToRead) => {}
+            e => panic!("Unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_nonce_exhaustion() {
+        let (mut client_channel, mut server_channel) = kk_channels();
+        assert_eq!(client_channel.sending_nonce(), 0);
+        assert_eq!(server_channel.receiving_nonce(), 0);
+
+        // Header and body
+        roundtrip(&mut client_channel, &mut server_channel, b"Hello");
+        assert_eq!(client_channel.sending_nonce(), 2);
+        assert_eq!(server_channel.receiving_nonce(), 2);
+        assert_eq!(server_channel.sending_nonce(), 0);
+
+        // Pretend the limit is low, we would use the reserved nonce for the second message
+        client_channel.nonce_limit = 5;
+        roundtrip(&mut client_channel, &mut server_channel, b"Hello");
+        match client_channel.encrypt_message(b"Hello") {
+            Err(NoiseError::NonceExhausted) => {}
+            e => panic!("Unexpected result: {:?}", e),
+        }
+        // Nothing was consumed
+        assert_eq!(client_channel.sending_nonce(), 4);
+    }
+
+    #[test]
+    fn test_handshake_hash() {
+        let (client_channel, server_channel) = kk_channels();
+        assert_eq!(
+            client_channel.handshake_hash(),
+            server_channel.handshake_hash()
+        );
+
+        let (other_client_channel, _) = kk_channels();
+        assert_ne!(
+            client_channel.handshake_hash(),
+            other_client_channel.handshake_hash()
+        );
+    }
+
+    #[test]
+    fn test_bad_handshake_message() {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+
+        // A valid first message, but with a different magic
+        let mut state = builder(KK_PARAMS)
+            .local_private_key(&initiator_privkey.0)
+            .remote_public_key(&responder_pubkey.0)
+            .build_initiator()
             .unwrap();
-        assert_eq!(msg.to_vec(), decrypted_msg);
+        let mut msg = [0u8; KK_MSG_1_SIZE];
+        state
+            .write_message(b"impractical_revault", &mut msg)
+            .unwrap();
+
+        match KKHandshakeActOne::responder(
+            &responder_privkey,
+            &[initiator_pubkey],
+            &KKMessageActOne(msg),
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        ) {
+            Err(NoiseError::BadHandshake) => {}
+            e => panic!("Unexpected result: {:?}", e),
+        }
+
+        // Another version string than the first one, without the flag of a versions bitfield
+        let mut state = builder(KK_PARAMS)
+            .local_private_key(&initiator_privkey.0)
+            .remote_public_key(&responder_pubkey.0)
+            .build_initiator()
+            .unwrap();
+        let mut msg = [0u8; KK_MSG_1_SIZE];
+        state
+            .write_message(b"practical_revault_1", &mut msg)
+            .unwrap();
+        match KKHandshakeActOne::responder(
+            &responder_privkey,
+            &[initiator_pubkey],
+            &KKMessageActOne(msg),
+            &[0, 1],
+            CipherSuite::default(),
+        ) {
+            Err(NoiseError::BadHandshake) => {}
+            e => panic!("Unexpected result: {:?}", e),
+        }
     }
 
     #[test]
-    fn test_message_size_limit() {
-        let (initiator_pubkey, initiator_privkey) = gen_keypair();
-        let (responder_pubkey, responder_privkey) = gen_keypair();
+    fn test_encrypted_message_conversions() {
+        let (mut client_channel, mut server_channel) = kk_channels();
+        let encrypted_msg = client_channel.encrypt_message(b"Hello").unwrap();
+        let (header, body) = encrypted_msg.0.split_at(NOISE_MESSAGE_HEADER_SIZE);
 
-        // client
-        let (_, msg_1) =
-            KKHandshakeActOne::initiator(&initiator_privkey, &responder_pubkey).unwrap();
+        let header = NoiseEncryptedHeader::try_from(header).unwrap();
+        assert_eq!(
+            header.as_ref(),
+            &encrypted_msg.0[..NOISE_MESSAGE_HEADER_SIZE]
+        );
+        assert_eq!(header.len(), NOISE_MESSAGE_HEADER_SIZE);
+        assert_eq!(NoiseEncryptedHeader::from(header.0).0, header.0);
+        for len in [
+            0,
+            NOISE_MESSAGE_HEADER_SIZE - 1,
+            NOISE_MESSAGE_HEADER_SIZE + 1,
+        ] {
+            assert!(matches!(
+                NoiseEncryptedHeader::try_from(&encrypted_msg.0[..len]),
+                Err(NoiseError::InvalidCiphertext)
+            ));
+        }
 
-        // server
-        let serv_act_1 =
-            KKHandshakeActOne::responder(&responder_privkey, &[initiator_pubkey], &msg_1).unwrap();
-        let (serv_act_2, _msg_2) = KKHandshakeActTwo::responder(serv_act_1).unwrap();
-        let mut server_channel = KKChannel::from_handshake(serv_act_2).unwrap();
+        let body = NoiseEncryptedMessage::try_from(body.to_vec()).unwrap();
+        assert_eq!(body.as_ref(), &encrypted_msg.0[NOISE_MESSAGE_HEADER_SIZE..]);
+        assert_eq!(&body[..], body.as_ref());
+        assert!(body.iter().eq((&body).into_iter()));
+        assert!(matches!(
+            NoiseEncryptedMessage::try_from(vec![0; MAC_SIZE - 1]),
+            Err(NoiseError::InvalidCiphertext)
+        ));
 
-        // Hit the limit
-        let msg = [0u8; NOISE_PLAINTEXT_MAX_SIZE];
-        server_channel
-            .encrypt_message(&msg)
-            .expect("Maximum allowed");
+        // They can be used as is
+        server_channel.decrypt_header(&header).unwrap();
+        assert_eq!(
+            server_channel.decrypt_message(&body).unwrap(),
+            b"Hello".to_vec()
+        );
+        let bytes: Vec<u8> = body.into_iter().collect();
+        assert_eq!(
+            Vec::from(NoiseEncryptedMessage::new(bytes.clone()).unwrap()),
+            bytes
+        );
+    }
 
-        // Fail if msg too large
-        let msg = [0u8; NOISE_MESSAGE_MAX_SIZE - NOISE_MESSAGE_HEADER_SIZE + 1];
-        server_channel
-            .encrypt_message(&msg)
-            .expect_err("Limit exceeded");
+    #[test]
+    fn test_handshake_message_from_bytes() {
+        // from_slice is an alias of from_bytes
+        for parse in [KKMessageActOne::from_bytes, KKMessageActOne::from_slice] {
+            for len in 0..KK_MSG_1_SIZE + 2 {
+                let res = parse(&vec![0x42; len]);
+                if len == KK_MSG_1_SIZE {
+                    assert_eq!(res.unwrap().as_bytes(), &[0x42; KK_MSG_1_SIZE]);
+                } else {
+                    assert!(matches!(res, Err(NoiseError::HandshakePatternMismatch)));
+                }
+            }
+        }
+        for parse in [KKMessageActTwo::from_bytes, KKMessageActTwo::from_slice] {
+            for len in 0..KK_VERSIONED_MSG_2_SIZE + 2 {
+                let res = parse(&vec![0x42; len]);
+                if len == KK_MSG_2_SIZE || len == KK_VERSIONED_MSG_2_SIZE {
+                    assert_eq!(res.unwrap().as_bytes(), &vec![0x42; len][..]);
+                } else {
+                    assert!(matches!(res, Err(NoiseError::HandshakePatternMismatch)));
+                }
+            }
+        }
+    }
 
-        // We can encrypt an empty message
-        let msg = b"";
-        server_channel
-            .encrypt_message(msg)
-            .expect("Empty message is fine to encrypt");
+    #[test]
+    fn test_handshake_pattern_mismatch() {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (_, responder_privkey) = generate_keypair();
+
+        // An XX first message doesn't even have the size of a KK one
+        let (_, msg) = XXHandshakeActOne::initiator(&initiator_privkey).unwrap();
+        match KKMessageActOne::from_bytes(&msg.0) {
+            Err(NoiseError::HandshakePatternMismatch) => {}
+            e => panic!("Unexpected result: {:?}", e.map(|_| ())),
+        }
+        match KKMessageActTwo::from_bytes(&[0u8; KK_VERSIONED_MSG_2_SIZE + 1]) {
+            Err(NoiseError::HandshakePatternMismatch) => {}
+            e => panic!("Unexpected result: {:?}", e.map(|_| ())),
+        }
 
-        // We cannot decrypt an empty message
+        // A right-sized message with an invalid ephemeral key can't be processed at all
+        let garbage = KKMessageActOne::from_bytes(&[0u8; KK_MSG_1_SIZE]).unwrap();
+        match KKHandshakeActOne::responder(
+            &responder_privkey,
+            &[initiator_pubkey],
+            &garbage,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        ) {
+            Err(NoiseError::HandshakePatternMismatch) => {}
+            e => panic!("Unexpected result: {:?}", e),
+        }
+
+        // Whereas a message from an unknown peer fails to decrypt
+        let (_, msg) = KKHandshakeActOne::initiator(
+            &initiator_privkey,
+            &public_from_secret(&responder_privkey),
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let (other_pubkey, _) = generate_keypair();
+        match KKHandshakeActOne::responder(
+            &responder_privkey,
+            &[other_pubkey],
+            &msg,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        ) {
+            Err(NoiseError::MissingStaticKey) => {}
+            e => panic!("Unexpected result: {:?}", e),
+        }
+    }
+
+    fn negotiate(
+        initiator_versions: &[u8],
+        responder_versions: &[u8],
+    ) -> Result<(KKChannel, KKChannel), NoiseError> {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+
+        let (cli_act_1, msg_1) = KKHandshakeActOne::initiator(
+            &initiator_privkey,
+            &responder_pubkey,
+            initiator_versions,
+            CipherSuite::default(),
+        )?;
+        let serv_act_1 = KKHandshakeActOne::responder(
+            &responder_privkey,
+            &[initiator_pubkey],
+            &msg_1,
+            responder_versions,
+            CipherSuite::default(),
+        )?;
+        let (serv_act_2, msg_2) = KKHandshakeActTwo::responder(serv_act_1)?;
+        let cli_act_2 = KKHandshakeActTwo::initiator(cli_act_1, &msg_2)?;
+
+        Ok((
+            KKChannel::from_handshake(cli_act_2)?,
+            KKChannel::from_handshake(serv_act_2)?,
+        ))
+    }
+
+    #[test]
+    fn test_prologue() {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+
+        // Same prologue on both ends
+        let (cli_act_1, msg_1) = KKHandshakeActOne::initiator_with_prologue(
+            &initiator_privkey,
+            &responder_pubkey,
+            PROTOCOL_VERSIONS,
+            b"mainnet",
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let serv_act_1 = KKHandshakeActOne::responder_with_prologue(
+            &responder_privkey,
+            &[initiator_pubkey],
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            b"mainnet",
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let (serv_act_2, msg_2) = KKHandshakeActTwo::responder(serv_act_1).unwrap();
+        let cli_act_2 = KKHandshakeActTwo::initiator(cli_act_1, &msg_2).unwrap();
+        let mut client_channel = KKChannel::from_handshake(cli_act_2).unwrap();
+        let mut server_channel = KKChannel::from_handshake(serv_act_2).unwrap();
+        assert_eq!(
+            client_channel.handshake_hash(),
+            server_channel.handshake_hash()
+        );
+        let encrypted_msg = client_channel.encrypt_message(b"Hello").unwrap();
+        let (header, body) = encrypted_msg.0.split_at(NOISE_MESSAGE_HEADER_SIZE);
         server_channel
-            .decrypt_message(&NoiseEncryptedMessage(msg.to_vec()))
-            .expect_err("Encrypted message with no header");
+            .decrypt_header(&NoiseEncryptedHeader(header.try_into().unwrap()))
+            .unwrap();
+        assert_eq!(
+            server_channel
+                .decrypt_message(&NoiseEncryptedMessage(body.to_vec()))
+                .unwrap(),
+            b"Hello".to_vec()
+        );
+
+        // A handshake for another network is refused. We can't tell it from an unknown peer.
+        match KKHandshakeActOne::responder(
+            &responder_privkey,
+            &[initiator_pubkey],
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        ) {
+            Err(NoiseError::MissingStaticKey) => {}
+            e => panic!("Unexpected result: {:?}", e),
+        }
+        match KKHandshakeActOne::responder_with_prologue(
+            &responder_privkey,
+            &[initiator_pubkey],
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            b"testnet",
+            CipherSuite::default(),
+        ) {
+            Err(NoiseError::MissingStaticKey) => {}
+            e => panic!("Unexpected result: {:?}", e),
+        }
+
+        // An unknown peer is reported as such with a prologue too
+        let (other_pubkey, _) = generate_keypair();
+        match KKHandshakeActOne::responder_with_prologue(
+            &responder_privkey,
+            &[other_pubkey],
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            b"mainnet",
+            CipherSuite::default(),
+        ) {
+            Err(NoiseError::MissingStaticKey) => {}
+            e => panic!("Unexpected result: {:?}", e),
+        }
     }
 
     #[test]
-    fn test_bad_messages() {
-        let (initiator_pubkey, initiator_privkey) = gen_keypair();
-        let (responder_pubkey, responder_privkey) = gen_keypair();
+    fn test_version_negotiation() {
+        // A responder only supporting the first version
+        let (client_channel, server_channel) = negotiate(&[0, 1], &[0]).unwrap();
+        assert_eq!(client_channel.negotiated_version(), 0);
+        assert_eq!(server_channel.negotiated_version(), 0);
 
-        // KK handshake fails if messages are badly formed.
-        // Without a valid cli_act_2 nor serv_act_2, no KKChannel can be constructed.
-        let (cli_act_1, _) = KKHandshakeActOne::initiator(&initiator_privkey, &responder_pubkey)
-            .expect("The first act is valid.");
+        // The highest common one is selected
+        let (client_channel, server_channel) = negotiate(&[0, 1, 3], &[3, 0, 1, 2]).unwrap();
+        assert_eq!(client_channel.negotiated_version(), 3);
+        assert_eq!(server_channel.negotiated_version(), 3);
+        let (client_channel, server_channel) = negotiate(&[1], &[0, 1, 2]).unwrap();
+        assert_eq!(client_channel.negotiated_version(), 1);
+        assert_eq!(server_channel.negotiated_version(), 1);
 
-        let bad_msg = KKMessageActOne([1u8; KK_MSG_1_SIZE]);
-        KKHandshakeActOne::responder(&responder_privkey, &[initiator_pubkey], &bad_msg)
-            .expect_err("This one is invalid as bad_msg cannot be decrypted.");
+        // No common version
+        match negotiate(&[1], &[0]) {
+            Err(NoiseError::UnsupportedVersion) => {}
+            e => panic!("Unexpected result: {:?}", e),
+        }
 
-        let bad_msg = KKMessageActTwo([1u8; KK_MSG_2_SIZE]);
-        KKHandshakeActTwo::initiator(cli_act_1, &bad_msg).expect_err("So is this one.");
+        // Invalid versions
+        negotiate(&[], &[0]).expect_err("No version");
+        negotiate(&[0], &[]).expect_err("No version");
+        negotiate(&[0, MAX_PROTOCOL_VERSION + 1], &[0]).expect_err("Version too high");
+        negotiate(&[0, MAX_PROTOCOL_VERSION], &[0, MAX_PROTOCOL_VERSION]).unwrap();
+    }
+
+    fn legacy_builder<'a>() -> Builder<'a> {
+        Builder::with_resolver(
+            KK_PARAMS.parse().expect("Valid params"),
+            Box::new(SodiumResolver),
+        )
+    }
+
+    // The first act of the responder as it was before the version negotiation, and still is
+    // on the deployed peers
+    fn legacy_responder(
+        my_privkey: &SecretKey,
+        their_pubkey: &PublicKey,
+        message: &[u8; KK_MSG_1_SIZE],
+    ) -> Result<HandshakeState, NoiseError> {
+        let mut state = legacy_builder()
+            .local_private_key(&my_privkey.0)
+            .remote_public_key(&their_pubkey.0)
+            .build_responder()?;
+
+        let mut msg = [0u8; KK_MSG_1_SIZE];
+        state.read_message(message, &mut msg)?;
+        if &msg[..HANDSHAKE_MESSAGE.len()] != HANDSHAKE_MESSAGE {
+            return Err(NoiseError::BadHandshake);
+        }
+
+        Ok(state)
+    }
+
+    // Same for the initiator
+    fn legacy_initiator(
+        my_privkey: &SecretKey,
+        their_pubkey: &PublicKey,
+    ) -> (HandshakeState, [u8; KK_MSG_1_SIZE]) {
+        let mut state = legacy_builder()
+            .local_private_key(&my_privkey.0)
+            .remote_public_key(&their_pubkey.0)
+            .build_initiator()
+            .unwrap();
+
+        let mut msg = [0u8; KK_MSG_1_SIZE];
+        state.write_message(HANDSHAKE_MESSAGE, &mut msg).unwrap();
+        (state, msg)
+    }
+
+    // Send a message from a legacy transport state to a channel, and another one back
+    fn legacy_roundtrip(legacy_state: HandshakeState, channel: &mut KKChannel) {
+        let mut legacy_state = legacy_state.into_transport_mode().unwrap();
+
+        let msg = b"From a legacy peer";
+        let mut encrypted_msg = vec![0u8; encrypted_msg_size(msg.len())];
+        let len = ((msg.len() + MAC_SIZE) as u16).to_be_bytes();
+        let (header, body) = encrypted_msg.split_at_mut(NOISE_MESSAGE_HEADER_SIZE);
+        legacy_state.write_message(&len, header).unwrap();
+        legacy_state.write_message(msg, body).unwrap();
+        assert_eq!(
+            channel.decrypt_framed(&encrypted_msg).unwrap(),
+            msg.to_vec()
+        );
+
+        let encrypted_msg = channel.encrypt_message(b"To a legacy peer").unwrap().0;
+        let (header, body) = encrypted_msg.split_at(NOISE_MESSAGE_HEADER_SIZE);
+        let mut plaintext = vec![0u8; body.len()];
+        legacy_state.read_message(header, &mut plaintext).unwrap();
+        let len = legacy_state.read_message(body, &mut plaintext).unwrap();
+        assert_eq!(&plaintext[..len], b"To a legacy peer");
+    }
+
+    #[test]
+    fn test_legacy_interop() {
+        let (initiator_pubkey, initiator_privkey) = generate_keypair();
+        let (responder_pubkey, responder_privkey) = generate_keypair();
+
+        // Offering only the first version, the first message is the same as before the version
+        // negotiation. The answer of a legacy responder is accepted and implies it.
+        let (cli_act_1, msg_1) = KKHandshakeActOne::initiator(
+            &initiator_privkey,
+            &responder_pubkey,
+            LEGACY_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+        assert_eq!(cli_act_1.act_two_size(), KK_MSG_2_SIZE);
+        let mut serv_state =
+            legacy_responder(&responder_privkey, &initiator_pubkey, msg_1.as_bytes()).unwrap();
+        let mut msg_2 = [0u8; KK_MSG_2_SIZE];
+        serv_state.write_message(&[], &mut msg_2).unwrap();
+        let cli_act_2 =
+            KKHandshakeActTwo::initiator(cli_act_1, &KKMessageActTwo::from_bytes(&msg_2).unwrap())
+                .unwrap();
+        let mut client_channel = KKChannel::from_handshake(cli_act_2).unwrap();
+        assert_eq!(client_channel.negotiated_version(), 0);
+        legacy_roundtrip(serv_state, &mut client_channel);
+
+        // Offering other versions, a legacy responder refuses it
+        let (_, msg_1) = KKHandshakeActOne::initiator(
+            &initiator_privkey,
+            &responder_pubkey,
+            &[0, 1],
+            CipherSuite::default(),
+        )
+        .unwrap();
+        match legacy_responder(&responder_privkey, &initiator_pubkey, msg_1.as_bytes()) {
+            Err(NoiseError::BadHandshake) => {}
+            e => panic!("Unexpected result: {:?}", e.map(|_| ())),
+        }
+
+        // A legacy initiator gets the first version from a responder supporting others, and
+        // an empty second message
+        let (mut cli_state, msg_1) = legacy_initiator(&initiator_privkey, &responder_pubkey);
+        let serv_act_1 = KKHandshakeActOne::responder(
+            &responder_privkey,
+            &[initiator_pubkey],
+            &KKMessageActOne::from_bytes(&msg_1).unwrap(),
+            &[0, 1],
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let (serv_act_2, msg_2) = KKHandshakeActTwo::responder(serv_act_1).unwrap();
+        assert_eq!(msg_2.as_bytes().len(), KK_MSG_2_SIZE);
+        let mut payload = [0u8; KK_MSG_2_SIZE];
+        assert_eq!(
+            cli_state
+                .read_message(msg_2.as_bytes(), &mut payload)
+                .unwrap(),
+            0
+        );
+        let mut server_channel = KKChannel::from_handshake(serv_act_2).unwrap();
+        assert_eq!(server_channel.negotiated_version(), 0);
+        legacy_roundtrip(cli_state, &mut server_channel);
     }
 }
@@ -5,159 +5,5731 @@
 //!
 
 use crate::{
-    error::Error,
+    error::{Error, NoiseError},
     noise::{
-        KKChannel, KKHandshakeActOne, KKHandshakeActTwo, KKMessageActOne, KKMessageActTwo,
-        NoiseEncryptedHeader, NoiseEncryptedMessage, PublicKey, SecretKey, KK_MSG_1_SIZE,
-        KK_MSG_2_SIZE, NOISE_MESSAGE_HEADER_SIZE,
+        body_len_from_header, public_key_from_bytes, CipherSuite, KKChannel, KKHandshakeActOne,
+        KKHandshakeActTwo, KKMessageActOne, KKMessageActTwo, KKResponder, KeyBytes,
+        NoiseEncryptedHeader, PublicKey, SecretKey, HASH_SIZE, KK_MSG_1_SIZE, LEGACY_VERSIONS,
+        MAC_SIZE, NOISE_MESSAGE_HEADER_SIZE, NOISE_PLAINTEXT_MAX_SIZE, PROTOCOL_VERSIONS,
     },
 };
+use std::collections::VecDeque;
+use std::convert::TryInto;
 use std::io::{ErrorKind, Read, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::{thread, time::Duration};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc, Arc, Condvar, Mutex, RwLock,
+};
+#[cfg(unix)]
+use std::{
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+// Since protocol version 1 every plaintext sent through a KKTransport is prefixed by a
+// one-byte frame type, so we can send control messages alongside the application ones. A
+// version 0 peer sends the application messages as is, and knows no control message.
+/// An application message
+const FRAME_DATA: u8 = 0x00;
+/// The sender rotated its outgoing key right after sending this frame
+const FRAME_REKEY: u8 = 0x01;
+/// Sent in response to a [FRAME_REKEY], the sender rotated its outgoing key right after
+/// sending this frame
+const FRAME_REKEY_ACK: u8 = 0x02;
+/// The sender is closing the connection and won't send anything else, see
+/// [KKTransport::shutdown] and [KKTransport::close_write]. Its body is empty, and it's sent
+/// right before shutting down the write side of the stream. The receiver reports it as
+/// [Error::ConnectionClosed] once it read the messages sent before it, whereas the stream
+/// being closed without it is reported as [Error::CleanEof] or [Error::TruncatedMessage].
+const FRAME_CLOSE: u8 = 0x03;
+/// A chunk of the data streamed by [KKTransport::write_from]
+const FRAME_STREAM: u8 = 0x04;
+/// The end of the data streamed by [KKTransport::write_from]
+const FRAME_STREAM_END: u8 = 0x05;
+/// Sent when we didn't write for [PingPolicy::interval], to check the peer is still alive
+const FRAME_PING: u8 = 0x06;
+/// Sent in response to a [FRAME_PING]
+const FRAME_PONG: u8 = 0x07;
+/// An application message sent by [KKTransport::write_acked], to be acknowledged
+const FRAME_DATA_ACKED: u8 = 0x08;
+/// Sent in response to a [FRAME_DATA_ACKED], once it was read
+const FRAME_ACK: u8 = 0x09;
+/// The sender's contribution to the session token, the first frame sent by both ends
+const FRAME_SESSION: u8 = 0x0a;
+
+/// Size of the frame type prefix
+const FRAME_TYPE_SIZE: usize = 1;
+/// The first protocol version in which the plaintexts are prefixed by a frame type
+const FRAMED_VERSION: u8 = 1;
+/// Size of the sequence number following the frame type of application messages, if enabled
+/// with [TransportConfig::sequence_numbers]
+const SEQUENCE_NUMBER_SIZE: usize = 8;
+
+/// Size of the token returned by [KKTransport::session_token], and of each end's contribution
+pub const SESSION_TOKEN_SIZE: usize = 32;
+
+// The first protocol version in which both ends send a FRAME_SESSION after the handshake
+const SESSION_TOKEN_VERSION: u8 = 1;
+
+/// The timeout used by [KKTransport::connect]
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The time a peer has to complete the whole Noise handshake once connected, so it can't
+/// keep a connection half-open by sending the handshake bytes slowly.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Sent by a responder refusing the handshake right before closing the connection, so that the
+// initiator can tell it apart from a responder predating the version negotiation, which closes
+// it without a word upon an offer it doesn't know. It's shorter than the second handshake
+// message, so an initiator predating the negotiation sees the connection closed either way.
+const HANDSHAKE_REFUSAL: &[u8] = &[0];
+
+// How often a cancellable accept checks whether it was cancelled
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How to retry a read or a write which failed without making progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times to attempt the read or write, including the first one
+    pub max_attempts: usize,
+    /// How long to sleep after the first failed attempt
+    pub backoff: Duration,
+    /// Whether to double the sleep after each failed attempt
+    pub exponential: bool,
+}
+
+impl RetryPolicy {
+    /// Never retry, fail on the first error
+    pub fn no_retry() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff: Duration::from_secs(0),
+            exponential: false,
+        }
+    }
+
+    /// How long to sleep after this (1-indexed) failed attempt
+    pub fn delay(&self, attempt: usize) -> Duration {
+        if self.exponential {
+            let exponent = attempt.saturating_sub(1).min(31) as u32;
+            self.backoff
+                .checked_mul(1 << exponent)
+                .unwrap_or(Duration::MAX)
+        } else {
+            self.backoff
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            backoff: Duration::from_secs(1),
+            exponential: false,
+        }
+    }
+}
+
+/// When to check that the peer is still alive, see [TransportConfig::ping]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingPolicy {
+    /// Send a ping after not writing anything for this long
+    pub interval: Duration,
+    /// Consider the connection dead if the peer didn't answer the ping after this long
+    pub timeout: Duration,
+}
+
+/// The listen backlog used by [KKServer::bind]
+pub const DEFAULT_BACKLOG: i32 = 128;
+
+/// The number of handshakes a [KKServer] performs at the same time by default
+pub const DEFAULT_MAX_PENDING_HANDSHAKES: usize = 64;
+
+// How long a KKServer waits before accepting again after failing to, eg because we ran out
+// of file descriptors
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// The default for [TransportConfig::read_buffer_size]
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// Configuration of a [KKTransport]. The sequence numbers, pings and acknowledgements need a
+/// peer supporting protocol version 1, with an older one they make the reads and writes fail
+/// with [Error::Unsupported].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransportConfig {
+    /// How to retry reads and writes
+    pub retry: RetryPolicy,
+    /// Whether to number the application messages, and refuse the ones that are not numbered
+    /// in increasing order with [Error::ReplayDetected]. Noise already prevents replays
+    /// within a channel, this protects against a bug of the application layer (eg resending a
+    /// message after reconnecting) rather than against an attacker. Both ends must enable it.
+    /// Disabled by default.
+    pub sequence_numbers: bool,
+    /// Whether to send pings to keep an idle connection open through middleboxes (eg a NAT),
+    /// and to detect a dead peer. As the transport is synchronous, the pings are only sent
+    /// while blocked in [KKTransport::read], which then returns an [Error::Transport] of kind
+    /// [ErrorKind::TimedOut] if the peer doesn't answer in time. While pinging, reads are not
+    /// affected by the [RetryPolicy]. The peer must be able to answer pings, but doesn't need
+    /// to enable them. Disabled by default.
+    pub ping: Option<PingPolicy>,
+    /// Whether to acknowledge the messages the peer sent with [KKTransport::write_acked]. The
+    /// peer gets an [Error::InvalidFrame] if it sent one while we didn't enable it. Disabled by
+    /// default.
+    pub acks: bool,
+    /// How many bytes of application messages we keep in memory when they are received while
+    /// waiting for a control message (eg in [KKTransport::rekey]), and return at once from
+    /// [KKTransport::read_available]. Once reached we stop reading from the stream until the
+    /// application reads them, so a peer sending faster than we process can't make us
+    /// allocate without bound. [DEFAULT_READ_BUFFER_SIZE] by default.
+    pub read_buffer_size: usize,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        TransportConfig {
+            retry: RetryPolicy::default(),
+            sequence_numbers: false,
+            ping: None,
+            acks: false,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+        }
+    }
+}
+
+/// Counters of what went through a [KKTransport] since the handshake, see
+/// [KKTransport::stats]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TransportStats {
+    /// Number of application messages sent
+    pub msgs_sent: u64,
+    /// Number of application messages received
+    pub msgs_recv: u64,
+    /// Size of the application messages sent
+    pub plaintext_bytes_sent: u64,
+    /// Size of the application messages received
+    pub plaintext_bytes_recv: u64,
+    /// Number of bytes written to the stream, including the encryption overhead and the
+    /// control messages
+    pub bytes_sent: u64,
+    /// Number of bytes read from the stream, including the encryption overhead and the
+    /// control messages
+    pub bytes_recv: u64,
+}
+
+/// How far a [KKTransport::write_nonblocking] or [KKTransport::flush_pending] got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteProgress {
+    /// Number of encrypted bytes handed over to the OS by this call
+    pub flushed: usize,
+    /// Number of encrypted bytes still buffered, to be written by the next calls
+    pub pending: usize,
+}
+
+impl WriteProgress {
+    /// Whether all the messages were handed over to the OS
+    pub fn is_complete(&self) -> bool {
+        self.pending == 0
+    }
+}
+
+// The memory used by a message we keep in a buffer. Accounts for the allocation itself, so
+// that a flood of empty messages is bounded too.
+fn buffered_size(msg: &[u8]) -> usize {
+    msg.len() + std::mem::size_of::<Vec<u8>>()
+}
+
+// The connection underlying a KKTransport
+#[derive(Debug)]
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn read_timeout(&self) -> std::io::Result<Option<Duration>> {
+        match self {
+            Stream::Tcp(stream) => stream.read_timeout(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read_timeout(),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn write_timeout(&self) -> std::io::Result<Option<Duration>> {
+        match self {
+            Stream::Tcp(stream) => stream.write_timeout(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write_timeout(),
+        }
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
 
-/// Wrapper type for a TcpStream and KKChannel that automatically enforces authenticated and
-/// encrypted channels when communicating
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Wrapper type for a TcpStream (or a UnixStream) and KKChannel that automatically enforces
+/// authenticated and encrypted channels when communicating
 #[derive(Debug)]
 pub struct KKTransport {
-    stream: TcpStream,
+    stream: Stream,
     channel: KKChannel,
+    // Application messages we received while waiting for a control message, and whether the
+    // peer asked us to acknowledge them
+    pending: VecDeque<(Vec<u8>, bool)>,
+    // The memory used by the `pending` messages, see [buffered_size]
+    pending_size: usize,
+    // Bytes we read from the stream but that don't form a full header or body yet
+    read_buf: Vec<u8>,
+    // The size of the next body to read, if we already decrypted its header
+    pending_body_len: Option<usize>,
+    // The maximum size of an application message we accept to read
+    max_message_size: usize,
+    // Encrypted bytes a non-blocking write couldn't hand over to the OS yet
+    write_pending: Vec<u8>,
+    config: TransportConfig,
+    stats: TransportStats,
+    // The sequence number of the next application message we send
+    next_sent_seq: u64,
+    // The sequence number of the last application message we received
+    last_recv_seq: Option<u64>,
+    // Whether the peer asked us to acknowledge the last message we read, once we hand it to
+    // the application
+    read_needs_ack: bool,
+    // When we last wrote to the stream
+    last_write: Instant,
+    // When we give up waiting for the answer to the ping we sent, if any
+    pong_deadline: Option<Instant>,
+    // See [KKTransport::session_token]
+    session_token: [u8; SESSION_TOKEN_SIZE],
 }
 
 impl KKTransport {
     /// Connect to server at given address, and enact Noise handshake with given private key.
+    /// Gives up connecting after [DEFAULT_CONNECT_TIMEOUT].
+    /// No prologue is bound into the handshake, see [KKTransportBuilder::prologue] to set one.
+    /// If the server closes the connection upon our handshake message, as the ones predating
+    /// the version negotiation do, we connect once again offering the [LEGACY_VERSIONS] only.
     pub fn connect(
         addr: SocketAddr,
         my_noise_privkey: &SecretKey,
         their_noise_pubkey: &PublicKey,
     ) -> Result<KKTransport, Error> {
-        let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(10))?;
+        KKTransport::connect_with_timeout(
+            addr,
+            my_noise_privkey,
+            their_noise_pubkey,
+            DEFAULT_CONNECT_TIMEOUT,
+        )
+    }
 
-        let (cli_act_1, msg_1) =
-            KKHandshakeActOne::initiator(my_noise_privkey, their_noise_pubkey)?;
+    /// Connect to server at given address, and enact Noise handshake with given private key.
+    /// The `timeout` applies to the TCP connection and to the handshake each.
+    pub fn connect_with_timeout(
+        addr: SocketAddr,
+        my_noise_privkey: &SecretKey,
+        their_noise_pubkey: &PublicKey,
+        timeout: Duration,
+    ) -> Result<KKTransport, Error> {
+        let dial = || Ok(TcpStream::connect_timeout(&addr, timeout)?);
+        KKTransport::initiator_handshake(
+            dial()?,
+            Some(&dial),
+            my_noise_privkey,
+            their_noise_pubkey,
+            &[],
+            timeout,
+        )
+    }
 
-        // write msg_1 to stream (e, es, ss)
-        stream.write_all(&msg_1.0)?;
+    /// Same as [KKTransport::connect], but the connection originates from the given `local`
+    /// address (eg for a firewall filtering by source port). Its port may be 0 to let the OS
+    /// pick one. `SO_REUSEADDR` is set, so that the same port can be used again right after the
+    /// connection is closed (see [bind_listener]).
+    pub fn connect_from_local(
+        local: SocketAddr,
+        remote: SocketAddr,
+        my_noise_privkey: &SecretKey,
+        their_noise_pubkey: &PublicKey,
+    ) -> Result<KKTransport, Error> {
+        let dial = || {
+            let socket = socket2::Socket::new(
+                socket2::Domain::for_address(remote),
+                socket2::Type::STREAM,
+                None,
+            )?;
+            socket.set_reuse_address(true)?;
+            socket.bind(&local.into())?;
+            socket.connect_timeout(&remote.into(), DEFAULT_CONNECT_TIMEOUT)?;
+            Ok(socket.into())
+        };
+        KKTransport::initiator_handshake(
+            dial()?,
+            Some(&dial),
+            my_noise_privkey,
+            their_noise_pubkey,
+            &[],
+            DEFAULT_HANDSHAKE_TIMEOUT,
+        )
+    }
 
-        // read msg_2 from stream (e, ee, se)
-        let mut msg_2 = [0u8; KK_MSG_2_SIZE];
-        stream.read_exact(&mut msg_2)?;
+    /// Same as [KKTransport::connect_with_timeout], but on failure tells whether the peer was
+    /// unreachable or refused the handshake, see [ConnectError]. The `timeout` applies to the
+    /// TCP connection and to the handshake each.
+    pub fn try_connect(
+        addr: SocketAddr,
+        my_noise_privkey: &SecretKey,
+        their_noise_pubkey: &PublicKey,
+        timeout: Duration,
+    ) -> Result<KKTransport, ConnectError> {
+        let mut versions = PROTOCOL_VERSIONS;
+        let (stream, channel, deadline) = loop {
+            let (cli_act_1, msg_1) = KKHandshakeActOne::initiator(
+                my_noise_privkey,
+                their_noise_pubkey,
+                versions,
+                CipherSuite::default(),
+            )
+            .map_err(ConnectError::InvalidKey)?;
+
+            let stream = TcpStream::connect_timeout(&addr, timeout).map_err(|e| {
+                if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) {
+                    ConnectError::TcpTimeout
+                } else {
+                    ConnectError::Tcp(e)
+                }
+            })?;
+            stream.set_nodelay(true).map_err(ConnectError::Tcp)?;
+
+            let deadline = Instant::now() + timeout;
+            let res = with_deadline(&stream, deadline, None, |stream| {
+                Ok(handshake_initiator_classified(stream, cli_act_1, msg_1))
+            })
+            .map_err(|e| match e {
+                Error::Transport(e) => ConnectError::Tcp(e),
+                e => ConnectError::HandshakeRejected(e),
+            })?;
+            match res {
+                Ok(channel) => break (stream, channel, deadline),
+                // See [KKTransport::initiator_handshake]
+                Err(ConnectError::HandshakeRejected(e))
+                    if versions != LEGACY_VERSIONS && is_unanswered(&e) =>
+                {
+                    debug!("Handshake unanswered, retrying with the legacy versions");
+                    versions = LEGACY_VERSIONS;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        KKTransport::new(Stream::Tcp(stream), channel, deadline).map_err(|e| match e {
+            Error::Transport(e) if e.kind() == ErrorKind::TimedOut => {
+                ConnectError::HandshakeReadTimeout
+            }
+            Error::Transport(e) => ConnectError::Tcp(e),
+            e => ConnectError::HandshakeRejected(e),
+        })
+    }
+
+    /// Resolve `host` and try to connect to each of its addresses in turn (eg both an IPv6 and
+    /// an IPv4 one), giving up on each after [DEFAULT_CONNECT_TIMEOUT]. Returns the first
+    /// connection for which the Noise handshake succeeded, or the last error.
+    pub fn connect_to_host(
+        host: &str,
+        port: u16,
+        my_noise_privkey: &SecretKey,
+        their_noise_pubkey: &PublicKey,
+    ) -> Result<KKTransport, Error> {
+        let mut last_error = None;
+
+        for addr in (host, port).to_socket_addrs()? {
+            match KKTransport::connect_with_timeout(
+                addr,
+                my_noise_privkey,
+                their_noise_pubkey,
+                DEFAULT_CONNECT_TIMEOUT,
+            ) {
+                Ok(transport) => return Ok(transport),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("No address found for host '{}'", host),
+            )
+            .into()
+        }))
+    }
+
+    /// Connect to the server at the given `target` through a SOCKS5 proxy (such as Tor), and
+    /// enact Noise handshake with given private key.
+    /// The target is of the form `host:port`, where `host` is resolved by the proxy (eg an
+    /// onion address).
+    /// Gives up if the proxy didn't open the tunnel after [DEFAULT_HANDSHAKE_TIMEOUT], then
+    /// likewise for the Noise handshake.
+    pub fn connect_through_proxy(
+        proxy: SocketAddr,
+        target: &str,
+        my_noise_privkey: &SecretKey,
+        their_noise_pubkey: &PublicKey,
+    ) -> Result<KKTransport, Error> {
+        let (host, port) = target
+            .rsplit_once(':')
+            .and_then(|(host, port)| port.parse::<u16>().ok().map(|port| (host, port)))
+            .ok_or_else(|| {
+                std::io::Error::new(ErrorKind::InvalidInput, "Target must be 'host:port'")
+            })?;
+
+        let dial = || socks5_dial(&proxy, host, port, DEFAULT_HANDSHAKE_TIMEOUT);
+        KKTransport::initiator_handshake(
+            dial()?,
+            Some(&dial),
+            my_noise_privkey,
+            their_noise_pubkey,
+            &[],
+            DEFAULT_HANDSHAKE_TIMEOUT,
+        )
+    }
+
+    /// Same as [KKTransport::connect_through_proxy], through an HTTP proxy supporting the
+    /// `CONNECT` method instead. The optional `proxy_auth` user and password are sent in the
+    /// clear (Basic authentication) to the proxy.
+    /// A response outside of the 2xx range makes this return an [Error::Transport].
+    pub fn connect_through_http_proxy(
+        proxy: SocketAddr,
+        target: &str,
+        proxy_auth: Option<(&str, &str)>,
+        my_noise_privkey: &SecretKey,
+        their_noise_pubkey: &PublicKey,
+    ) -> Result<KKTransport, Error> {
+        let valid_target = target.rsplit_once(':').is_some_and(|(host, port)| {
+            !host.is_empty()
+                && port.parse::<u16>().is_ok()
+                && !target.contains(|c: char| c.is_whitespace() || c.is_control())
+        });
+        if !valid_target {
+            return Err(
+                std::io::Error::new(ErrorKind::InvalidInput, "Target must be 'host:port'").into(),
+            );
+        }
+
+        let dial = || {
+            let stream = TcpStream::connect_timeout(&proxy, DEFAULT_CONNECT_TIMEOUT)?;
+            with_deadline(
+                &stream,
+                Instant::now() + DEFAULT_HANDSHAKE_TIMEOUT,
+                None,
+                |stream| Ok(http_connect(stream, target, proxy_auth)?),
+            )?;
+            Ok(stream)
+        };
+        KKTransport::initiator_handshake(
+            dial()?,
+            Some(&dial),
+            my_noise_privkey,
+            their_noise_pubkey,
+            &[],
+            DEFAULT_HANDSHAKE_TIMEOUT,
+        )
+    }
 
-        let msg_act_2 = KKMessageActTwo(msg_2);
-        let cli_act_2 = KKHandshakeActTwo::initiator(cli_act_1, &msg_act_2)?;
-        let channel = KKChannel::from_handshake(cli_act_2)?;
-        Ok(KKTransport { stream, channel })
+    /// Enact the Noise handshake as an initiator with given private key over an already
+    /// connected stream. This is useful if the connection needs to be established in a
+    /// special manner (eg through a proxy).
+    /// Gives up if the handshake isn't completed after [DEFAULT_HANDSHAKE_TIMEOUT].
+    /// Unlike [KKTransport::connect] this can't connect again, so a responder predating the
+    /// version negotiation closes the connection: use [handshake_initiator_offering] with the
+    /// [LEGACY_VERSIONS] over a new stream to reach it.
+    pub fn from_stream_initiator(
+        stream: TcpStream,
+        my_noise_privkey: &SecretKey,
+        their_noise_pubkey: &PublicKey,
+    ) -> Result<KKTransport, Error> {
+        KKTransport::initiator_handshake(
+            stream,
+            None,
+            my_noise_privkey,
+            their_noise_pubkey,
+            &[],
+            DEFAULT_HANDSHAKE_TIMEOUT,
+        )
     }
 
     /// Accept an incoming connection and immediately perform the noise KK handshake
     /// as a responder with our single private key and a set of possible public key for them.
     /// This is used by servers to identify the origin of the message.
+    /// Gives up if the handshake isn't completed after [DEFAULT_HANDSHAKE_TIMEOUT], see
+    /// [KKTransport::accept_with_timeout] to configure it. Peers must not use a prologue, see
+    /// [KKTransportBuilder::prologue] to accept ones which do.
     pub fn accept(
         listener: &TcpListener,
         my_noise_privkey: &SecretKey,
         their_possible_pubkeys: &[PublicKey],
     ) -> Result<KKTransport, Error> {
-        let (mut stream, _) = listener.accept().map_err(|e| Error::Transport(e))?;
+        let (stream, _) = listener.accept().map_err(Error::Transport)?;
+        KKTransport::from_stream_responder(stream, my_noise_privkey, their_possible_pubkeys)
+    }
 
-        // read msg_1 from stream
-        let mut msg_1 = [0u8; KK_MSG_1_SIZE];
-        stream.read_exact(&mut msg_1)?;
-        let msg_act_1 = KKMessageActOne(msg_1);
+    /// Same as [KKTransport::accept], but gives up if the handshake isn't completed after
+    /// `timeout` once the connection was accepted. Waiting for a connection is not limited.
+    pub fn accept_with_timeout(
+        listener: &TcpListener,
+        my_noise_privkey: &SecretKey,
+        their_possible_pubkeys: &[PublicKey],
+        timeout: Duration,
+    ) -> Result<KKTransport, Error> {
+        let (stream, _) = listener.accept()?;
+        KKTransport::responder_handshake(
+            stream,
+            my_noise_privkey,
+            their_possible_pubkeys,
+            &[],
+            timeout,
+            |_| true,
+        )
+    }
+
+    /// Like [KKTransport::accept], but `auth` is called with the static key of the peer before
+    /// completing the handshake (eg to check it against a revocation list). If it returns
+    /// `false` the connection is closed and [Error::Rejected] is returned.
+    pub fn accept_with_auth<F: FnOnce(&PublicKey) -> bool>(
+        listener: &TcpListener,
+        my_noise_privkey: &SecretKey,
+        their_possible_pubkeys: &[PublicKey],
+        auth: F,
+    ) -> Result<KKTransport, Error> {
+        let (stream, _) = listener.accept().map_err(Error::Transport)?;
+        KKTransport::responder_handshake(
+            stream,
+            my_noise_privkey,
+            their_possible_pubkeys,
+            &[],
+            DEFAULT_HANDSHAKE_TIMEOUT,
+            auth,
+        )
+    }
+
+    /// Like [KKTransport::accept], but gives up with [Error::Cancelled] as soon as `cancel` is
+    /// set, whether we are waiting for a connection or in the middle of the handshake. The
+    /// flag is polled every few tens of milliseconds. This is used to stop an accept loop
+    /// when shutting down.
+    pub fn accept_with_cancel(
+        listener: &TcpListener,
+        my_noise_privkey: &SecretKey,
+        their_possible_pubkeys: &[PublicKey],
+        cancel: &AtomicBool,
+    ) -> Result<KKTransport, Error> {
+        listener.set_nonblocking(true)?;
+        let accepted = loop {
+            if cancel.load(Ordering::Relaxed) {
+                break Err(Error::Cancelled);
+            }
+            match listener.accept() {
+                Ok((stream, _)) => break Ok(stream),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(CANCEL_POLL_INTERVAL),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => break Err(e.into()),
+            }
+        };
+        listener.set_nonblocking(false)?;
+        // The stream may inherit the non-blocking mode of the listener on some platforms
+        let stream = accepted?;
+        stream.set_nonblocking(false)?;
+
+        KKTransport::responder_handshake_tracked(
+            stream,
+            |msg_act_1| {
+                KKHandshakeActOne::responder(
+                    my_noise_privkey,
+                    their_possible_pubkeys,
+                    msg_act_1,
+                    PROTOCOL_VERSIONS,
+                    CipherSuite::default(),
+                )
+            },
+            DEFAULT_HANDSHAKE_TIMEOUT,
+            Some(cancel),
+            |_| true,
+            &mut HandshakeProgress::new(),
+        )
+    }
+
+    /// Perform the noise KK handshake as a responder over an already accepted stream, with
+    /// our single private key and a set of possible public key for them.
+    pub fn from_stream_responder(
+        stream: TcpStream,
+        my_noise_privkey: &SecretKey,
+        their_possible_pubkeys: &[PublicKey],
+    ) -> Result<KKTransport, Error> {
+        KKTransport::responder_handshake(
+            stream,
+            my_noise_privkey,
+            their_possible_pubkeys,
+            &[],
+            DEFAULT_HANDSHAKE_TIMEOUT,
+            |_| true,
+        )
+    }
+
+    /// Connect to the server listening on the Unix domain socket at this path, and enact
+    /// Noise handshake with given private key.
+    /// Gives up if the handshake isn't completed after [DEFAULT_HANDSHAKE_TIMEOUT].
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(
+        path: P,
+        my_noise_privkey: &SecretKey,
+        their_noise_pubkey: &PublicKey,
+    ) -> Result<KKTransport, Error> {
+        let stream = UnixStream::connect(path)?;
+        let deadline = Instant::now() + DEFAULT_HANDSHAKE_TIMEOUT;
+        let channel = with_deadline(&stream, deadline, None, |stream| {
+            handshake_initiator(stream, my_noise_privkey, their_noise_pubkey)
+        })?;
+        KKTransport::new(Stream::Unix(stream), channel, deadline)
+    }
+
+    /// Accept an incoming connection on a Unix domain socket and immediately perform the
+    /// noise KK handshake as a responder, see [KKTransport::accept].
+    #[cfg(unix)]
+    pub fn accept_unix(
+        listener: &UnixListener,
+        my_noise_privkey: &SecretKey,
+        their_possible_pubkeys: &[PublicKey],
+    ) -> Result<KKTransport, Error> {
+        let (stream, _) = listener.accept()?;
+        let deadline = Instant::now() + DEFAULT_HANDSHAKE_TIMEOUT;
+        let channel = with_deadline(&stream, deadline, None, |stream| {
+            handshake_responder(stream, my_noise_privkey, their_possible_pubkeys)
+        })?;
+        KKTransport::new(Stream::Unix(stream), channel, deadline)
+    }
+
+    // Enact the handshake over `stream`, offering all our PROTOCOL_VERSIONS. A responder
+    // predating the version negotiation closes the connection upon such an offer instead of
+    // answering it. In this case, if we can `redial`, we connect once again and offer the
+    // LEGACY_VERSIONS only, which it understands. Each attempt gets the whole `timeout`.
+    fn initiator_handshake(
+        mut stream: TcpStream,
+        redial: Option<&dyn Fn() -> Result<TcpStream, Error>>,
+        my_noise_privkey: &SecretKey,
+        their_noise_pubkey: &PublicKey,
+        prologue: &[u8],
+        timeout: Duration,
+    ) -> Result<KKTransport, Error> {
+        let mut versions = PROTOCOL_VERSIONS;
+        loop {
+            // Our messages are small and latency-sensitive
+            stream.set_nodelay(true)?;
+
+            let deadline = Instant::now() + timeout;
+            let res = with_deadline(&stream, deadline, None, |stream| {
+                handshake_initiator_offering(
+                    stream,
+                    my_noise_privkey,
+                    their_noise_pubkey,
+                    versions,
+                    prologue,
+                )
+            });
+            match (res, redial) {
+                (Ok(channel), _) => {
+                    return KKTransport::new(Stream::Tcp(stream), channel, deadline)
+                }
+                (Err(e), Some(redial)) if versions != LEGACY_VERSIONS && is_unanswered(&e) => {
+                    debug!("Handshake unanswered, retrying with the legacy versions");
+                    versions = LEGACY_VERSIONS;
+                    stream = redial()?;
+                }
+                (Err(e), _) => return Err(e),
+            }
+        }
+    }
+
+    /// Same as [KKTransport::accept], but on failure reports how far the handshake went, to
+    /// help diagnosing connection issues.
+    pub fn try_accept(
+        listener: &TcpListener,
+        my_noise_privkey: &SecretKey,
+        their_possible_pubkeys: &[PublicKey],
+    ) -> Result<KKTransport, HandshakeFailure> {
+        let (stream, peer_addr) = listener.accept().map_err(|e| HandshakeFailure {
+            peer_addr: None,
+            stage: HandshakeStage::Accept,
+            bytes_read: 0,
+            error: e.into(),
+        })?;
+
+        let mut progress = HandshakeProgress::new();
+        KKTransport::responder_handshake_tracked(
+            stream,
+            |msg_act_1| {
+                KKHandshakeActOne::responder(
+                    my_noise_privkey,
+                    their_possible_pubkeys,
+                    msg_act_1,
+                    PROTOCOL_VERSIONS,
+                    CipherSuite::default(),
+                )
+            },
+            DEFAULT_HANDSHAKE_TIMEOUT,
+            None,
+            |_| true,
+            &mut progress,
+        )
+        .map_err(|error| HandshakeFailure {
+            peer_addr: Some(peer_addr),
+            stage: progress.stage,
+            bytes_read: progress.bytes_read,
+            error,
+        })
+    }
+
+    fn responder_handshake<F: FnOnce(&PublicKey) -> bool>(
+        stream: TcpStream,
+        my_noise_privkey: &SecretKey,
+        their_possible_pubkeys: &[PublicKey],
+        prologue: &[u8],
+        timeout: Duration,
+        auth: F,
+    ) -> Result<KKTransport, Error> {
+        KKTransport::responder_handshake_tracked(
+            stream,
+            |msg_act_1| {
+                KKHandshakeActOne::responder_with_prologue(
+                    my_noise_privkey,
+                    their_possible_pubkeys,
+                    msg_act_1,
+                    PROTOCOL_VERSIONS,
+                    prologue,
+                    CipherSuite::default(),
+                )
+            },
+            timeout,
+            None,
+            auth,
+            &mut HandshakeProgress::new(),
+        )
+    }
+
+    // The first act is left to `respond`, so that the responder state may be reused
+    fn responder_handshake_tracked<R, F>(
+        stream: TcpStream,
+        respond: R,
+        timeout: Duration,
+        cancel: Option<&AtomicBool>,
+        auth: F,
+        progress: &mut HandshakeProgress,
+    ) -> Result<KKTransport, Error>
+    where
+        R: FnOnce(&KKMessageActOne) -> Result<KKHandshakeActOne, NoiseError>,
+        F: FnOnce(&PublicKey) -> bool,
+    {
+        // Our messages are small and latency-sensitive
+        stream.set_nodelay(true)?;
 
-        let serv_act_1 =
-            KKHandshakeActOne::responder(&my_noise_privkey, their_possible_pubkeys, &msg_act_1)?;
-        let (serv_act_2, msg_2) = KKHandshakeActTwo::responder(serv_act_1)?;
-        let channel = KKChannel::from_handshake(serv_act_2)?;
+        let deadline = Instant::now() + timeout;
+        match with_deadline(&stream, deadline, cancel, |stream| {
+            handshake_responder_tracked(stream, respond, auth, progress)
+        }) {
+            Ok(channel) => KKTransport::new(Stream::Tcp(stream), channel, deadline),
+            Err(Error::Rejected) => {
+                // Don't answer more than the refusal, they will notice the connection is closed
+                let _ = stream.shutdown(Shutdown::Both);
+                Err(Error::Rejected)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Sets up the transport over a completed handshake, and exchanges the contributions to the
+    // session token before the `deadline`
+    fn new(stream: Stream, channel: KKChannel, deadline: Instant) -> Result<KKTransport, Error> {
+        let mut transport = KKTransport {
+            stream,
+            channel,
+            pending: VecDeque::new(),
+            pending_size: 0,
+            read_buf: Vec::new(),
+            pending_body_len: None,
+            max_message_size: NOISE_PLAINTEXT_MAX_SIZE,
+            write_pending: Vec::new(),
+            config: TransportConfig::default(),
+            stats: TransportStats::default(),
+            next_sent_seq: 0,
+            last_recv_seq: None,
+            read_needs_ack: false,
+            last_write: Instant::now(),
+            pong_deadline: None,
+            session_token: [0; SESSION_TOKEN_SIZE],
+        };
 
-        // write msg_2 to stream
-        stream.write_all(&msg_2.0)?;
+        // A version 0 peer doesn't send its contribution
+        if transport.channel.negotiated_version() < SESSION_TOKEN_VERSION {
+            let hash = transport.channel.handshake_hash();
+            transport.session_token = sodiumoxide::crypto::hash::sha256::hash(&hash).0;
+            return Ok(transport);
+        }
+
+        let mut ours = [0u8; SESSION_TOKEN_SIZE];
+        sodiumoxide::randombytes::randombytes_into(&mut ours);
+        transport.write_frame(FRAME_SESSION, &ours)?;
+        let prev_timeout = transport.stream.read_timeout()?;
+        let mut theirs = Vec::new();
+        let res = transport.read_frame_until(deadline, &mut theirs);
+        transport.stream.set_read_timeout(prev_timeout)?;
+        if res? != FRAME_SESSION || theirs.len() != SESSION_TOKEN_SIZE {
+            return Err(Error::InvalidFrame);
+        }
+        // Both ends must combine them in the same order
+        let (initiator, responder) = if transport.channel.is_initiator() {
+            (&ours[..], &theirs[..])
+        } else {
+            (&theirs[..], &ours[..])
+        };
+        let mut data = transport.channel.handshake_hash().to_vec();
+        data.extend_from_slice(initiator);
+        data.extend_from_slice(responder);
+        transport.session_token = sodiumoxide::crypto::hash::sha256::hash(&data).0;
 
-        Ok(KKTransport { stream, channel })
+        Ok(transport)
     }
 
     /// Write a message to the other end of the encrypted communication channel. Attempts
     /// to recover from certain kinds of error.
+    /// The message must not be larger than [crate::noise::NOISE_PLAINTEXT_MAX_SIZE] minus one
+    /// byte, which is used internally for the frame type (unless the peer only supports
+    /// protocol version 0), and minus the size of the sequence number if
+    /// [TransportConfig::sequence_numbers] is enabled.
     pub fn write(&mut self, msg: &[u8]) -> Result<(), Error> {
-        let encrypted_msg = self.channel.encrypt_message(msg)?.0;
-        let mut attempts = 0;
+        self.write_frame(FRAME_DATA, msg)?;
+        self.stats.msgs_sent += 1;
+        self.stats.plaintext_bytes_sent += msg.len() as u64;
+        Ok(())
+    }
+
+    /// Same as [KKTransport::write], but never retries: the first error of the stream is
+    /// returned right away whatever the [RetryPolicy], for callers which would rather fail
+    /// fast than sleep between attempts.
+    pub fn write_once(&mut self, msg: &[u8]) -> Result<(), Error> {
+        let encrypted_msg = self.encrypt_frame(FRAME_DATA, msg)?;
+        self.write_all_once(&encrypted_msg)?;
+        self.stats.msgs_sent += 1;
+        self.stats.plaintext_bytes_sent += msg.len() as u64;
+        Ok(())
+    }
+
+    /// Write a message to the other end of the encrypted communication channel, without
+    /// blocking. The part of the ciphertext the OS doesn't accept right away is kept, and
+    /// written by the next calls to [KKTransport::flush_pending] (or this function).
+    /// The message is encrypted once and for all, so it's always delivered whole. Any other
+    /// write, or [KKTransport::flush], first blocks until the pending bytes are written to
+    /// preserve the order of the messages. The pending bytes are lost if the transport is
+    /// dropped.
+    pub fn write_nonblocking(&mut self, msg: &[u8]) -> Result<WriteProgress, Error> {
+        let encrypted_msg = self.encrypt_frame(FRAME_DATA, msg)?;
+        self.write_pending.extend_from_slice(&encrypted_msg);
+        self.stats.msgs_sent += 1;
+        self.stats.plaintext_bytes_sent += msg.len() as u64;
+        self.flush_pending()
+    }
+
+    /// Write as much as possible of what [KKTransport::write_nonblocking] left, without
+    /// blocking.
+    pub fn flush_pending(&mut self) -> Result<WriteProgress, Error> {
+        let mut flushed = 0;
+        self.stream.set_nonblocking(true)?;
+        let res = self.write_pending_tail(&mut flushed);
+        self.stream.set_nonblocking(false)?;
+        match res {
+            Err(e) if e.kind() != ErrorKind::WouldBlock => Err(e.into()),
+            _ => Ok(WriteProgress {
+                flushed,
+                pending: self.write_pending.len(),
+            }),
+        }
+    }
+
+    /// Write a message to the other end of the encrypted communication channel, and wait for
+    /// it to acknowledge it. The other end must enable [TransportConfig::acks].
+    /// May return [Error::BufferFull], see [KKTransport::rekey].
+    /// The message is acknowledged by the other end's transport once it hands it to its
+    /// application (from [KKTransport::read] or similar), which tells it was delivered rather
+    /// than just handed to our TCP stack. This costs a round trip for each message, and blocks
+    /// for as long as the other end doesn't read: both ends can't wait for an acknowledgement
+    /// from each other at the same time.
+    /// Application messages received in the meantime are kept for the next calls to
+    /// [KKTransport::read].
+    pub fn write_acked(&mut self, msg: &[u8]) -> Result<(), Error> {
+        self.write_frame(FRAME_DATA_ACKED, msg)?;
+        self.stats.msgs_sent += 1;
+        self.stats.plaintext_bytes_sent += msg.len() as u64;
+
         loop {
-            match self.stream.write_all(&encrypted_msg) {
-                Ok(n) => return Ok(n),
-                // write_all returns the first error of non-ErrorKind::Interrupted kind that
-                // write returns, in which case no bytes were written to the writer, and can
-                // try again. Here we try up to 5 times.
-                Err(e) => {
-                    attempts += 1;
-                    if attempts == 5 {
-                        return Err(Error::from(e));
-                    } else {
-                        thread::sleep(Duration::from_secs(1));
-                        continue;
-                    }
-                }
+            let (frame_type, _) = self.read_control_frame()?;
+            match frame_type {
+                FRAME_ACK => return Ok(()),
+                frame_type => self.handle_control_frame(frame_type)?,
             }
         }
     }
 
-    /// Read a message from the other end of the encrypted communication channel.
-    fn _read(&mut self) -> Result<Vec<u8>, Error> {
-        let mut cypherheader = [0u8; NOISE_MESSAGE_HEADER_SIZE];
-        self.stream.read_exact(&mut cypherheader)?;
-        let msg_len = self
-            .channel
-            .decrypt_header(&NoiseEncryptedHeader(cypherheader))?;
+    /// Write several messages to the other end of the encrypted communication channel, with a
+    /// single write to the stream. They are read one at a time by the other end.
+    /// If one of the messages is too large (see [KKTransport::write]), none is written.
+    pub fn write_batch(&mut self, msgs: &[&[u8]]) -> Result<(), Error> {
+        if msgs
+            .iter()
+            .any(|msg| msg.len() + self.data_overhead() > NOISE_PLAINTEXT_MAX_SIZE)
+        {
+            return Err(NoiseError::InvalidPlaintext.into());
+        }
 
-        // Note that `msg_len` cannot be > 65K (2 bytes)
-        let mut cypherbody = vec![0u8; msg_len as usize];
-        self.stream.read_exact(&mut cypherbody)?;
-        self.channel
-            .decrypt_message(&NoiseEncryptedMessage(cypherbody))
-            .map_err(|e| e.into())
+        let mut encrypted_msgs = Vec::new();
+        for msg in msgs {
+            encrypted_msgs.extend_from_slice(&self.encrypt_frame(FRAME_DATA, msg)?);
+        }
+        self.write_all(&encrypted_msgs)?;
+        self.stats.msgs_sent += msgs.len() as u64;
+        self.stats.plaintext_bytes_sent += msgs.iter().map(|msg| msg.len() as u64).sum::<u64>();
+        Ok(())
     }
 
-    /// Read a message from the other end of the encrypted communication channel.
-    /// Will recover from certain kinds of error, those for which no bytes are
-    /// read from the stream, by retrying up to 5 times with a 1s sleep between
-    /// attempts. After 5 attempts, or an unrecoverable error, will return an
-    /// error.  
-    pub fn read(&mut self) -> Result<Vec<u8>, Error> {
-        let mut attempts = 0;
+    /// Stream all the data from this reader to the other end of the encrypted communication
+    /// channel, without holding it all in memory. It must be read with
+    /// [KKTransport::read_to]. Returns the number of bytes streamed.
+    /// Returns [Error::Unsupported] if the peer only supports protocol version 0.
+    pub fn write_from<R: Read>(&mut self, reader: &mut R) -> Result<u64, Error> {
+        if !self.is_framed() {
+            return Err(Error::Unsupported);
+        }
+        let mut chunk = vec![0u8; NOISE_PLAINTEXT_MAX_SIZE - FRAME_TYPE_SIZE];
+        let mut total = 0;
         loop {
-            match self._read() {
-                Ok(msg) => return Ok(msg),
-                Err(error) => match error {
-                    e if attempts == 4 => return Err(e),
-                    Error::Transport(e) => match e.kind() {
-                        ErrorKind::UnexpectedEof => return Err(Error::Transport(e)),
-                        ErrorKind::Interrupted => return Err(Error::Transport(e)),
-                        _ => {
-                            thread::sleep(Duration::from_secs(1));
-                            continue;
-                        }
-                    },
-                    e => e,
-                },
+            let len = match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
             };
-            attempts += 1;
+            self.write_frame(FRAME_STREAM, &chunk[..len])?;
+            total += len as u64;
         }
+        self.write_frame(FRAME_STREAM_END, &[])?;
+
+        Ok(total)
     }
 
-    /// Get the static public key of the peer
-    pub fn remote_static(&self) -> PublicKey {
-        self.channel.remote_static()
+    /// Read the data streamed by the other end with [KKTransport::write_from] into this
+    /// writer. Returns the number of bytes streamed.
+    /// Application messages received in the meantime are kept for the next calls to
+    /// [KKTransport::read]. If writing fails, or this returns [Error::BufferFull] (see
+    /// [KKTransport::rekey]), the rest of the data must not be read anymore.
+    pub fn read_to<W: Write>(&mut self, writer: &mut W) -> Result<u64, Error> {
+        if !self.is_framed() {
+            return Err(Error::Unsupported);
+        }
+        let mut total = 0;
+        loop {
+            let (frame_type, msg) = self.read_control_frame()?;
+            match frame_type {
+                FRAME_STREAM => {
+                    writer.write_all(&msg)?;
+                    total += msg.len() as u64;
+                }
+                FRAME_STREAM_END => return Ok(total),
+                frame_type => self.handle_control_frame(frame_type)?,
+            }
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::gen_keypair;
-    use std::thread;
+    // Whether the peer supports the frame types, see [FRAMED_VERSION]
+    fn is_framed(&self) -> bool {
+        self.channel.negotiated_version() >= FRAMED_VERSION
+    }
+
+    // The size of what we prepend to the application messages
+    fn data_overhead(&self) -> usize {
+        if !self.is_framed() {
+            0
+        } else if self.config.sequence_numbers {
+            FRAME_TYPE_SIZE + SEQUENCE_NUMBER_SIZE
+        } else {
+            FRAME_TYPE_SIZE
+        }
+    }
+
+    fn encrypt_frame(&mut self, frame_type: u8, msg: &[u8]) -> Result<Vec<u8>, Error> {
+        if !self.is_framed() {
+            if frame_type != FRAME_DATA || self.config.sequence_numbers {
+                return Err(Error::Unsupported);
+            }
+            return Ok(self.channel.encrypt_message(msg)?.0);
+        }
+        let mut plaintext = Vec::with_capacity(self.data_overhead() + msg.len());
+        plaintext.push(frame_type);
+        if matches!(frame_type, FRAME_DATA | FRAME_DATA_ACKED) && self.config.sequence_numbers {
+            plaintext.extend_from_slice(&self.next_sent_seq.to_be_bytes());
+            self.next_sent_seq += 1;
+        }
+        plaintext.extend_from_slice(msg);
+        Ok(self.channel.encrypt_message(&plaintext)?.0)
+    }
+
+    fn write_frame(&mut self, frame_type: u8, msg: &[u8]) -> Result<(), Error> {
+        let encrypted_msg = self.encrypt_frame(frame_type, msg)?;
+        self.write_all(&encrypted_msg)
+    }
+
+    // Write what a non-blocking write left, keeping track of the progress even on error
+    fn write_pending_tail(&mut self, flushed: &mut usize) -> std::io::Result<()> {
+        while !self.write_pending.is_empty() {
+            match self.stream.write(&self.write_pending) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.write_pending.drain(..n);
+                    self.stats.bytes_sent += n as u64;
+                    self.last_write = Instant::now();
+                    *flushed += n;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    // A single attempt at writing to the stream. What a non-blocking write left goes first.
+    // On error, the part of the message that wasn't written is kept for the next writes: the
+    // other end couldn't decrypt the following messages without it, and it must not be
+    // written twice.
+    fn write_all_once(&mut self, encrypted_msg: &[u8]) -> std::io::Result<()> {
+        if !self.write_pending.is_empty() {
+            self.write_pending.extend_from_slice(encrypted_msg);
+            return self.write_pending_tail(&mut 0);
+        }
+
+        let mut written = 0;
+        while written < encrypted_msg.len() {
+            match self.stream.write(&encrypted_msg[written..]) {
+                Ok(0) => {
+                    self.write_pending
+                        .extend_from_slice(&encrypted_msg[written..]);
+                    return Err(ErrorKind::WriteZero.into());
+                }
+                Ok(n) => {
+                    written += n;
+                    self.stats.bytes_sent += n as u64;
+                    self.last_write = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.write_pending
+                        .extend_from_slice(&encrypted_msg[written..]);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_all(&mut self, encrypted_msg: &[u8]) -> Result<(), Error> {
+        let mut attempts = 0;
+        let mut res = self.write_all_once(encrypted_msg);
+        loop {
+            match res {
+                Ok(()) => return Ok(()),
+                // Whatever failed to be written is pending, so we only need to write the
+                // pending bytes again. Here we try up to `max_attempts` times.
+                Err(e) => {
+                    attempts += 1;
+                    if attempts >= self.config.retry.max_attempts {
+                        debug!("Write failed after {} attempts: {:?}", attempts, e.kind());
+                        return Err(Error::from(e));
+                    } else {
+                        debug!(
+                            "Write failed ({:?}), retrying (attempt {} out of {})",
+                            e.kind(),
+                            attempts,
+                            self.config.retry.max_attempts
+                        );
+                        thread::sleep(self.config.retry.delay(attempts));
+                        res = self.write_pending_tail(&mut 0);
+                    }
+                }
+            }
+        }
+    }
+
+    // Read from the stream until at least `len` bytes are buffered. What was read is kept
+    // even on error, so this can be called again to resume.
+    fn fill_read_buf(&mut self, len: usize) -> std::io::Result<()> {
+        while self.read_buf.len() < len {
+            let start = self.read_buf.len();
+            self.read_buf.resize(len, 0);
+            match self.stream.read(&mut self.read_buf[start..]) {
+                Ok(0) => {
+                    self.read_buf.truncate(start);
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                Ok(n) => self.read_buf.truncate(start + n),
+                Err(e) => {
+                    self.read_buf.truncate(start);
+                    if e.kind() != ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read a message from the other end of the encrypted communication channel, into the
+    /// given buffer.
+    /// If this fails because of the stream, it can be called again to resume reading
+    /// the message.
+    fn _read(&mut self, plaintext: &mut Vec<u8>) -> Result<(), Error> {
+        if self.config.sequence_numbers && !self.is_framed() {
+            return Err(Error::Unsupported);
+        }
+        let res = self._read_inner(plaintext);
+        res.map_err(|e| self.eof_error(e))
+    }
+
+    // Tell whether the peer closed the connection between two messages or in the middle of one
+    fn eof_error(&self, error: Error) -> Error {
+        match error {
+            Error::Transport(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                if self.read_buf.is_empty() && self.pending_body_len.is_none() {
+                    Error::CleanEof
+                } else {
+                    Error::TruncatedMessage
+                }
+            }
+            e => e,
+        }
+    }
+
+    // Get the size of the next body to read, reading and decrypting its header if we didn't
+    // already
+    fn read_header(&mut self) -> Result<usize, Error> {
+        if let Some(len) = self.pending_body_len {
+            return Ok(len);
+        }
+
+        self.fill_read_buf(NOISE_MESSAGE_HEADER_SIZE)?;
+        let cypherheader = self.read_buf[..NOISE_MESSAGE_HEADER_SIZE]
+            .try_into()
+            .expect("We just checked it was NOISE_MESSAGE_HEADER_SIZE long");
+        self.read_buf.drain(..NOISE_MESSAGE_HEADER_SIZE);
+        self.stats.bytes_recv += NOISE_MESSAGE_HEADER_SIZE as u64;
+        let len = body_len_from_header(
+            self.channel
+                .decrypt_header(&NoiseEncryptedHeader(cypherheader))?,
+        );
+        self.pending_body_len = Some(len);
+        Ok(len)
+    }
+
+    fn _read_inner(&mut self, plaintext: &mut Vec<u8>) -> Result<(), Error> {
+        let msg_len = self.read_header()?;
+
+        // Note that `msg_len` cannot be > 65K (2 bytes)
+        self.fill_read_buf(msg_len)?;
+        self.pending_body_len = None;
+        let res = self
+            .channel
+            .decrypt_into(&self.read_buf[..msg_len], plaintext);
+        self.read_buf.drain(..msg_len);
+        self.stats.bytes_recv += msg_len as u64;
+        res?;
+        if !self.is_framed() {
+            self.stats.msgs_recv += 1;
+            self.stats.plaintext_bytes_recv += plaintext.len() as u64;
+            return Ok(());
+        }
+        self.read_needs_ack = plaintext.first() == Some(&FRAME_DATA_ACKED) && self.config.acks;
+        if self.read_needs_ack {
+            // From now on it's a regular application message, acknowledged once delivered
+            plaintext[0] = FRAME_DATA;
+        }
+        if plaintext.first() == Some(&FRAME_DATA) {
+            if self.config.sequence_numbers {
+                self.check_sequence_number(plaintext)?;
+            }
+            self.stats.msgs_recv += 1;
+            self.stats.plaintext_bytes_recv += (plaintext.len() - FRAME_TYPE_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    // Check and remove the sequence number following the frame type of an application message
+    fn check_sequence_number(&mut self, plaintext: &mut Vec<u8>) -> Result<(), Error> {
+        let seq: [u8; SEQUENCE_NUMBER_SIZE] = plaintext
+            .get(FRAME_TYPE_SIZE..FRAME_TYPE_SIZE + SEQUENCE_NUMBER_SIZE)
+            .ok_or(Error::InvalidFrame)?
+            .try_into()
+            .expect("Just sliced it");
+        let seq = u64::from_be_bytes(seq);
+        if matches!(self.last_recv_seq, Some(last) if seq <= last) {
+            return Err(Error::ReplayDetected);
+        }
+        self.last_recv_seq = Some(seq);
+        plaintext.drain(FRAME_TYPE_SIZE..FRAME_TYPE_SIZE + SEQUENCE_NUMBER_SIZE);
+        Ok(())
+    }
+
+    /// Read a message from the other end of the encrypted communication channel.
+    /// Will recover from certain kinds of error, those for which no bytes are
+    /// read from the stream, by retrying as per the [RetryPolicy] (by default up to 5
+    /// times with a 1s sleep between attempts). After the last attempt, or an unrecoverable
+    /// error, will return an error.
+    /// Control messages (eg a rekey request) are handled transparently.
+    pub fn read(&mut self) -> Result<Vec<u8>, Error> {
+        if let Some((msg, needs_ack)) = self.pop_pending() {
+            self.deliver(needs_ack);
+            return Ok(msg);
+        }
+
+        let mut msg = Vec::new();
+        self.read_into(&mut msg)?;
+        Ok(msg)
+    }
+
+    /// Write a public key as a single message, to be read with [KKTransport::read_pubkey]
+    pub fn write_pubkey(&mut self, pubkey: &PublicKey) -> Result<(), Error> {
+        self.write(pubkey.as_bytes())
+    }
+
+    /// Read a public key sent with [KKTransport::write_pubkey]. Returns
+    /// [NoiseError::InvalidKey] if the message is not [crate::noise::KEY_SIZE] bytes long.
+    pub fn read_pubkey(&mut self) -> Result<PublicKey, Error> {
+        let msg = self.read()?;
+        Ok(public_key_from_bytes(&msg)?)
+    }
+
+    /// Read a message from the other end of the encrypted communication channel into the
+    /// given buffer, reusing its allocation. The buffer is cleared beforehand.
+    /// Returns the size of the message. See [KKTransport::read].
+    pub fn read_into(&mut self, buf: &mut Vec<u8>) -> Result<usize, Error> {
+        if let Some((msg, needs_ack)) = self.pop_pending() {
+            self.deliver(needs_ack);
+            buf.clear();
+            buf.extend_from_slice(&msg);
+            return Ok(buf.len());
+        }
+
+        loop {
+            match self.config.ping {
+                Some(policy) => self.read_plaintext_pinging(buf, policy)?,
+                None => self.read_plaintext(buf)?,
+            }
+            match self.pop_frame_type(buf)? {
+                FRAME_DATA => {
+                    self.deliver(self.read_needs_ack);
+                    return Ok(buf.len());
+                }
+                frame_type => self.handle_control_frame(frame_type)?,
+            }
+        }
+    }
+
+    // Process a control frame we received. Errors on unknown frame types, as there is no way
+    // we can recover from a peer speaking a different protocol than us.
+    fn handle_control_frame(&mut self, frame_type: u8) -> Result<(), Error> {
+        match frame_type {
+            FRAME_REKEY => {
+                // They rotated their outgoing key, rotate ours in turn.
+                self.channel.rekey_incoming();
+                self.write_frame(FRAME_REKEY_ACK, &[])?;
+                self.channel.rekey_outgoing();
+            }
+            FRAME_REKEY_ACK => self.channel.rekey_incoming(),
+            FRAME_CLOSE => return Err(Error::ConnectionClosed),
+            FRAME_PING => self.write_frame(FRAME_PONG, &[])?,
+            FRAME_PONG => self.pong_deadline = None,
+            // An acknowledgement we stopped waiting for, eg as write_acked timed out
+            FRAME_ACK => {}
+            _ => return Err(Error::InvalidFrame),
+        }
+
+        Ok(())
+    }
+
+    /// Rotate the keys of both directions of the encrypted communication channel. This is
+    /// advised for long-lived connections, before reaching the cipher nonce limit.
+    /// This sends a rekey request to the other end, and waits for it to acknowledge it.
+    /// Application messages received in the meantime are kept for the next calls to
+    /// [KKTransport::read]. If they exceed [TransportConfig::read_buffer_size], this returns
+    /// [Error::BufferFull] and the acknowledgement is processed by the next reads once the
+    /// application read them. A single message larger than this size is discarded, and the
+    /// connection should be closed.
+    /// A peer which only supports protocol version 0 can't rekey, this returns
+    /// [Error::Unsupported] without sending anything.
+    pub fn rekey(&mut self) -> Result<(), Error> {
+        self.write_frame(FRAME_REKEY, &[])?;
+        self.channel.rekey_outgoing();
+
+        loop {
+            let (frame_type, _) = self.read_control_frame()?;
+            match frame_type {
+                FRAME_REKEY_ACK => {
+                    self.channel.rekey_incoming();
+                    return Ok(());
+                }
+                // If they started a rekey concurrently it's fine, as each frame only rotates
+                // a single direction.
+                frame_type => self.handle_control_frame(frame_type)?,
+            }
+        }
+    }
+
+    /// Read a message from the other end of the encrypted communication channel, giving up
+    /// once the `deadline` is passed. This is not affected by the [RetryPolicy].
+    /// Returns an [Error::Transport] of kind [ErrorKind::TimedOut] if no full message was
+    /// received in time. As for [KKTransport::try_read], the partially received message is
+    /// kept for the next read.
+    pub fn read_until(&mut self, deadline: Instant) -> Result<Vec<u8>, Error> {
+        if let Some((msg, needs_ack)) = self.pop_pending() {
+            self.deliver(needs_ack);
+            return Ok(msg);
+        }
+
+        let prev_timeout = self.stream.read_timeout()?;
+        let res = self.read_until_inner(deadline);
+        self.stream.set_read_timeout(prev_timeout)?;
+        res
+    }
+
+    fn read_until_inner(&mut self, deadline: Instant) -> Result<Vec<u8>, Error> {
+        let mut msg = Vec::new();
+        loop {
+            match self.read_frame_until(deadline, &mut msg)? {
+                FRAME_DATA => {
+                    self.deliver(self.read_needs_ack);
+                    return Ok(msg);
+                }
+                frame_type => self.handle_control_frame(frame_type)?,
+            }
+        }
+    }
+
+    // Read the next frame into `msg` before the deadline, and return its type. Changes the
+    // read timeout of the stream.
+    fn read_frame_until(&mut self, deadline: Instant, msg: &mut Vec<u8>) -> Result<u8, Error> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Err(std::io::Error::from(ErrorKind::TimedOut).into());
+            }
+            self.stream.set_read_timeout(Some(remaining))?;
+
+            match self._read(msg) {
+                Ok(()) => return self.pop_frame_type(msg),
+                // The socket timed out, or was interrupted. Check the deadline again.
+                Err(Error::Transport(e))
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Read a message from the other end of the encrypted communication channel, if a full
+    /// one is available, without blocking.
+    /// Returns `None` if no full message was received yet. The partially received message is
+    /// kept across calls.
+    pub fn try_read(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.try_read_undelivered()?.map(|(msg, needs_ack)| {
+            self.deliver(needs_ack);
+            msg
+        }))
+    }
+
+    // Same as try_read, but leaves it to the caller to deliver the message
+    fn try_read_undelivered(&mut self) -> Result<Option<(Vec<u8>, bool)>, Error> {
+        if let Some(msg) = self.pop_pending() {
+            return Ok(Some(msg));
+        }
+
+        let mut msg = Vec::new();
+        loop {
+            self.stream.set_nonblocking(true)?;
+            let res = self._read(&mut msg);
+            // Control frames may need us to write, so we always get back to blocking mode.
+            self.stream.set_nonblocking(false)?;
+
+            match res {
+                Ok(()) => match self.pop_frame_type(&mut msg)? {
+                    FRAME_DATA => return Ok(Some((msg, self.read_needs_ack))),
+                    frame_type => self.handle_control_frame(frame_type)?,
+                },
+                Err(Error::Transport(e)) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Get the size of the next message, without blocking nor consuming it, eg to size a
+    /// buffer for [KKTransport::read_into]. Returns `None` if its header wasn't received yet.
+    /// The length is encrypted, so peeking at the stream is not enough: this reads and
+    /// decrypts the header, and keeps it for the next read.
+    /// The size is exact unless a control message comes first, in which case it's an
+    /// upper bound.
+    pub fn peek_message_len(&mut self) -> Result<Option<u16>, Error> {
+        if let Some((msg, _)) = self.pending.front() {
+            return Ok(Some(msg.len() as u16));
+        }
+
+        self.stream.set_nonblocking(true)?;
+        let res = self.read_header();
+        self.stream.set_nonblocking(false)?;
+        match res {
+            // The body contains the frame type and its MAC, control frames don't contain more
+            Ok(len) => Ok(Some(
+                len.saturating_sub(MAC_SIZE + self.data_overhead()) as u16
+            )),
+            Err(Error::Transport(e)) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(self.eof_error(e)),
+        }
+    }
+
+    /// Read all the messages already received from the other end of the encrypted
+    /// communication channel, without blocking. See [KKTransport::try_read].
+    /// This stops once the messages exceed [TransportConfig::read_buffer_size], the next ones
+    /// are left for the next calls.
+    /// If this fails, the messages read beforehand are kept for the next calls to
+    /// [KKTransport::read].
+    pub fn read_available(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        let (mut msgs, mut size) = (Vec::new(), 0);
+        while size < self.config.read_buffer_size {
+            match self.try_read_undelivered() {
+                Ok(Some(msg)) => {
+                    size += buffered_size(&msg.0);
+                    msgs.push(msg);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    for msg in msgs.into_iter().rev() {
+                        self.pending_size += buffered_size(&msg.0);
+                        self.pending.push_front(msg);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(msgs
+            .into_iter()
+            .map(|(msg, needs_ack)| {
+                self.deliver(needs_ack);
+                msg
+            })
+            .collect())
+    }
+
+    // Remove the frame type from the content of a transport message, and return it. All the
+    // messages of a version 0 peer are application ones.
+    fn pop_frame_type(&self, plaintext: &mut Vec<u8>) -> Result<u8, Error> {
+        if !self.is_framed() {
+            return Ok(FRAME_DATA);
+        }
+        if plaintext.is_empty() {
+            return Err(Error::InvalidFrame);
+        }
+        Ok(plaintext
+            .drain(..FRAME_TYPE_SIZE)
+            .next()
+            .expect("Not empty"))
+    }
+
+    // Read frames until one that is not an application message, keeping the application
+    // messages for the next reads. Stops reading from the stream once they fill the buffer.
+    fn read_control_frame(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        loop {
+            if self.pending_size >= self.config.read_buffer_size {
+                return Err(Error::BufferFull);
+            }
+            let (frame_type, msg) = self.read_frame()?;
+            if frame_type != FRAME_DATA {
+                return Ok((frame_type, msg));
+            }
+            if buffered_size(&msg) > self.config.read_buffer_size {
+                return Err(Error::BufferFull);
+            }
+            self.pending_size += buffered_size(&msg);
+            self.pending.push_back((msg, self.read_needs_ack));
+        }
+    }
+
+    // Get the next application message kept by read_control_frame, and whether it must be
+    // acknowledged once delivered
+    fn pop_pending(&mut self) -> Option<(Vec<u8>, bool)> {
+        let (msg, needs_ack) = self.pending.pop_front()?;
+        self.pending_size -= buffered_size(&msg);
+        Some((msg, needs_ack))
+    }
+
+    // Called as we hand an application message over, to acknowledge it if the peer asked to.
+    // This never fails, so the message is never lost: what we can't write now is kept for
+    // the next writes.
+    fn deliver(&mut self, needs_ack: bool) {
+        if !needs_ack {
+            return;
+        }
+        match self.encrypt_frame(FRAME_ACK, &[]) {
+            Ok(ack) => {
+                self.write_pending.extend_from_slice(&ack);
+                if let Err(e) = self.write_pending_tail(&mut 0) {
+                    debug!("Could not write an acknowledgement yet: {:?}", e.kind());
+                }
+            }
+            // The channel can't encrypt anymore, the next write will tell
+            Err(e) => debug!("Could not encrypt an acknowledgement: {}", e),
+        }
+    }
+
+    // Read a frame from the other end of the encrypted communication channel, and split
+    // its type from its content.
+    fn read_frame(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        let mut msg = Vec::new();
+        self.read_plaintext(&mut msg)?;
+        let frame_type = self.pop_frame_type(&mut msg)?;
+        Ok((frame_type, msg))
+    }
+
+    fn read_plaintext(&mut self, plaintext: &mut Vec<u8>) -> Result<(), Error> {
+        let mut attempts = 0;
+        loop {
+            match self._read(plaintext) {
+                Ok(()) => return Ok(()),
+                Err(Error::Transport(e)) if e.kind() != ErrorKind::Interrupted => {
+                    // What was read so far is kept, so we can resume.
+                    attempts += 1;
+                    if attempts >= self.config.retry.max_attempts {
+                        debug!("Read failed after {} attempts: {:?}", attempts, e.kind());
+                        return Err(Error::Transport(e));
+                    }
+                    debug!(
+                        "Read failed ({:?}), retrying (attempt {} out of {})",
+                        e.kind(),
+                        attempts,
+                        self.config.retry.max_attempts
+                    );
+                    thread::sleep(self.config.retry.delay(attempts));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Same as read_plaintext, but send a ping whenever we didn't write for the interval of
+    // the policy, and give up if it's not answered in time.
+    fn read_plaintext_pinging(
+        &mut self,
+        plaintext: &mut Vec<u8>,
+        policy: PingPolicy,
+    ) -> Result<(), Error> {
+        let prev_timeout = self.stream.read_timeout()?;
+        let res = self.read_plaintext_pinging_inner(plaintext, policy);
+        self.stream.set_read_timeout(prev_timeout)?;
+        res
+    }
+
+    fn read_plaintext_pinging_inner(
+        &mut self,
+        plaintext: &mut Vec<u8>,
+        policy: PingPolicy,
+    ) -> Result<(), Error> {
+        loop {
+            let now = Instant::now();
+            match self.pong_deadline {
+                Some(deadline) if now >= deadline => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::TimedOut,
+                        "Peer did not answer our ping",
+                    )
+                    .into());
+                }
+                None if now >= self.last_write + policy.interval => {
+                    self.write_frame(FRAME_PING, &[])?;
+                    self.pong_deadline = Some(Instant::now() + policy.timeout);
+                    continue;
+                }
+                _ => {}
+            }
+            let wakeup = self
+                .pong_deadline
+                .unwrap_or(self.last_write + policy.interval);
+            self.stream.set_read_timeout(Some(
+                wakeup
+                    .saturating_duration_since(now)
+                    .max(Duration::from_millis(1)),
+            ))?;
+
+            match self._read(plaintext) {
+                Ok(()) => return Ok(()),
+                // The socket timed out, or was interrupted. Check whether to ping again.
+                Err(Error::Transport(e))
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Iterate over the messages received from the other end of the channel. The iteration
+    /// ends once the connection is closed, either gracefully or not, and yields the other
+    /// errors. A connection closed in the middle of a message yields a last
+    /// [Error::TruncatedMessage].
+    pub fn messages(&mut self) -> Messages<'_> {
+        Messages {
+            transport: self,
+            done: false,
+        }
+    }
+
+    /// Get the counters of messages and bytes sent and received so far
+    pub fn stats(&self) -> TransportStats {
+        self.stats
+    }
+
+    /// Flush the underlying stream, making sure all the messages written so far were handed
+    /// over to the OS. This blocks for the bytes a [KKTransport::write_nonblocking] left.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.write_pending_tail(&mut 0)?;
+        self.stream.flush().map_err(|e| e.into())
+    }
+
+    /// Set the maximum size of a message we accept to read. A peer announcing a larger message
+    /// makes the read fail with [crate::noise::NoiseError::InvalidCiphertext] before we
+    /// allocate for it. Defaults to [NOISE_PLAINTEXT_MAX_SIZE].
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size.min(NOISE_PLAINTEXT_MAX_SIZE);
+        self.channel
+            .set_max_message_size(self.max_message_size + self.data_overhead() + MAC_SIZE);
+    }
+
+    /// Get the maximum size of a message we accept to read
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// Get the configuration of this transport
+    pub fn config(&self) -> TransportConfig {
+        self.config
+    }
+
+    /// Set the configuration of this transport
+    pub fn set_config(&mut self, config: TransportConfig) {
+        self.config = config;
+        // The overhead of the application messages may have changed
+        self.set_max_message_size(self.max_message_size);
+    }
+
+    /// Set how to retry reads and writes which failed without making progress
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.config.retry = policy;
+    }
+
+    /// Gracefully close the connection. This notifies the other end, which will get an
+    /// [Error::ConnectionClosed] from its next read once it processed the messages sent
+    /// before, then flushes and shuts down both directions of the stream.
+    /// Messages the other end sent that weren't read yet are lost. A peer which only supports
+    /// protocol version 0 isn't notified, and gets an [Error::CleanEof] instead.
+    #[doc(alias = "close")]
+    pub fn shutdown(mut self) -> Result<(), Error> {
+        if self.is_framed() {
+            self.write_frame(FRAME_CLOSE, &[])?;
+        }
+        self.stream.flush()?;
+        self.stream.shutdown(Shutdown::Both).map_err(|e| e.into())
+    }
+
+    /// Close our half of the connection. Like [KKTransport::shutdown] the other end gets an
+    /// [Error::ConnectionClosed] once it read all our messages, but we can keep on reading
+    /// the messages it sends until it closes its half too. Writing afterwards fails.
+    pub fn close_write(&mut self) -> Result<(), Error> {
+        if self.is_framed() {
+            self.write_frame(FRAME_CLOSE, &[])?;
+        }
+        self.stream.flush()?;
+        self.stream.shutdown(Shutdown::Write).map_err(|e| e.into())
+    }
+
+    /// Set the timeout of each read from the stream, or block indefinitely if `None` (the
+    /// default). A read which times out returns an [Error::Transport] (see
+    /// [Error::is_timeout]) once the [RetryPolicy] is exhausted. What was received of a message
+    /// is kept, and nothing is decrypted before it's complete, so reading again resumes it.
+    /// A zero duration is refused with an [Error::Transport].
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.stream.set_read_timeout(timeout).map_err(|e| e.into())
+    }
+
+    /// Get the timeout of each read from the stream, see [KKTransport::set_read_timeout]
+    pub fn read_timeout(&self) -> Result<Option<Duration>, Error> {
+        self.stream.read_timeout().map_err(|e| e.into())
+    }
+
+    /// Set the timeout of each write to the stream, or block indefinitely if `None` (the
+    /// default). A write which times out returns an [Error::Transport] (see
+    /// [Error::is_timeout]) once the [RetryPolicy] is exhausted. The message may have been
+    /// partially written, so its remainder is kept and written before any subsequent message
+    /// (or by [KKTransport::flush] and [KKTransport::flush_pending]).
+    /// A zero duration is refused with an [Error::Transport].
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.stream.set_write_timeout(timeout).map_err(|e| e.into())
+    }
+
+    /// Get the timeout of each write to the stream, see [KKTransport::set_write_timeout]
+    pub fn write_timeout(&self) -> Result<Option<Duration>, Error> {
+        self.stream.write_timeout().map_err(|e| e.into())
+    }
+
+    /// Enable or disable Nagle's algorithm (`TCP_NODELAY`). Nagle's algorithm is disabled by
+    /// default after connecting or accepting.
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<(), Error> {
+        self.tcp_stream()?
+            .set_nodelay(nodelay)
+            .map_err(|e| e.into())
+    }
+
+    /// Whether Nagle's algorithm is disabled (`TCP_NODELAY` is set)
+    pub fn nodelay(&self) -> Result<bool, Error> {
+        self.tcp_stream()?.nodelay().map_err(|e| e.into())
+    }
+
+    /// Enable TCP keepalive probes after the connection is idle for the given duration, or
+    /// disable them if `None`. This lets us detect a dead peer on long-idle connections (eg
+    /// behind a NAT or Tor). Keepalive is disabled by default.
+    pub fn set_keepalive(&self, idle: Option<Duration>) -> Result<(), Error> {
+        let socket = socket2::SockRef::from(self.tcp_stream()?);
+        match idle {
+            Some(idle) => socket.set_tcp_keepalive(&socket2::TcpKeepalive::new().with_time(idle)),
+            None => socket.set_keepalive(false),
+        }
+        .map_err(|e| e.into())
+    }
+
+    /// Whether TCP keepalive probes are enabled
+    pub fn keepalive(&self) -> Result<bool, Error> {
+        socket2::SockRef::from(self.tcp_stream()?)
+            .keepalive()
+            .map_err(|e| e.into())
+    }
+
+    // The TCP options can't be set on a Unix domain socket
+    fn tcp_stream(&self) -> Result<&TcpStream, Error> {
+        match self.stream {
+            Stream::Tcp(ref stream) => Ok(stream),
+            #[cfg(unix)]
+            Stream::Unix(_) => {
+                Err(std::io::Error::new(ErrorKind::Unsupported, "Not a TCP connection").into())
+            }
+        }
+    }
+
+    /// Get the underlying TCP stream, eg to set a socket option we don't expose. Errors if
+    /// we are connected through a Unix domain socket.
+    ///
+    /// **Reading from or writing to it bypasses the encryption and desynchronizes the Noise
+    /// state, making the transport unusable.**
+    pub fn as_raw_stream(&self) -> Result<&TcpStream, Error> {
+        self.tcp_stream()
+    }
+
+    /// Get a mutable reference to the underlying TCP stream. Errors if we are connected
+    /// through a Unix domain socket.
+    ///
+    /// **Reading from or writing to it bypasses the encryption and desynchronizes the Noise
+    /// state, making the transport unusable.**
+    pub fn raw_stream_mut(&mut self) -> Result<&mut TcpStream, Error> {
+        match self.stream {
+            Stream::Tcp(ref mut stream) => Ok(stream),
+            #[cfg(unix)]
+            Stream::Unix(_) => {
+                Err(std::io::Error::new(ErrorKind::Unsupported, "Not a TCP connection").into())
+            }
+        }
+    }
+
+    /// Get the address of the peer, or `None` if we are connected through a Unix domain
+    /// socket.
+    pub fn peer_addr(&self) -> Result<Option<SocketAddr>, Error> {
+        match self.stream {
+            Stream::Tcp(ref stream) => Ok(Some(stream.peer_addr()?)),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(None),
+        }
+    }
+
+    /// Get our address for this connection, or `None` if we are connected through a Unix
+    /// domain socket.
+    pub fn local_addr(&self) -> Result<Option<SocketAddr>, Error> {
+        match self.stream {
+            Stream::Tcp(ref stream) => Ok(Some(stream.local_addr()?)),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(None),
+        }
+    }
+
+    /// Get the static public key of the peer
+    pub fn remote_static(&self) -> PublicKey {
+        self.channel.remote_static()
+    }
+
+    /// Get our static public key this connection was established with
+    pub fn local_static(&self) -> PublicKey {
+        self.channel.local_static()
+    }
+
+    /// Get a short identifier of the peer for logging, see [crate::noise::peer_id]
+    pub fn peer_id(&self) -> String {
+        self.channel.peer_id()
+    }
+
+    /// Get the protocol version negotiated during the Noise handshake
+    pub fn negotiated_version(&self) -> u8 {
+        self.channel.negotiated_version()
+    }
+
+    /// Whether we initiated the connection (and the handshake), as opposed to accepting it
+    pub fn is_initiator(&self) -> bool {
+        self.channel.is_initiator()
+    }
+
+    /// Get the hash of the Noise handshake, unique to this session and agreed upon by both
+    /// ends. See [KKChannel::handshake_hash].
+    pub fn handshake_hash(&self) -> [u8; HASH_SIZE] {
+        self.channel.handshake_hash()
+    }
+
+    /// Get a token unique to this session and agreed upon by both ends, eg to bind the
+    /// application messages to it so that they can't be replayed in another session. It's
+    /// derived from the handshake hash and from random bytes both ends exchange right after
+    /// the handshake, so neither end picks it alone. With a peer which only supports protocol
+    /// version 0 nothing is exchanged, and it's derived from the handshake hash alone.
+    pub fn session_token(&self) -> [u8; SESSION_TOKEN_SIZE] {
+        self.session_token
+    }
+
+    /// Get the nonce the channel will use to encrypt the next message header. Each message
+    /// (including the internal control ones) uses two nonces.
+    pub fn sending_nonce(&self) -> u64 {
+        self.channel.sending_nonce()
+    }
+
+    /// Get the nonce the channel will use to decrypt the next message header. Each message
+    /// (including the internal control ones) uses two nonces.
+    pub fn receiving_nonce(&self) -> u64 {
+        self.channel.receiving_nonce()
+    }
+}
+
+/// An iterator over the messages read from a [KKTransport], see [KKTransport::messages].
+#[derive(Debug)]
+pub struct Messages<'a> {
+    transport: &'a mut KKTransport,
+    done: bool,
+}
+
+impl Iterator for Messages<'_> {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.transport.read() {
+            Err(Error::ConnectionClosed) | Err(Error::CleanEof) => {
+                self.done = true;
+                None
+            }
+            Err(Error::TruncatedMessage) => {
+                self.done = true;
+                Some(Err(Error::TruncatedMessage))
+            }
+            res => Some(res),
+        }
+    }
+}
+
+/// A builder for a [KKTransport], to set all its options before connecting or accepting.
+/// The defaults are the same as [KKTransport::connect] and [KKTransport::accept].
+#[derive(Debug, Clone)]
+pub struct KKTransportBuilder {
+    connect_timeout: Duration,
+    handshake_timeout: Duration,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    max_message_size: usize,
+    config: TransportConfig,
+    prologue: Vec<u8>,
+}
+
+impl Default for KKTransportBuilder {
+    fn default() -> Self {
+        KKTransportBuilder {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            read_timeout: None,
+            write_timeout: None,
+            nodelay: true,
+            keepalive: None,
+            max_message_size: NOISE_PLAINTEXT_MAX_SIZE,
+            config: TransportConfig::default(),
+            prologue: Vec::new(),
+        }
+    }
+}
+
+impl KKTransportBuilder {
+    /// A builder with the default options
+    pub fn new() -> KKTransportBuilder {
+        KKTransportBuilder::default()
+    }
+
+    /// How long to try connecting before giving up. Defaults to [DEFAULT_CONNECT_TIMEOUT].
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// How long the whole handshake may take. Defaults to [DEFAULT_HANDSHAKE_TIMEOUT].
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// The read timeout of the stream once connected. None (block forever) by default.
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// The write timeout of the stream once connected. None (block forever) by default.
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Whether to disable Nagle's algorithm, see [KKTransport::set_nodelay]. True by default.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// See [KKTransport::set_keepalive]. Disabled by default.
+    pub fn keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.keepalive = idle;
+        self
+    }
+
+    /// See [KKTransport::set_max_message_size]
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// See [KKTransport::set_retry_policy]
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.config.retry = policy;
+        self
+    }
+
+    /// See [KKTransport::set_config]. This overrides a previously set retry policy.
+    pub fn config(mut self, config: TransportConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The prologue to bind into the handshake. Empty by default.
+    pub fn prologue(mut self, prologue: &[u8]) -> Self {
+        self.prologue = prologue.to_vec();
+        self
+    }
+
+    /// Connect to server at given address, and enact Noise handshake with given private key
+    pub fn connect(
+        &self,
+        addr: SocketAddr,
+        my_noise_privkey: &SecretKey,
+        their_noise_pubkey: &PublicKey,
+    ) -> Result<KKTransport, Error> {
+        let dial = || Ok(TcpStream::connect_timeout(&addr, self.connect_timeout)?);
+        let transport = KKTransport::initiator_handshake(
+            dial()?,
+            Some(&dial),
+            my_noise_privkey,
+            their_noise_pubkey,
+            &self.prologue,
+            self.handshake_timeout,
+        )?;
+        self.apply(transport)
+    }
+
+    /// Accept an incoming connection and perform the noise KK handshake as a responder, see
+    /// [KKTransport::accept]
+    pub fn accept(
+        &self,
+        listener: &TcpListener,
+        my_noise_privkey: &SecretKey,
+        their_possible_pubkeys: &[PublicKey],
+    ) -> Result<KKTransport, Error> {
+        let (stream, _) = listener.accept()?;
+        let transport = KKTransport::responder_handshake(
+            stream,
+            my_noise_privkey,
+            their_possible_pubkeys,
+            &self.prologue,
+            self.handshake_timeout,
+            |_| true,
+        )?;
+        self.apply(transport)
+    }
+
+    // Set the options on a freshly established transport
+    fn apply(&self, mut transport: KKTransport) -> Result<KKTransport, Error> {
+        let stream = transport.tcp_stream()?;
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        if !self.nodelay {
+            transport.set_nodelay(false)?;
+        }
+        if self.keepalive.is_some() {
+            transport.set_keepalive(self.keepalive)?;
+        }
+        transport.set_config(self.config);
+        transport.set_max_message_size(self.max_message_size);
+        Ok(transport)
+    }
+}
+
+impl KKResponder {
+    /// Perform the noise KK handshake as a responder over an already accepted stream. This is
+    /// the same as [KKTransport::from_stream_responder] without setting up the responder each
+    /// time, for servers accepting many connections from a fixed set of peers.
+    pub fn accept(&self, stream: TcpStream) -> Result<KKTransport, Error> {
+        KKTransport::responder_handshake_tracked(
+            stream,
+            |msg_act_1| self.respond(msg_act_1),
+            DEFAULT_HANDSHAKE_TIMEOUT,
+            None,
+            |_| true,
+            &mut HandshakeProgress::new(),
+        )
+    }
+}
+
+/// An authenticated and encrypted channel to exchange messages with a peer. This allows to
+/// be generic over the transport, eg to use a [MockTransport] in tests.
+pub trait Transport {
+    /// Read the next message sent by the peer
+    fn read(&mut self) -> Result<Vec<u8>, Error>;
+
+    /// Send a message to the peer
+    fn write(&mut self, msg: &[u8]) -> Result<(), Error>;
+}
+
+impl Transport for KKTransport {
+    fn read(&mut self) -> Result<Vec<u8>, Error> {
+        KKTransport::read(self)
+    }
+
+    fn write(&mut self, msg: &[u8]) -> Result<(), Error> {
+        KKTransport::write(self, msg)
+    }
+}
+
+/// The file descriptor of the underlying socket, eg to register it in an event loop.
+/// **Reading from or writing to it bypasses the encryption and desynchronizes the Noise
+/// state, making the transport unusable.**
+#[cfg(unix)]
+impl AsRawFd for KKTransport {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+/// A client [KKTransport] which transparently reconnects to the server, and performs the
+/// handshake again, when the connection is lost.
+/// Messages that were in flight when the connection was lost are not resent.
+#[derive(Debug)]
+pub struct ReconnectingKKTransport {
+    addr: SocketAddr,
+    my_noise_privkey: SecretKey,
+    their_noise_pubkey: PublicKey,
+    // How to retry connecting
+    policy: RetryPolicy,
+    // None if the connection was lost and we could not reconnect yet
+    transport: Option<KKTransport>,
+    // Messages received on a previous connection, to be read before the current one's
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl ReconnectingKKTransport {
+    /// Connect to the server at given address as per [KKTransport::connect]. When the
+    /// connection is lost, connecting again is attempted as per the `policy`.
+    pub fn connect(
+        addr: SocketAddr,
+        my_noise_privkey: &SecretKey,
+        their_noise_pubkey: &PublicKey,
+        policy: RetryPolicy,
+    ) -> Result<ReconnectingKKTransport, Error> {
+        let mut transport = ReconnectingKKTransport {
+            addr,
+            my_noise_privkey: my_noise_privkey.clone(),
+            their_noise_pubkey: *their_noise_pubkey,
+            policy,
+            transport: None,
+            pending: VecDeque::new(),
+        };
+        transport.transport()?;
+        Ok(transport)
+    }
+
+    // Get the current connection, or establish a new one
+    fn transport(&mut self) -> Result<&mut KKTransport, Error> {
+        if self.transport.is_none() {
+            self.transport = Some(self.reconnect()?);
+        }
+        Ok(self.transport.as_mut().expect("Just set"))
+    }
+
+    fn reconnect(&self) -> Result<KKTransport, Error> {
+        let mut attempts = 0;
+        loop {
+            match KKTransport::connect(self.addr, &self.my_noise_privkey, &self.their_noise_pubkey)
+            {
+                Ok(transport) => {
+                    // The KK handshake already guarantees it, but never silently trust
+                    // another key.
+                    if transport.remote_static() != self.their_noise_pubkey {
+                        return Err(Error::Handshake(NoiseError::MissingStaticKey));
+                    }
+                    return Ok(transport);
+                }
+                // Only retry if the server may just be unreachable for now
+                Err(e) if Self::is_fatal(&e) => {
+                    attempts += 1;
+                    if attempts >= self.policy.max_attempts {
+                        return Err(e);
+                    }
+                    thread::sleep(self.policy.delay(attempts));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Whether this error means the connection is lost
+    fn is_fatal(error: &Error) -> bool {
+        error.is_io() || error.is_closed() || error.is_eof()
+    }
+
+    /// Switch to a new static key, eg after it was rotated by the operator. A connection is
+    /// established with `new_privkey` before closing the current one, so the server must
+    /// already know the new key. If it rejects it, or the connection fails, we keep the
+    /// current key and connection.
+    /// The current connection is closed gracefully: the server gets the messages we sent on it
+    /// before the closing notice, and the ones it sent until it closed its side in turn (or
+    /// for up to [DEFAULT_HANDSHAKE_TIMEOUT]) are kept for the next calls to
+    /// [ReconnectingKKTransport::read].
+    pub fn rotate_key(&mut self, new_privkey: &SecretKey) -> Result<(), ConnectError> {
+        let new_transport = KKTransport::try_connect(
+            self.addr,
+            new_privkey,
+            &self.their_noise_pubkey,
+            DEFAULT_CONNECT_TIMEOUT,
+        )?;
+        self.my_noise_privkey = new_privkey.clone();
+
+        if let Some(mut old_transport) = self.transport.replace(new_transport) {
+            // If this fails the connection was lost already, along with what was in flight
+            if old_transport.close_write().is_ok() {
+                let deadline = Instant::now() + DEFAULT_HANDSHAKE_TIMEOUT;
+                while let Ok(msg) = old_transport.read_until(deadline) {
+                    self.pending.push_back(msg);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read a message from the server, see [KKTransport::read]. If the connection was lost,
+    /// read from a new one instead.
+    pub fn read(&mut self) -> Result<Vec<u8>, Error> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(msg);
+        }
+
+        match self.transport()?.read() {
+            Err(e) if Self::is_fatal(&e) => {
+                self.transport = None;
+                self.transport()?.read()
+            }
+            res => res,
+        }
+    }
+
+    /// Write a message to the server, see [KKTransport::write]. If the connection was lost,
+    /// write it to a new one instead.
+    pub fn write(&mut self, msg: &[u8]) -> Result<(), Error> {
+        match self.transport()?.write(msg) {
+            Err(e) if Self::is_fatal(&e) => {
+                self.transport = None;
+                self.transport()?.write(msg)
+            }
+            res => res,
+        }
+    }
+
+    /// Whether we are currently connected to the server
+    pub fn is_connected(&self) -> bool {
+        self.transport.is_some()
+    }
+}
+
+impl Transport for ReconnectingKKTransport {
+    fn read(&mut self) -> Result<Vec<u8>, Error> {
+        ReconnectingKKTransport::read(self)
+    }
+
+    fn write(&mut self, msg: &[u8]) -> Result<(), Error> {
+        ReconnectingKKTransport::write(self, msg)
+    }
+}
+
+/// An in-memory [Transport], for testing code using a transport without any socket.
+#[derive(Debug)]
+pub struct MockTransport {
+    sender: mpsc::Sender<Vec<u8>>,
+    receiver: mpsc::Receiver<Vec<u8>>,
+}
+
+impl MockTransport {
+    /// Create two connected ends: what is written to one is read from the other.
+    pub fn pair() -> (MockTransport, MockTransport) {
+        let (sender_a, receiver_a) = mpsc::channel();
+        let (sender_b, receiver_b) = mpsc::channel();
+        (
+            MockTransport {
+                sender: sender_a,
+                receiver: receiver_b,
+            },
+            MockTransport {
+                sender: sender_b,
+                receiver: receiver_a,
+            },
+        )
+    }
+}
+
+impl Transport for MockTransport {
+    /// Blocks until the other end writes a message. Errors with [Error::ConnectionClosed]
+    /// once the other end was dropped and all its messages were read.
+    fn read(&mut self) -> Result<Vec<u8>, Error> {
+        self.receiver.recv().map_err(|_| Error::ConnectionClosed)
+    }
+
+    /// Errors with [Error::ConnectionClosed] if the other end was dropped.
+    fn write(&mut self, msg: &[u8]) -> Result<(), Error> {
+        self.sender
+            .send(msg.to_vec())
+            .map_err(|_| Error::ConnectionClosed)
+    }
+}
+
+/// A server accepting connections from a set of authorized peers, and performing the Noise
+/// KK handshake with each of them.
+#[derive(Debug)]
+pub struct KKServer {
+    listener: TcpListener,
+    noise_privkey: SecretKey,
+    // Shared so it can be updated while serving
+    authorized_pubkeys: Arc<RwLock<Vec<PublicKey>>>,
+    // Number of connections we dropped because the handshake failed
+    failed_handshakes: AtomicUsize,
+    // Bound into every handshake, empty by default
+    prologue: Vec<u8>,
+    // The time a peer has to complete the handshake
+    handshake_timeout: Duration,
+    // The number of handshakes performed at the same time by serve()
+    max_pending_handshakes: usize,
+}
+
+impl KKServer {
+    /// Create a server accepting connections from the given peers on this listener
+    pub fn new(
+        listener: TcpListener,
+        noise_privkey: SecretKey,
+        authorized_pubkeys: Vec<PublicKey>,
+    ) -> KKServer {
+        KKServer {
+            listener,
+            noise_privkey,
+            authorized_pubkeys: Arc::new(RwLock::new(authorized_pubkeys)),
+            failed_handshakes: AtomicUsize::new(0),
+            prologue: Vec::new(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_pending_handshakes: DEFAULT_MAX_PENDING_HANDSHAKES,
+        }
+    }
+
+    /// Set the prologue that the peers must use to connect to this server
+    pub fn set_prologue(&mut self, prologue: &[u8]) {
+        self.prologue = prologue.to_vec();
+    }
+
+    /// Set the time a peer has to complete the whole handshake once connected, before we drop
+    /// the connection. Defaults to [DEFAULT_HANDSHAKE_TIMEOUT].
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Set how many handshakes [KKServer::serve] performs at the same time. Once reached, it
+    /// stops accepting connections until one of them completes. Defaults to
+    /// [DEFAULT_MAX_PENDING_HANDSHAKES].
+    pub fn set_max_pending_handshakes(&mut self, max: usize) {
+        assert!(max > 0, "Must allow at least one handshake");
+        self.max_pending_handshakes = max;
+    }
+
+    /// Create a server accepting connections from the given peers on this address. See
+    /// [bind_listener] for how the listener is created.
+    pub fn bind(
+        addr: SocketAddr,
+        noise_privkey: SecretKey,
+        authorized_pubkeys: Vec<PublicKey>,
+    ) -> Result<KKServer, Error> {
+        let listener = bind_listener(addr, DEFAULT_BACKLOG)?;
+        Ok(KKServer::new(listener, noise_privkey, authorized_pubkeys))
+    }
+
+    /// The address the server is listening on
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.listener.local_addr().map_err(|e| e.into())
+    }
+
+    /// Accept a single connection and perform the Noise KK handshake. An error is returned
+    /// if either accepting the connection or the handshake failed.
+    pub fn accept(&self) -> Result<KKTransport, Error> {
+        let (stream, _) = self.listener.accept()?;
+        self.handshake(stream)
+    }
+
+    fn handshake(&self, stream: TcpStream) -> Result<KKTransport, Error> {
+        let peer_addr = stream.peer_addr();
+        // Don't hold the lock while waiting for the peer
+        let authorized_pubkeys = self.peers();
+        KKTransport::responder_handshake(
+            stream,
+            &self.noise_privkey,
+            &authorized_pubkeys,
+            &self.prologue,
+            self.handshake_timeout,
+            |_| true,
+        )
+        .inspect_err(|e| {
+            self.failed_handshakes.fetch_add(1, Ordering::Relaxed);
+            match peer_addr {
+                Ok(addr) => debug!("Dropping connection from {}: {}", addr, e),
+                Err(_) => debug!("Dropping connection: {}", e),
+            }
+        })
+    }
+
+    /// Accept connections in a loop, and call `handler` with the transport for each of them
+    /// once the handshake succeeded. Each handshake is performed in its own thread, up to
+    /// [KKServer::set_max_pending_handshakes] at the same time, so a slow peer doesn't hold
+    /// the others back. `handler` is called from that thread, and may therefore be called
+    /// for several connections at once.
+    /// Connections for which the handshake failed (eg from an unknown peer) are dropped and
+    /// counted, see [KKServer::failed_handshakes]. Failing to accept a connection (eg a peer
+    /// resetting it, or us running out of file descriptors) is retried. Only returns if the
+    /// listener itself is unusable.
+    pub fn serve<F>(&self, handler: F) -> Result<(), Error>
+    where
+        F: Fn(KKTransport) + Send + Sync,
+    {
+        // The number of handshakes in progress
+        let pending = (Mutex::new(0), Condvar::new());
+        let (pending, handler) = (&pending, &handler);
+
+        thread::scope(|scope| loop {
+            {
+                let mut count = pending.0.lock().expect("Lock poisoned");
+                while *count >= self.max_pending_handshakes {
+                    count = pending.1.wait(count).expect("Lock poisoned");
+                }
+            }
+
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::InvalidInput => return Err(e.into()),
+                Err(e) => {
+                    debug!("Failed to accept a connection: {}", e);
+                    if !matches!(
+                        e.kind(),
+                        ErrorKind::Interrupted | ErrorKind::ConnectionAborted
+                    ) {
+                        thread::sleep(ACCEPT_RETRY_DELAY);
+                    }
+                    continue;
+                }
+            };
+
+            *pending.0.lock().expect("Lock poisoned") += 1;
+            scope.spawn(move || {
+                let res = self.handshake(stream);
+                *pending.0.lock().expect("Lock poisoned") -= 1;
+                pending.1.notify_one();
+                if let Ok(transport) = res {
+                    handler(transport);
+                }
+            });
+        })
+    }
+
+    /// The set of peers currently allowed to connect
+    pub fn peers(&self) -> Vec<PublicKey> {
+        self.authorized_pubkeys
+            .read()
+            .expect("Lock poisoned")
+            .clone()
+    }
+
+    /// Allow this peer to connect, for all subsequent connections
+    pub fn add_peer(&self, pubkey: PublicKey) {
+        let mut pubkeys = self.authorized_pubkeys.write().expect("Lock poisoned");
+        if !pubkeys.contains(&pubkey) {
+            pubkeys.push(pubkey);
+        }
+    }
+
+    /// Don't allow this peer to connect anymore. Connections already established with it
+    /// are left untouched.
+    /// Returns false if the peer was not authorized in the first place.
+    pub fn remove_peer(&self, pubkey: &PublicKey) -> bool {
+        let mut pubkeys = self.authorized_pubkeys.write().expect("Lock poisoned");
+        let len = pubkeys.len();
+        pubkeys.retain(|k| k != pubkey);
+        pubkeys.len() != len
+    }
+
+    /// Atomically replace the set of peers allowed to connect. Connections already
+    /// established are left untouched.
+    pub fn replace_peers(&self, pubkeys: Vec<PublicKey>) {
+        *self.authorized_pubkeys.write().expect("Lock poisoned") = pubkeys;
+    }
+
+    /// The number of connections dropped so far because the handshake failed
+    pub fn failed_handshakes(&self) -> usize {
+        self.failed_handshakes.load(Ordering::Relaxed)
+    }
+}
+
+/// Enact the Noise KK handshake as an initiator over any stream (eg a Unix domain socket),
+/// and get the resulting channel. [KKTransport] does this over a TCP stream.
+/// All the [PROTOCOL_VERSIONS] are offered, with no fallback for a responder predating the
+/// version negotiation, see [handshake_initiator_offering].
+pub fn handshake_initiator<S: Read + Write>(
+    stream: &mut S,
+    my_noise_privkey: &SecretKey,
+    their_noise_pubkey: &PublicKey,
+) -> Result<KKChannel, Error> {
+    handshake_initiator_with_prologue(stream, my_noise_privkey, their_noise_pubkey, &[])
+}
+
+/// Same as [handshake_initiator], binding the `prologue` into the handshake. The responder
+/// must use the same, see [handshake_responder_with_prologue].
+pub fn handshake_initiator_with_prologue<S: Read + Write>(
+    stream: &mut S,
+    my_noise_privkey: &SecretKey,
+    their_noise_pubkey: &PublicKey,
+    prologue: &[u8],
+) -> Result<KKChannel, Error> {
+    handshake_initiator_offering(
+        stream,
+        my_noise_privkey,
+        their_noise_pubkey,
+        PROTOCOL_VERSIONS,
+        prologue,
+    )
+}
+
+/// Same as [handshake_initiator_with_prologue], offering these protocol `versions` rather
+/// than all of the [PROTOCOL_VERSIONS]. Offer the [LEGACY_VERSIONS] to a responder predating
+/// the version negotiation: it closes the connection upon any other offer.
+pub fn handshake_initiator_offering<S: Read + Write>(
+    stream: &mut S,
+    my_noise_privkey: &SecretKey,
+    their_noise_pubkey: &PublicKey,
+    versions: &[u8],
+    prologue: &[u8],
+) -> Result<KKChannel, Error> {
+    let (cli_act_1, msg_1) = KKHandshakeActOne::initiator_with_prologue(
+        my_noise_privkey,
+        their_noise_pubkey,
+        versions,
+        prologue,
+        CipherSuite::default(),
+    )
+    .map_err(Error::Handshake)?;
+
+    // write msg_1 to stream (e, es, ss)
+    stream.write_all(&msg_1.0)?;
+    stream.flush()?;
+
+    // read msg_2 from stream (e, ee, se)
+    let mut msg_2 = vec![0u8; cli_act_1.act_two_size()];
+    read_act_two(stream, &mut msg_2)?;
+
+    let msg_act_2 = KKMessageActTwo(msg_2);
+    let cli_act_2 =
+        KKHandshakeActTwo::initiator(cli_act_1, &msg_act_2).map_err(Error::Handshake)?;
+    KKChannel::from_handshake(cli_act_2).map_err(Error::Handshake)
+}
+
+// The responder closed the connection upon our first handshake message without sending
+// anything, not even the HANDSHAKE_REFUSAL
+#[derive(Debug)]
+struct Unanswered;
+
+impl std::fmt::Display for Unanswered {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Connection closed without an answer to the handshake")
+    }
+}
+
+impl std::error::Error for Unanswered {}
+
+// Read the second handshake message, telling apart a responder which closed the connection
+// without sending anything
+fn read_act_two<S: Read>(stream: &mut S, msg_2: &mut [u8]) -> std::io::Result<()> {
+    let mut read = 0;
+    while read < msg_2.len() {
+        match stream.read(&mut msg_2[read..]) {
+            Ok(0) if read == 0 => {
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, Unanswered))
+            }
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Whether the responder closed the connection upon our first handshake message instead of
+// answering it, as one predating the version negotiation does for an offer it doesn't know
+fn is_unanswered(error: &Error) -> bool {
+    matches!(error, Error::Transport(e) if e.get_ref().is_some_and(|e| e.is::<Unanswered>()))
+}
+
+// Same as handshake_initiator once the first message is created, but tells which step failed
+fn handshake_initiator_classified<S: Read + Write>(
+    stream: &mut S,
+    cli_act_1: KKHandshakeActOne,
+    msg_1: KKMessageActOne,
+) -> Result<KKChannel, ConnectError> {
+    stream
+        .write_all(&msg_1.0)
+        .and_then(|()| stream.flush())
+        .map_err(ConnectError::HandshakeWrite)?;
+
+    let mut msg_2 = vec![0u8; cli_act_1.act_two_size()];
+    read_act_two(stream, &mut msg_2).map_err(|e| match e.kind() {
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => ConnectError::HandshakeReadTimeout,
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => {
+            ConnectError::HandshakeRejected(e.into())
+        }
+        _ => ConnectError::Tcp(e),
+    })?;
+
+    KKHandshakeActTwo::initiator(cli_act_1, &KKMessageActTwo(msg_2))
+        .and_then(KKChannel::from_handshake)
+        .map_err(|e| ConnectError::HandshakeRejected(Error::Handshake(e)))
+}
+
+/// Perform the Noise KK handshake as a responder over any stream (eg a Unix domain socket),
+/// with our single private key and a set of possible public key for them, and get the
+/// resulting channel. [KKTransport] does this over a TCP stream.
+pub fn handshake_responder<S: Read + Write>(
+    stream: &mut S,
+    my_noise_privkey: &SecretKey,
+    their_possible_pubkeys: &[PublicKey],
+) -> Result<KKChannel, Error> {
+    handshake_responder_with_prologue(stream, my_noise_privkey, their_possible_pubkeys, &[])
+}
+
+/// Same as [handshake_responder], for an initiator which bound this `prologue` into the
+/// handshake, see [handshake_initiator_with_prologue].
+pub fn handshake_responder_with_prologue<S: Read + Write>(
+    stream: &mut S,
+    my_noise_privkey: &SecretKey,
+    their_possible_pubkeys: &[PublicKey],
+    prologue: &[u8],
+) -> Result<KKChannel, Error> {
+    handshake_responder_with_auth(
+        stream,
+        my_noise_privkey,
+        their_possible_pubkeys,
+        prologue,
+        |_| true,
+    )
+}
+
+/// The step of the handshake a responder was at, see [HandshakeFailure]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeStage {
+    /// Accepting the connection
+    Accept,
+    /// Reading the first message
+    ActOneRead,
+    /// Processing the first message with each of the possible keys of the peer
+    KeyMatch,
+    /// Answering with the second message
+    ActTwoWrite,
+}
+
+/// Why and where an incoming handshake failed, see [KKTransport::try_accept]
+#[derive(Debug)]
+pub struct HandshakeFailure {
+    /// The address of the peer, if the connection was accepted
+    pub peer_addr: Option<SocketAddr>,
+    /// The step which failed
+    pub stage: HandshakeStage,
+    /// How many bytes the peer sent
+    pub bytes_read: usize,
+    /// The error of this step
+    pub error: Error,
+}
+
+impl std::fmt::Display for HandshakeFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Handshake failed at stage {:?} after reading {} bytes",
+            self.stage, self.bytes_read
+        )?;
+        if let Some(peer_addr) = self.peer_addr {
+            write!(f, " from {}", peer_addr)?;
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
+impl std::error::Error for HandshakeFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<HandshakeFailure> for Error {
+    fn from(failure: HandshakeFailure) -> Error {
+        failure.error
+    }
+}
+
+/// Why an outgoing connection failed, see [KKTransport::try_connect]
+#[derive(Debug)]
+pub enum ConnectError {
+    /// Their key is not a valid public key, we didn't try to connect
+    InvalidKey(NoiseError),
+    /// The TCP connection wasn't established in time, the peer is unreachable
+    TcpTimeout,
+    /// The TCP connection failed otherwise, eg it was refused
+    Tcp(std::io::Error),
+    /// The connection was established but sending our handshake message failed
+    HandshakeWrite(std::io::Error),
+    /// We sent our handshake message but the answer didn't come in time
+    HandshakeReadTimeout,
+    /// The peer is reachable but didn't complete the handshake. It either closed the
+    /// connection (as we do for an unknown key) or answered with an invalid message. This
+    /// usually means that one of us doesn't have the right key.
+    HandshakeRejected(Error),
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectError::InvalidKey(e) => write!(f, "Invalid peer key: {}", e),
+            ConnectError::TcpTimeout => write!(f, "TCP connection timed out"),
+            ConnectError::Tcp(e) => write!(f, "TCP connection failed: {}", e),
+            ConnectError::HandshakeWrite(e) => write!(f, "Sending the handshake failed: {}", e),
+            ConnectError::HandshakeReadTimeout => write!(f, "No answer to the handshake in time"),
+            ConnectError::HandshakeRejected(e) => write!(f, "Handshake rejected: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectError::InvalidKey(e) => Some(e),
+            ConnectError::Tcp(e) | ConnectError::HandshakeWrite(e) => Some(e),
+            ConnectError::HandshakeRejected(e) => Some(e),
+            ConnectError::TcpTimeout | ConnectError::HandshakeReadTimeout => None,
+        }
+    }
+}
+
+impl From<ConnectError> for Error {
+    fn from(error: ConnectError) -> Error {
+        match error {
+            ConnectError::InvalidKey(e) => Error::Handshake(e),
+            ConnectError::TcpTimeout | ConnectError::HandshakeReadTimeout => {
+                std::io::Error::from(ErrorKind::TimedOut).into()
+            }
+            ConnectError::Tcp(e) | ConnectError::HandshakeWrite(e) => e.into(),
+            ConnectError::HandshakeRejected(e) => e,
+        }
+    }
+}
+
+// How far a responder went in the handshake
+struct HandshakeProgress {
+    stage: HandshakeStage,
+    bytes_read: usize,
+}
+
+impl HandshakeProgress {
+    fn new() -> HandshakeProgress {
+        HandshakeProgress {
+            stage: HandshakeStage::ActOneRead,
+            bytes_read: 0,
+        }
+    }
+}
+
+// Same as handshake_responder, but gives up with Error::Rejected before completing the
+// handshake if `auth` refuses the peer.
+fn handshake_responder_with_auth<S: Read + Write, F: FnOnce(&PublicKey) -> bool>(
+    stream: &mut S,
+    my_noise_privkey: &SecretKey,
+    their_possible_pubkeys: &[PublicKey],
+    prologue: &[u8],
+    auth: F,
+) -> Result<KKChannel, Error> {
+    handshake_responder_tracked(
+        stream,
+        |msg_act_1| {
+            KKHandshakeActOne::responder_with_prologue(
+                my_noise_privkey,
+                their_possible_pubkeys,
+                msg_act_1,
+                PROTOCOL_VERSIONS,
+                prologue,
+                CipherSuite::default(),
+            )
+        },
+        auth,
+        &mut HandshakeProgress::new(),
+    )
+}
+
+fn handshake_responder_tracked<S, R, F>(
+    stream: &mut S,
+    respond: R,
+    auth: F,
+    progress: &mut HandshakeProgress,
+) -> Result<KKChannel, Error>
+where
+    S: Read + Write,
+    R: FnOnce(&KKMessageActOne) -> Result<KKHandshakeActOne, NoiseError>,
+    F: FnOnce(&PublicKey) -> bool,
+{
+    // read msg_1 from stream, keeping track of how much we got
+    progress.stage = HandshakeStage::ActOneRead;
+    let mut msg_1 = [0u8; KK_MSG_1_SIZE];
+    while progress.bytes_read < KK_MSG_1_SIZE {
+        match stream.read(&mut msg_1[progress.bytes_read..]) {
+            Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(n) => progress.bytes_read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let msg_act_1 = KKMessageActOne(msg_1);
+
+    progress.stage = HandshakeStage::KeyMatch;
+
+    // The refusal is best effort, we are closing the connection anyway
+    let serv_act_1 = respond(&msg_act_1).map_err(|e| {
+        let _ = stream.write_all(HANDSHAKE_REFUSAL);
+        Error::Handshake(e)
+    })?;
+    let their_pubkey = serv_act_1
+        .peer_static()
+        .expect("The responder always knows their key");
+    if !auth(&their_pubkey) {
+        let _ = stream.write_all(HANDSHAKE_REFUSAL);
+        return Err(Error::Rejected);
+    }
+    progress.stage = HandshakeStage::ActTwoWrite;
+    let (serv_act_2, msg_2) = KKHandshakeActTwo::responder(serv_act_1).map_err(Error::Handshake)?;
+    let channel = KKChannel::from_handshake(serv_act_2).map_err(Error::Handshake)?;
+
+    // write msg_2 to stream
+    stream.write_all(&msg_2.0)?;
+    stream.flush()?;
+
+    Ok(channel)
+}
+
+// A stream whose reads and writes can be given a timeout, so that we can enforce a deadline
+// on the handshake
+trait TimeoutStream {
+    fn read_timeout(&self) -> std::io::Result<Option<Duration>>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+    fn write_timeout(&self) -> std::io::Result<Option<Duration>>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl TimeoutStream for TcpStream {
+    fn read_timeout(&self) -> std::io::Result<Option<Duration>> {
+        TcpStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn write_timeout(&self) -> std::io::Result<Option<Duration>> {
+        TcpStream::write_timeout(self)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl TimeoutStream for UnixStream {
+    fn read_timeout(&self) -> std::io::Result<Option<Duration>> {
+        UnixStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn write_timeout(&self) -> std::io::Result<Option<Duration>> {
+        UnixStream::write_timeout(self)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+// A TCP or Unix stream which errors with a TimedOut once the deadline is past, however the
+// reads and writes are split. If a `cancel` flag is given, it is polled and the reads and
+// writes error once it's set.
+struct DeadlineStream<'a, S> {
+    stream: &'a S,
+    deadline: Instant,
+    cancel: Option<&'a AtomicBool>,
+}
+
+impl<S> DeadlineStream<'_, S> {
+    fn remaining(&self) -> std::io::Result<Duration> {
+        if self.is_cancelled() {
+            return Err(std::io::Error::other("Handshake cancelled"));
+        }
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "Handshake not completed in time",
+            ));
+        }
+        Ok(match self.cancel {
+            Some(_) => remaining.min(CANCEL_POLL_INTERVAL),
+            None => remaining,
+        })
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.is_some_and(|c| c.load(Ordering::Relaxed))
+    }
+
+    // Whether to poll again after this error, as we only timed out to check the cancel flag
+    fn poll_again(&self, e: &std::io::Error) -> bool {
+        self.cancel.is_some() && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+    }
+}
+
+// A read timeout is reported as WouldBlock on some platforms
+fn timed_out(e: std::io::Error) -> std::io::Error {
+    if e.kind() == ErrorKind::WouldBlock {
+        std::io::Error::new(ErrorKind::TimedOut, "Handshake not completed in time")
+    } else {
+        e
+    }
+}
+
+impl<S: TimeoutStream> Read for DeadlineStream<'_, S>
+where
+    for<'s> &'s S: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            self.stream.set_read_timeout(Some(self.remaining()?))?;
+            match { self.stream }.read(buf) {
+                Err(e) if self.poll_again(&e) => continue,
+                res => return res.map_err(timed_out),
+            }
+        }
+    }
+}
+
+impl<S: TimeoutStream> Write for DeadlineStream<'_, S>
+where
+    for<'s> &'s S: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        loop {
+            self.stream.set_write_timeout(Some(self.remaining()?))?;
+            match { self.stream }.write(buf) {
+                Err(e) if self.poll_again(&e) => continue,
+                res => return res.map_err(timed_out),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        { self.stream }.flush()
+    }
+}
+
+// Run f over the stream, making its reads and writes fail once the deadline is past or the
+// `cancel` flag is set. The previous timeouts of the stream are restored afterwards.
+fn with_deadline<S, T, F>(
+    stream: &S,
+    deadline: Instant,
+    cancel: Option<&AtomicBool>,
+    f: F,
+) -> Result<T, Error>
+where
+    S: TimeoutStream,
+    F: FnOnce(&mut DeadlineStream<S>) -> Result<T, Error>,
+{
+    let (read_timeout, write_timeout) = (stream.read_timeout()?, stream.write_timeout()?);
+    let res = f(&mut DeadlineStream {
+        stream,
+        deadline,
+        cancel,
+    });
+    stream.set_read_timeout(read_timeout)?;
+    stream.set_write_timeout(write_timeout)?;
+    match res {
+        Err(_) if cancel.is_some_and(|c| c.load(Ordering::Relaxed)) => Err(Error::Cancelled),
+        res => res,
+    }
+}
+
+/// Create a listener on this address with `SO_REUSEADDR` set, and the given backlog of
+/// pending connections. This allows to restart a server right away, without waiting for the
+/// connections of its previous instance to get out of the `TIME_WAIT` state.
+/// Note that the semantics of `SO_REUSEADDR` differ across platforms: on Windows, it allows
+/// another process to bind to the very same address while we are listening on it.
+pub fn bind_listener(addr: SocketAddr, backlog: i32) -> Result<TcpListener, Error> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        None,
+    )?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    Ok(socket.into())
+}
+
+// Connect to the SOCKS5 proxy and ask it to open a tunnel to the target, giving up if the
+// proxy doesn't complete it within `timeout`.
+fn socks5_dial(
+    proxy: &SocketAddr,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> Result<TcpStream, Error> {
+    let stream = TcpStream::connect_timeout(proxy, DEFAULT_CONNECT_TIMEOUT)?;
+    with_deadline(&stream, Instant::now() + timeout, None, |stream| {
+        Ok(socks5_connect(stream, host, port)?)
+    })?;
+    Ok(stream)
+}
+
+// Ask the SOCKS5 proxy at the other end of this stream to connect to the given host, as per
+// RFC 1928. We only support connecting without authentication.
+fn socks5_connect<S: Read + Write>(stream: &mut S, host: &str, port: u16) -> std::io::Result<()> {
+    let socks_error = std::io::Error::other;
+
+    // Version 5, one method: no authentication
+    stream.write_all(&[0x05, 0x01, 0x00])?;
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method)?;
+    if method != [0x05, 0x00] {
+        return Err(socks_error(
+            "SOCKS5 proxy refused our authentication method",
+        ));
+    }
+
+    // Version 5, CONNECT, reserved, domain name
+    let host_len: u8 = host
+        .len()
+        .try_into()
+        .map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "SOCKS5 host too long"))?;
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host_len];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != 0x05 {
+        return Err(socks_error("Invalid SOCKS5 reply version"));
+    }
+    match reply[1] {
+        0x00 => {}
+        0x02 => {
+            return Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                "SOCKS5 connection not allowed by ruleset",
+            ))
+        }
+        0x05 => {
+            return Err(std::io::Error::new(
+                ErrorKind::ConnectionRefused,
+                "SOCKS5 connection refused",
+            ))
+        }
+        0x06 => {
+            return Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "SOCKS5 TTL expired",
+            ))
+        }
+        0x01 => return Err(socks_error("SOCKS5 general server failure")),
+        0x03 => return Err(socks_error("SOCKS5 network unreachable")),
+        0x04 => return Err(socks_error("SOCKS5 host unreachable")),
+        0x07 => return Err(socks_error("SOCKS5 command not supported")),
+        0x08 => return Err(socks_error("SOCKS5 address type not supported")),
+        _ => return Err(socks_error("Unknown SOCKS5 error")),
+    }
+
+    // We don't care about the bound address, but need to consume it (and the port).
+    let addr_len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => return Err(socks_error("Invalid SOCKS5 reply address type")),
+    };
+    let mut bound_addr = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound_addr)?;
+
+    Ok(())
+}
+
+// The largest response to a CONNECT request we accept, it's only a few headers
+const HTTP_RESPONSE_MAX_SIZE: usize = 8 * 1024;
+
+// Ask the HTTP proxy at the other end of this stream to open a tunnel to the target, as per
+// RFC 9110 section 9.3.6.
+fn http_connect<S: Read + Write>(
+    stream: &mut S,
+    target: &str,
+    proxy_auth: Option<(&str, &str)>,
+) -> std::io::Result<()> {
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    if let Some((user, password)) = proxy_auth {
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64_encode(format!("{}:{}", user, password).as_bytes())
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+
+    // Read byte by byte, so we don't consume what follows the headers
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= HTTP_RESPONSE_MAX_SIZE {
+            return Err(std::io::Error::other("HTTP proxy response too large"));
+        }
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte)?;
+        response.push(byte[0]);
+    }
+
+    // The status line is of the form 'HTTP/1.1 200 Connection established'
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    let mut parts = status_line.split(' ');
+    let status = match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => status
+            .parse::<u16>()
+            .map_err(|_| std::io::Error::other("Invalid HTTP proxy status code"))?,
+        _ => return Err(std::io::Error::other("Invalid HTTP proxy response")),
+    };
+    match status {
+        200..=299 => Ok(()),
+        407 => Err(std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            "HTTP proxy authentication required",
+        )),
+        _ => Err(std::io::Error::other(format!(
+            "HTTP proxy refused the connection: '{}'",
+            status_line
+        ))),
+    }
+}
+
+// Standard base64 with padding, for the proxy credentials
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::{
+        encrypted_msg_size, generate_keypair, NoiseEncryptedMessage, HANDSHAKE_MESSAGE, KEY_SIZE,
+        KK_MSG_2_SIZE, KK_VERSIONED_MSG_2_SIZE,
+    };
+    use sodiumoxide::crypto::hash::sha256;
+    use std::thread;
+
+    // A dummy protocol handler: answers pings until the peer leaves
+    fn pong<T: Transport>(transport: &mut T) -> Result<usize, Error> {
+        let mut count = 0;
+        loop {
+            match transport.read() {
+                Ok(msg) if msg == b"ping" => {
+                    transport.write(b"pong")?;
+                    count += 1;
+                }
+                Ok(_) => return Err(Error::InvalidFrame),
+                Err(e) if e.is_closed() => return Ok(count),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn transport_pair() -> (KKTransport, KKTransport) {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cli_thread = thread::spawn(move || {
+            KKTransport::connect(addr, &client_privkey, &server_pubkey)
+                .expect("Client channel connecting")
+        });
+        let server_transport = KKTransport::accept(&listener, &server_privkey, &[client_pubkey])
+            .expect("Server channel binding and accepting");
+
+        (cli_thread.join().unwrap(), server_transport)
+    }
+
+    // An in-memory duplex stream
+    struct Pipe {
+        sender: mpsc::Sender<Vec<u8>>,
+        receiver: mpsc::Receiver<Vec<u8>>,
+        buf: Vec<u8>,
+    }
+
+    fn pipe() -> (Pipe, Pipe) {
+        let (sender_a, receiver_a) = mpsc::channel();
+        let (sender_b, receiver_b) = mpsc::channel();
+        (
+            Pipe {
+                sender: sender_a,
+                receiver: receiver_b,
+                buf: Vec::new(),
+            },
+            Pipe {
+                sender: sender_b,
+                receiver: receiver_a,
+                buf: Vec::new(),
+            },
+        )
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.buf.is_empty() {
+                match self.receiver.recv() {
+                    Ok(data) => self.buf = data,
+                    Err(_) => return Ok(0),
+                }
+            }
+            let len = buf.len().min(self.buf.len());
+            buf[..len].copy_from_slice(&self.buf[..len]);
+            self.buf.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.sender
+                .send(buf.to_vec())
+                .map_err(|_| std::io::Error::from(ErrorKind::BrokenPipe))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_handshake_over_any_stream() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let (mut client_stream, mut server_stream) = pipe();
+
+        let privkey = server_privkey.clone();
+        let server_thread = thread::spawn(move || {
+            let channel =
+                handshake_responder(&mut server_stream, &privkey, &[client_pubkey]).unwrap();
+            (channel, server_stream)
+        });
+        let mut client_channel =
+            handshake_initiator(&mut client_stream, &client_privkey, &server_pubkey).unwrap();
+        let (mut server_channel, mut server_stream) = server_thread.join().unwrap();
+        assert_eq!(client_channel.remote_static(), server_pubkey);
+        assert_eq!(server_channel.remote_static(), client_pubkey);
+
+        let encrypted_msg = client_channel.encrypt_message(b"Hello").unwrap();
+        client_stream.write_all(&encrypted_msg.0).unwrap();
+        let mut header = [0u8; NOISE_MESSAGE_HEADER_SIZE];
+        server_stream.read_exact(&mut header).unwrap();
+        let len = server_channel
+            .decrypt_header(&NoiseEncryptedHeader(header))
+            .unwrap();
+        let mut body = vec![0u8; len as usize];
+        server_stream.read_exact(&mut body).unwrap();
+        assert_eq!(
+            server_channel
+                .decrypt_message(&NoiseEncryptedMessage(body))
+                .unwrap(),
+            b"Hello".to_vec()
+        );
+
+        // An unknown peer is refused
+        let (mut client_stream, mut server_stream) = pipe();
+        let (_, stranger_privkey) = generate_keypair();
+        thread::spawn(move || {
+            handshake_initiator(&mut client_stream, &stranger_privkey, &server_pubkey)
+        });
+        let err =
+            handshake_responder(&mut server_stream, &server_privkey, &[client_pubkey]).unwrap_err();
+        assert!(err.is_unknown_peer());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_transport_unix() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+
+        let path = std::env::temp_dir().join(format!("revault_net_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut transport =
+                KKTransport::accept_unix(&listener, &server_privkey, &[client_pubkey]).unwrap();
+            assert_eq!(transport.remote_static(), client_pubkey);
+            let msg = transport.read().unwrap();
+            transport.write(&msg).unwrap();
+            transport.shutdown().unwrap();
+        });
+
+        let mut transport =
+            KKTransport::connect_unix(&path, &client_privkey, &server_pubkey).unwrap();
+        transport.write(b"Ping").unwrap();
+        assert_eq!(transport.read().unwrap(), b"Ping".to_vec());
+        assert!(transport.read().unwrap_err().is_closed());
+        server_thread.join().unwrap();
+
+        // The TCP specifics degrade gracefully
+        assert_eq!(transport.peer_addr().unwrap(), None);
+        transport.nodelay().unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_transport_rekey() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+
+        let server_thread = thread::spawn(move || {
+            // This one would be received by the client while waiting for the rekey ack
+            server_transport.write(b"Before rekey").unwrap();
+            // Transparently handles the rekey request
+            assert_eq!(server_transport.read().unwrap(), b"After rekey".to_vec());
+            server_transport.write(b"After rekey too").unwrap();
+            server_transport
+        });
+
+        client_transport.rekey().unwrap();
+        client_transport.write(b"After rekey").unwrap();
+        assert_eq!(client_transport.read().unwrap(), b"Before rekey".to_vec());
+        assert_eq!(
+            client_transport.read().unwrap(),
+            b"After rekey too".to_vec()
+        );
+
+        // We can do it again
+        let mut server_transport = server_thread.join().unwrap();
+        let server_thread = thread::spawn(move || {
+            assert_eq!(server_transport.read().unwrap(), b"Again".to_vec());
+        });
+        client_transport.rekey().unwrap();
+        client_transport.write(b"Again").unwrap();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_transport_try_read() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+        assert!(server_transport.try_read().unwrap().is_none());
+
+        // Send the message byte by byte, it must not be returned until it is complete
+        let msg = b"Test message";
+        let mut plaintext = vec![FRAME_DATA];
+        plaintext.extend_from_slice(msg);
+        let cyphertext = client_transport
+            .channel
+            .encrypt_message(&plaintext)
+            .unwrap()
+            .0;
+        for byte in &cyphertext[..cyphertext.len() - 1] {
+            client_transport.stream.write_all(&[*byte]).unwrap();
+            assert!(server_transport.try_read().unwrap().is_none());
+        }
+        client_transport
+            .stream
+            .write_all(&cyphertext[cyphertext.len() - 1..])
+            .unwrap();
+        let received_msg = loop {
+            if let Some(msg) = server_transport.try_read().unwrap() {
+                break msg;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(received_msg, msg.to_vec());
+
+        // We are back to blocking mode
+        client_transport.write(msg).unwrap();
+        assert_eq!(server_transport.read().unwrap(), msg.to_vec());
+    }
+
+    #[test]
+    fn test_transport_from_stream() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cli_thread = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            let mut cli_transport =
+                KKTransport::from_stream_initiator(stream, &client_privkey, &server_pubkey)
+                    .expect("Client handshake");
+            cli_transport.write(b"Test message").unwrap();
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut server_transport =
+            KKTransport::from_stream_responder(stream, &server_privkey, &[client_pubkey])
+                .expect("Server handshake");
+        cli_thread.join().unwrap();
+        assert_eq!(server_transport.read().unwrap(), b"Test message".to_vec());
+    }
+
+    // Act as a SOCKS5 proxy, replying with `status` to the CONNECT request. Returns the
+    // requested domain and port.
+    fn mock_socks5(stream: &mut TcpStream, status: u8) -> (String, u16) {
+        let mut greeting = [0u8; 3];
+        stream.read_exact(&mut greeting).unwrap();
+        assert_eq!(greeting, [0x05, 0x01, 0x00]);
+        stream.write_all(&[0x05, 0x00]).unwrap();
+
+        let mut request = [0u8; 5];
+        stream.read_exact(&mut request).unwrap();
+        assert_eq!(request[..4], [0x05, 0x01, 0x00, 0x03]);
+        let mut host = vec![0u8; request[4] as usize];
+        stream.read_exact(&mut host).unwrap();
+        let mut port = [0u8; 2];
+        stream.read_exact(&mut port).unwrap();
+
+        stream
+            .write_all(&[0x05, status, 0x00, 0x01, 127, 0, 0, 1, 0x04, 0xd2])
+            .unwrap();
+
+        (String::from_utf8(host).unwrap(), u16::from_be_bytes(port))
+    }
+
+    // Act as an HTTP proxy, replying with `status_line` to the CONNECT request. Returns the
+    // request.
+    fn mock_http_proxy(stream: &mut TcpStream, status_line: &str) -> String {
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).unwrap();
+            request.push(byte[0]);
+        }
+        stream
+            .write_all(format!("{}\r\nVia: mock\r\n\r\n", status_line).as_bytes())
+            .unwrap();
+
+        String::from_utf8(request).unwrap()
+    }
+
+    #[test]
+    fn test_transport_http_proxy() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let my_privkey = client_privkey.clone();
+        let cli_thread = thread::spawn(move || {
+            let mut cli_transport = KKTransport::connect_through_http_proxy(
+                proxy,
+                "watchtower.example:8383",
+                Some(("user", "pass")),
+                &my_privkey,
+                &server_pubkey,
+            )
+            .expect("Connecting through the proxy");
+            cli_transport.write(b"Test message").unwrap();
+        });
+
+        // The proxy is the server itself, it doesn't matter to the client
+        let (mut stream, _) = listener.accept().unwrap();
+        let request = mock_http_proxy(&mut stream, "HTTP/1.1 200 Connection established");
+        assert_eq!(
+            request,
+            "CONNECT watchtower.example:8383 HTTP/1.1\r\n\
+             Host: watchtower.example:8383\r\n\
+             Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+        );
+        let mut server_transport =
+            KKTransport::from_stream_responder(stream, &server_privkey, &[client_pubkey]).unwrap();
+        cli_thread.join().unwrap();
+        assert_eq!(server_transport.read().unwrap(), b"Test message".to_vec());
+
+        // Now the proxy wants credentials, then refuses the connection
+        for (status_line, kind) in [
+            (
+                "HTTP/1.1 407 Proxy Authentication Required",
+                ErrorKind::PermissionDenied,
+            ),
+            ("HTTP/1.0 502 Bad Gateway", ErrorKind::Other),
+            ("SSH-2.0-OpenSSH_9.6", ErrorKind::Other),
+        ] {
+            let my_privkey = client_privkey.clone();
+            let cli_thread = thread::spawn(move || {
+                KKTransport::connect_through_http_proxy(
+                    proxy,
+                    "watchtower.example:8383",
+                    None,
+                    &my_privkey,
+                    &server_pubkey,
+                )
+            });
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = mock_http_proxy(&mut stream, status_line);
+            assert!(!request.contains("Proxy-Authorization"));
+            match cli_thread.join().unwrap() {
+                Err(Error::Transport(e)) => assert_eq!(e.kind(), kind),
+                e => panic!("Unexpected result: {:?}", e),
+            }
+        }
+
+        // Invalid targets
+        for target in ["watchtower.example", ":8383", "evil.example:80\r\nX: y:80"] {
+            match KKTransport::connect_through_http_proxy(
+                proxy,
+                target,
+                None,
+                &client_privkey,
+                &server_pubkey,
+            ) {
+                Err(Error::Transport(e)) => assert_eq!(e.kind(), ErrorKind::InvalidInput),
+                e => panic!("Unexpected result: {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_base64_encode() {
+        for (data, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"user:pass", "dXNlcjpwYXNz"),
+        ] {
+            assert_eq!(base64_encode(data), encoded);
+        }
+    }
+
+    #[test]
+    fn test_transport_socks5() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let my_privkey = client_privkey.clone();
+        let cli_thread = thread::spawn(move || {
+            let mut cli_transport = KKTransport::connect_through_proxy(
+                proxy,
+                "revaultxxxxxxxxxxxxxxx.onion:8383",
+                &my_privkey,
+                &server_pubkey,
+            )
+            .expect("Connecting through the proxy");
+            cli_transport.write(b"Test message").unwrap();
+        });
+
+        // The proxy is the server itself, it doesn't matter to the client
+        let (mut stream, _) = listener.accept().unwrap();
+        let (host, port) = mock_socks5(&mut stream, 0x00);
+        assert_eq!(host, "revaultxxxxxxxxxxxxxxx.onion");
+        assert_eq!(port, 8383);
+        let mut server_transport =
+            KKTransport::from_stream_responder(stream, &server_privkey, &[client_pubkey]).unwrap();
+        cli_thread.join().unwrap();
+        assert_eq!(server_transport.read().unwrap(), b"Test message".to_vec());
+
+        // Now the proxy refuses the connection
+        let my_privkey = client_privkey.clone();
+        let cli_thread = thread::spawn(move || {
+            KKTransport::connect_through_proxy(
+                proxy,
+                "revaultxxxxxxxxxxxxxxx.onion:8383",
+                &my_privkey,
+                &server_pubkey,
+            )
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        mock_socks5(&mut stream, 0x05);
+        match cli_thread.join().unwrap() {
+            Err(Error::Transport(e)) => assert_eq!(e.kind(), ErrorKind::ConnectionRefused),
+            e => panic!("Unexpected result: {:?}", e),
+        }
+
+        // Invalid target
+        KKTransport::connect_through_proxy(proxy, "revault.onion", &client_privkey, &server_pubkey)
+            .expect_err("No port");
+
+        // A silent proxy doesn't hang us, be it before or after the greeting
+        for answer_greeting in [false, true] {
+            let proxy_thread = thread::spawn({
+                let listener = listener.try_clone().unwrap();
+                move || {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    if answer_greeting {
+                        let mut greeting = [0u8; 3];
+                        stream.read_exact(&mut greeting).unwrap();
+                        stream.write_all(&[0x05, 0x00]).unwrap();
+                    }
+                    // Keep the connection open until the client gave up
+                    let _ = stream.read_to_end(&mut Vec::new());
+                }
+            });
+            let start = Instant::now();
+            match socks5_dial(&proxy, "revault.onion", 8383, Duration::from_millis(300)) {
+                Err(Error::Transport(e)) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+                e => panic!("Unexpected result: {:?}", e),
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+            proxy_thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_transport_handshake_hash() {
+        let (client_transport, server_transport) = transport_pair();
+        assert_eq!(
+            client_transport.handshake_hash(),
+            server_transport.handshake_hash()
+        );
+    }
+
+    #[test]
+    fn test_transport_unknown_peer() {
+        let ((_, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let (other_pubkey, _) = generate_keypair();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cli_thread = thread::spawn(move || {
+            KKTransport::connect(addr, &client_privkey, &server_pubkey)
+                .expect_err("Server doesn't know us")
+        });
+
+        let err = KKTransport::accept(&listener, &server_privkey, &[other_pubkey])
+            .expect_err("We don't know the client");
+        assert!(err.is_unknown_peer());
+        // The server closed the connection without responding
+        assert!(cli_thread.join().unwrap().is_io());
+    }
+
+    #[test]
+    fn test_transport_read_into() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+
+        let mut buf = Vec::with_capacity(2048);
+        let buf_ptr = buf.as_ptr();
+        for msg in &[&b"Test message"[..], &b"Some other"[..], &[0u8; 1024][..]] {
+            client_transport.write(msg).unwrap();
+            assert_eq!(server_transport.read_into(&mut buf).unwrap(), msg.len());
+            assert_eq!(&buf, msg);
+            // The buffer was reused (it was large enough)
+            assert_eq!(buf.as_ptr(), buf_ptr);
+        }
+    }
+
+    #[test]
+    fn test_transport_write_batch() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+
+        let msgs: [&[u8]; 3] = [b"First", b"Second", &[0u8; 1024]];
+        client_transport.write_batch(&msgs).unwrap();
+        for msg in &msgs {
+            assert_eq!(&server_transport.read().unwrap(), msg);
+        }
+
+        // Nothing is written if a message is too large, and the channel is still usable
+        let too_large = vec![0u8; NOISE_PLAINTEXT_MAX_SIZE];
+        client_transport
+            .write_batch(&[b"Not sent", &too_large])
+            .unwrap_err();
+        client_transport.write_batch(&[]).unwrap();
+        client_transport.write(b"Sent").unwrap();
+        assert_eq!(server_transport.read().unwrap(), b"Sent".to_vec());
+    }
+
+    #[test]
+    fn test_transport_stream_data() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+        let server_thread = thread::spawn(move || {
+            let mut received = Vec::new();
+            assert_eq!(
+                server_transport.read_to(&mut received).unwrap(),
+                expected.len() as u64
+            );
+            assert_eq!(received, expected);
+            // Regular messages can follow
+            assert_eq!(server_transport.read().unwrap(), b"Done".to_vec());
+
+            // Streaming nothing
+            let mut received = Vec::new();
+            assert_eq!(server_transport.read_to(&mut received).unwrap(), 0);
+        });
+
+        assert_eq!(
+            client_transport.write_from(&mut &data[..]).unwrap(),
+            data.len() as u64
+        );
+        client_transport.write(b"Done").unwrap();
+        client_transport.write_from(&mut std::io::empty()).unwrap();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_transport_stats() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+        // Only the contributions to the session token went through
+        let session_frame_size = encrypted_msg_size(FRAME_TYPE_SIZE + SESSION_TOKEN_SIZE) as u64;
+        assert_eq!(
+            client_transport.stats(),
+            TransportStats {
+                bytes_sent: session_frame_size,
+                bytes_recv: session_frame_size,
+                ..TransportStats::default()
+            }
+        );
+
+        client_transport.write(b"Ping").unwrap();
+        client_transport.write_batch(&[b"Ping", b""]).unwrap();
+        for _ in 0..3 {
+            let msg = server_transport.read().unwrap();
+            server_transport.write(&msg).unwrap();
+        }
+        for _ in 0..3 {
+            client_transport.read().unwrap();
+        }
+
+        // Each message is prefixed with its frame type
+        let wire_size = (encrypted_msg_size(4 + FRAME_TYPE_SIZE) * 2
+            + encrypted_msg_size(FRAME_TYPE_SIZE)) as u64
+            + session_frame_size;
+        let expected = TransportStats {
+            msgs_sent: 3,
+            msgs_recv: 3,
+            plaintext_bytes_sent: 8,
+            plaintext_bytes_recv: 8,
+            bytes_sent: wire_size,
+            bytes_recv: wire_size,
+        };
+        assert_eq!(client_transport.stats(), expected);
+        assert_eq!(server_transport.stats(), expected);
+
+        // Control messages only account for the bytes
+        server_transport.close_write().unwrap();
+        assert!(client_transport.read().unwrap_err().is_closed());
+        let stats = client_transport.stats();
+        assert_eq!(stats.msgs_recv, expected.msgs_recv);
+        assert_eq!(
+            stats.bytes_recv,
+            expected.bytes_recv + encrypted_msg_size(FRAME_TYPE_SIZE) as u64
+        );
+    }
+
+    #[test]
+    fn test_transport_read_available() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+        assert!(server_transport.read_available().unwrap().is_empty());
+
+        client_transport
+            .write_batch(&[b"First", b"Second", b"Third"])
+            .unwrap();
+        assert_eq!(server_transport.read().unwrap(), b"First".to_vec());
+        let mut msgs = Vec::new();
+        while msgs.len() < 2 {
+            msgs.extend(server_transport.read_available().unwrap());
+        }
+        assert_eq!(msgs, vec![b"Second".to_vec(), b"Third".to_vec()]);
+        assert!(server_transport.read_available().unwrap().is_empty());
+
+        // Messages read before an error aren't lost
+        client_transport.write(b"Last").unwrap();
+        client_transport.shutdown().unwrap();
+        let err = loop {
+            match server_transport.read_available() {
+                Ok(msgs) => assert!(msgs.is_empty()),
+                Err(e) => break e,
+            }
+        };
+        assert!(err.is_closed());
+        assert_eq!(server_transport.read().unwrap(), b"Last".to_vec());
+    }
+
+    #[test]
+    fn test_connect_from_local() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+
+        // Get a free port
+        let local_addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut transport =
+                KKTransport::accept(&listener, &server_privkey, &[client_pubkey]).unwrap();
+            assert_eq!(transport.peer_addr().unwrap(), Some(local_addr));
+            assert_eq!(transport.read().unwrap(), b"Hello".to_vec());
+        });
+
+        let mut transport =
+            KKTransport::connect_from_local(local_addr, addr, &client_privkey, &server_pubkey)
+                .unwrap();
+        assert_eq!(transport.local_addr().unwrap(), Some(local_addr));
+        assert_eq!(transport.peer_addr().unwrap(), Some(addr));
+        transport.write(b"Hello").unwrap();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_peek_message_len() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+        assert_eq!(server_transport.peek_message_len().unwrap(), None);
+
+        client_transport.write(&[0x42; 1000]).unwrap();
+        let len = loop {
+            if let Some(len) = server_transport.peek_message_len().unwrap() {
+                break len;
+            }
+        };
+        assert_eq!(len, 1000);
+        // It's not consumed
+        assert_eq!(server_transport.peek_message_len().unwrap(), Some(1000));
+        let mut buf = Vec::with_capacity(len as usize);
+        assert_eq!(server_transport.read_into(&mut buf).unwrap(), 1000);
+        assert_eq!(buf, vec![0x42; 1000]);
+
+        // Same with sequence numbers, which are not part of the message
+        let config = TransportConfig {
+            sequence_numbers: true,
+            ..TransportConfig::default()
+        };
+        client_transport.set_config(config);
+        server_transport.set_config(config);
+        client_transport.write(b"Hello").unwrap();
+        while server_transport.peek_message_len().unwrap().is_none() {}
+        assert_eq!(server_transport.peek_message_len().unwrap(), Some(5));
+        assert_eq!(server_transport.read().unwrap(), b"Hello".to_vec());
+
+        // And for a message kept while waiting for a control message
+        let client_thread = thread::spawn(move || {
+            client_transport.write(b"World!").unwrap();
+            assert_eq!(client_transport.read().unwrap(), b"ok".to_vec());
+        });
+        server_transport.rekey().unwrap();
+        assert_eq!(server_transport.peek_message_len().unwrap(), Some(6));
+        assert_eq!(server_transport.read().unwrap(), b"World!".to_vec());
+        server_transport.write(b"ok").unwrap();
+        client_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_session_token() {
+        let (client_transport, server_transport) = transport_pair();
+        let token = client_transport.session_token();
+        assert_eq!(server_transport.session_token(), token);
+        // It's not just the handshake hash
+        assert_ne!(&token[..], &client_transport.handshake_hash()[..]);
+
+        // Another session gets another token
+        let (client_transport, server_transport) = transport_pair();
+        assert_eq!(
+            client_transport.session_token(),
+            server_transport.session_token()
+        );
+        assert_ne!(client_transport.session_token(), token);
+
+        // A peer which doesn't send its contribution first is refused
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_thread = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut channel =
+                handshake_responder(&mut stream, &server_privkey, &[client_pubkey]).unwrap();
+            let msg = channel.encrypt_message(&[FRAME_DATA, 42]).unwrap();
+            stream.write_all(&msg.0).unwrap();
+        });
+        let err = KKTransport::connect(addr, &client_privkey, &server_pubkey).unwrap_err();
+        assert!(matches!(err, Error::InvalidFrame), "{:?}", err);
+        server_thread.join().unwrap();
+    }
+
+    // Read the plaintext of the next message from the stream, for a peer using a raw channel
+    fn read_raw_message(stream: &mut TcpStream, channel: &mut KKChannel) -> Vec<u8> {
+        let mut header = [0u8; NOISE_MESSAGE_HEADER_SIZE];
+        stream.read_exact(&mut header).unwrap();
+        let len = channel
+            .decrypt_header(&NoiseEncryptedHeader(header))
+            .unwrap();
+        let mut body = vec![0u8; len as usize];
+        stream.read_exact(&mut body).unwrap();
+        channel
+            .decrypt_message(&NoiseEncryptedMessage(body))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_transport_v0_peer() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_thread = thread::spawn(move || {
+            KKTransport::accept(&listener, &server_privkey, &[client_pubkey]).unwrap()
+        });
+
+        // A peer which only knows version 0 sends its messages as is, without a frame type
+        let (cli_act_1, msg_1) = KKHandshakeActOne::initiator(
+            &client_privkey,
+            &server_pubkey,
+            &[0],
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut channel = handshake_initiator_classified(&mut stream, cli_act_1, msg_1).unwrap();
+        let mut server_transport = server_thread.join().unwrap();
+        assert_eq!(server_transport.negotiated_version(), 0);
+        for msg in [&b"Hello"[..], &[FRAME_REKEY], &[]] {
+            let encrypted_msg = channel.encrypt_message(msg).unwrap();
+            stream.write_all(&encrypted_msg.0).unwrap();
+            assert_eq!(server_transport.read().unwrap(), msg);
+        }
+        assert_eq!(server_transport.stats().msgs_recv, 3);
+        server_transport.write(b"World").unwrap();
+        server_transport.write_batch(&[b"a", b"b"]).unwrap();
+        for msg in [&b"World"[..], b"a", b"b"] {
+            assert_eq!(read_raw_message(&mut stream, &mut channel), msg);
+        }
+        let msg = vec![0x42; NOISE_PLAINTEXT_MAX_SIZE];
+        server_transport.write(&msg).unwrap();
+        assert_eq!(read_raw_message(&mut stream, &mut channel), msg);
+
+        // The control messages are refused without sending anything
+        assert!(matches!(server_transport.rekey(), Err(Error::Unsupported)));
+        assert!(matches!(
+            server_transport.write_acked(b"Hello"),
+            Err(Error::Unsupported)
+        ));
+        assert!(matches!(
+            server_transport.write_from(&mut &b"Hello"[..]),
+            Err(Error::Unsupported)
+        ));
+        let mut config = server_transport.config();
+        config.sequence_numbers = true;
+        server_transport.set_config(config);
+        assert!(matches!(
+            server_transport.write(b"Hello"),
+            Err(Error::Unsupported)
+        ));
+        assert!(matches!(server_transport.read(), Err(Error::Unsupported)));
+        config.sequence_numbers = false;
+        server_transport.set_config(config);
+
+        // Closing doesn't send a close frame either
+        server_transport.write(b"Bye").unwrap();
+        server_transport.shutdown().unwrap();
+        assert_eq!(read_raw_message(&mut stream, &mut channel), b"Bye");
+        assert_eq!(stream.read(&mut [0]).unwrap(), 0);
+    }
+
+    // A responder as they were before the version negotiation, and still are on the deployed
+    // peers. It closes the connection upon any other first message than the legacy one.
+    fn legacy_accept(
+        listener: &TcpListener,
+        my_privkey: &SecretKey,
+        their_pubkey: &PublicKey,
+    ) -> Option<(TcpStream, snow::TransportState)> {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut state = snow::Builder::with_resolver(
+            "Noise_KK_25519_ChaChaPoly_SHA256".parse().unwrap(),
+            Box::new(snow::resolvers::SodiumResolver),
+        )
+        .local_private_key(&my_privkey.0)
+        .remote_public_key(&their_pubkey.0)
+        .build_responder()
+        .unwrap();
+
+        let mut msg_1 = [0u8; KK_MSG_1_SIZE];
+        stream.read_exact(&mut msg_1).unwrap();
+        let mut payload = [0u8; KK_MSG_1_SIZE];
+        state.read_message(&msg_1, &mut payload).unwrap();
+        if &payload[..HANDSHAKE_MESSAGE.len()] != HANDSHAKE_MESSAGE {
+            return None;
+        }
+        let mut msg_2 = [0u8; KK_MSG_2_SIZE];
+        state.write_message(&[], &mut msg_2).unwrap();
+        stream.write_all(&msg_2).unwrap();
+
+        Some((stream, state.into_transport_mode().unwrap()))
+    }
+
+    // Exchange a message with a legacy peer, see [legacy_accept]
+    fn legacy_roundtrip(stream: &mut TcpStream, state: &mut snow::TransportState) {
+        let mut header = [0u8; NOISE_MESSAGE_HEADER_SIZE];
+        stream.read_exact(&mut header).unwrap();
+        let mut len = [0u8; NOISE_MESSAGE_HEADER_SIZE];
+        state.read_message(&header, &mut len).unwrap();
+        let mut body = vec![0u8; u16::from_be_bytes([len[0], len[1]]) as usize];
+        stream.read_exact(&mut body).unwrap();
+        let mut msg = vec![0u8; body.len()];
+        let msg_len = state.read_message(&body, &mut msg).unwrap();
+        assert_eq!(&msg[..msg_len], b"Hello");
+
+        let msg = b"World";
+        let mut encrypted_msg = vec![0u8; encrypted_msg_size(msg.len())];
+        let len = ((msg.len() + MAC_SIZE) as u16).to_be_bytes();
+        let (header, body) = encrypted_msg.split_at_mut(NOISE_MESSAGE_HEADER_SIZE);
+        state.write_message(&len, header).unwrap();
+        state.write_message(msg, body).unwrap();
+        stream.write_all(&encrypted_msg).unwrap();
+    }
+
+    #[test]
+    fn test_connect_legacy_responder() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // It refuses our offer of all the versions, so we connect again offering the legacy
+        // ones only
+        let privkey = server_privkey.clone();
+        let server_thread = thread::spawn(move || {
+            for _ in 0..2 {
+                assert!(legacy_accept(&listener, &privkey, &client_pubkey).is_none());
+                let (mut stream, mut state) =
+                    legacy_accept(&listener, &privkey, &client_pubkey).unwrap();
+                legacy_roundtrip(&mut stream, &mut state);
+            }
+        });
+        let mut transport = KKTransport::connect(addr, &client_privkey, &server_pubkey).unwrap();
+        assert_eq!(transport.negotiated_version(), 0);
+        transport.write(b"Hello").unwrap();
+        assert_eq!(transport.read().unwrap(), b"World");
+        let mut transport = KKTransport::try_connect(
+            addr,
+            &client_privkey,
+            &server_pubkey,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(transport.negotiated_version(), 0);
+        transport.write(b"Hello").unwrap();
+        assert_eq!(transport.read().unwrap(), b"World");
+        server_thread.join().unwrap();
+
+        // A responder refusing the handshake says so, and we don't try again
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_thread = thread::spawn(move || {
+            KKTransport::accept(&listener, &server_privkey, &[]).unwrap_err();
+            listener
+        });
+        let err = KKTransport::connect(addr, &client_privkey, &server_pubkey).unwrap_err();
+        assert!(!is_unanswered(&err), "{:?}", err);
+        let listener = server_thread.join().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().is_err());
+    }
+
+    // Same as [legacy_accept] for an initiator
+    fn legacy_connect(
+        addr: SocketAddr,
+        my_privkey: &SecretKey,
+        their_pubkey: &PublicKey,
+    ) -> (TcpStream, snow::TransportState) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut state = snow::Builder::with_resolver(
+            "Noise_KK_25519_ChaChaPoly_SHA256".parse().unwrap(),
+            Box::new(snow::resolvers::SodiumResolver),
+        )
+        .local_private_key(&my_privkey.0)
+        .remote_public_key(&their_pubkey.0)
+        .build_initiator()
+        .unwrap();
+
+        let mut msg_1 = [0u8; KK_MSG_1_SIZE];
+        state.write_message(HANDSHAKE_MESSAGE, &mut msg_1).unwrap();
+        stream.write_all(&msg_1).unwrap();
+        let mut msg_2 = [0u8; KK_MSG_2_SIZE];
+        stream.read_exact(&mut msg_2).unwrap();
+        state.read_message(&msg_2, &mut []).unwrap();
+
+        (stream, state.into_transport_mode().unwrap())
+    }
+
+    #[test]
+    fn test_session_token_v0_peer() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let token_from_hash = |transport: &KKTransport| sha256::hash(&transport.handshake_hash()).0;
+
+        // A legacy responder doesn't send its contribution, the token is derived from the
+        // handshake hash alone
+        let privkey = server_privkey.clone();
+        let server_thread = thread::spawn(move || {
+            assert!(legacy_accept(&listener, &privkey, &client_pubkey).is_none());
+            let (mut stream, mut state) =
+                legacy_accept(&listener, &privkey, &client_pubkey).unwrap();
+            legacy_roundtrip(&mut stream, &mut state);
+            listener
+        });
+        let mut client_transport =
+            KKTransport::connect(addr, &client_privkey, &server_pubkey).unwrap();
+        assert_eq!(client_transport.negotiated_version(), 0);
+        client_transport.write(b"Hello").unwrap();
+        assert_eq!(client_transport.read().unwrap(), b"World");
+        let listener = server_thread.join().unwrap();
+        let token = client_transport.session_token();
+        assert_eq!(token, token_from_hash(&client_transport));
+
+        // Likewise with a legacy initiator
+        let server_thread = thread::spawn(move || {
+            KKTransport::accept(&listener, &server_privkey, &[client_pubkey]).unwrap()
+        });
+        let (mut stream, mut state) = legacy_connect(addr, &client_privkey, &server_pubkey);
+        let mut server_transport = server_thread.join().unwrap();
+        assert_eq!(server_transport.negotiated_version(), 0);
+        server_transport.write(b"Hello").unwrap();
+        legacy_roundtrip(&mut stream, &mut state);
+        assert_eq!(server_transport.read().unwrap(), b"World");
+        assert_eq!(
+            server_transport.session_token(),
+            token_from_hash(&server_transport)
+        );
+        assert_ne!(server_transport.session_token(), token);
+    }
+
+    #[test]
+    fn test_write_once() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+        client_transport.write_once(b"Hello").unwrap();
+        assert_eq!(server_transport.read().unwrap(), b"Hello".to_vec());
+        assert_eq!(client_transport.stats().msgs_sent, 1);
+
+        // Once the connection is broken it fails right away, even though the policy would
+        // have us retry for seconds
+        client_transport.set_retry_policy(RetryPolicy::default());
+        drop(server_transport);
+        let err = loop {
+            let start = Instant::now();
+            let res = client_transport.write_once(&[0; 1024]);
+            assert!(start.elapsed() < Duration::from_millis(500));
+            if let Err(e) = res {
+                break e;
+            }
+        };
+        assert!(err.is_io());
+    }
+
+    #[test]
+    fn test_write_nonblocking() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+        let bytes_sent = client_transport.stats().bytes_sent;
+        let progress = client_transport.write_nonblocking(b"Hello").unwrap();
+        assert!(progress.is_complete());
+        assert_eq!(
+            progress.flushed as u64,
+            client_transport.stats().bytes_sent - bytes_sent
+        );
+        assert_eq!(server_transport.read().unwrap(), b"Hello".to_vec());
+
+        // The server doesn't read, so the socket buffers fill up and we are left with a
+        // pending tail instead of blocking
+        let msg = vec![0x42; 60_000];
+        let mut sent = 0;
+        loop {
+            let start = Instant::now();
+            let progress = client_transport.write_nonblocking(&msg).unwrap();
+            assert!(start.elapsed() < Duration::from_secs(1));
+            sent += 1;
+            if !progress.is_complete() {
+                break;
+            }
+            assert!(sent < 10_000);
+        }
+        let stats = client_transport.stats();
+        assert_eq!(stats.msgs_sent, sent + 1);
+
+        // It resumes as the server reads, and the rest of the tail is written before the next
+        // blocking write
+        let server_thread = thread::spawn(move || {
+            for _ in 0..sent {
+                assert_eq!(server_transport.read().unwrap(), msg);
+            }
+            assert_eq!(server_transport.read().unwrap(), b"after".to_vec());
+        });
+        let mut flushed = 0;
+        while flushed == 0 {
+            let progress = client_transport.flush_pending().unwrap();
+            flushed += progress.flushed;
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(
+            client_transport.stats().bytes_sent,
+            stats.bytes_sent + flushed as u64
+        );
+        client_transport.write(b"after").unwrap();
+        assert!(client_transport.flush_pending().unwrap().is_complete());
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_transport_timeouts() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+        assert_eq!(server_transport.read_timeout().unwrap(), None);
+        assert_eq!(client_transport.write_timeout().unwrap(), None);
+        let timeout = Some(Duration::from_millis(100));
+        server_transport.set_read_timeout(timeout).unwrap();
+        client_transport.set_write_timeout(timeout).unwrap();
+        assert_eq!(server_transport.read_timeout().unwrap(), timeout);
+        assert_eq!(client_transport.write_timeout().unwrap(), timeout);
+        assert!(server_transport
+            .set_read_timeout(Some(Duration::from_secs(0)))
+            .is_err());
+
+        // Nothing to read: it times out gracefully
+        server_transport.set_retry_policy(RetryPolicy::no_retry());
+        let start = Instant::now();
+        let err = server_transport.read().unwrap_err();
+        assert!(err.is_timeout(), "{:?}", err);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // It times out in the middle of a message, which is resumed by the next read
+        let encrypted_msg = client_transport
+            .encrypt_frame(FRAME_DATA, b"Hello")
+            .unwrap();
+        client_transport
+            .stream
+            .write_all(&encrypted_msg[..NOISE_MESSAGE_HEADER_SIZE + 3])
+            .unwrap();
+        assert!(server_transport.read().unwrap_err().is_timeout());
+        client_transport
+            .stream
+            .write_all(&encrypted_msg[NOISE_MESSAGE_HEADER_SIZE + 3..])
+            .unwrap();
+        assert_eq!(server_transport.read().unwrap(), b"Hello".to_vec());
+        client_transport.write(b"World").unwrap();
+        assert_eq!(server_transport.read().unwrap(), b"World".to_vec());
+
+        // The server doesn't read, so the writes end up timing out. The part of the message
+        // which wasn't written is sent before the next one.
+        client_transport.set_retry_policy(RetryPolicy::no_retry());
+        let msg = vec![0x42; 60_000];
+        let mut sent = 0;
+        let err = loop {
+            match client_transport.write(&msg) {
+                Ok(()) => sent += 1,
+                Err(e) => break e,
+            }
+            assert!(sent < 10_000);
+        };
+        assert!(err.is_timeout(), "{:?}", err);
+        client_transport.set_write_timeout(None).unwrap();
+        server_transport.set_read_timeout(None).unwrap();
+        let server_thread = thread::spawn(move || {
+            for _ in 0..sent + 1 {
+                assert_eq!(server_transport.read().unwrap(), msg);
+            }
+            assert_eq!(server_transport.read().unwrap(), b"after".to_vec());
+        });
+        client_transport.write(b"after").unwrap();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_read_buffer_size() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+        assert_eq!(
+            server_transport.config().read_buffer_size,
+            DEFAULT_READ_BUFFER_SIZE
+        );
+        let config = TransportConfig {
+            read_buffer_size: 1_000,
+            ..TransportConfig::default()
+        };
+        server_transport.set_config(config);
+        client_transport.set_config(config);
+
+        // The client floods us while we wait for it to acknowledge a rekey: we stop reading
+        // once the buffer is full
+        let msgs: Vec<Vec<u8>> = (0..10).map(|i| vec![i; 200]).collect();
+        let batch: Vec<&[u8]> = msgs.iter().map(|msg| msg.as_slice()).collect();
+        client_transport.write_batch(&batch).unwrap();
+        assert!(matches!(
+            server_transport.rekey().unwrap_err(),
+            Error::BufferFull
+        ));
+        assert_eq!(server_transport.pending.len(), 5);
+        assert!(server_transport.pending_size < 1_000 + buffered_size(&msgs[0]));
+
+        // Nothing is lost, and the rekey completes once we read
+        let server_thread = thread::spawn(move || {
+            for msg in &msgs {
+                assert_eq!(&server_transport.read().unwrap(), msg);
+            }
+            assert_eq!(server_transport.read().unwrap(), b"after rekey".to_vec());
+
+            // Reading what's available stops at the buffer size too
+            let mut received = Vec::new();
+            while received.len() < 10 {
+                let msgs = server_transport.read_available().unwrap();
+                assert!(msgs.len() <= 5);
+                received.extend(msgs);
+            }
+            assert_eq!(received, vec![vec![0x42; 200]; 10]);
+            server_transport.write(&[0x42; 2_000]).unwrap();
+        });
+        client_transport.write(b"after rekey").unwrap();
+        let msg = [0x42; 200];
+        client_transport.write_batch(&[&msg[..]; 10]).unwrap();
+
+        // A single message which doesn't fit
+        assert!(matches!(
+            client_transport.rekey().unwrap_err(),
+            Error::BufferFull
+        ));
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_transport_max_message_size() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+        assert_eq!(
+            server_transport.max_message_size(),
+            NOISE_PLAINTEXT_MAX_SIZE
+        );
+        server_transport.set_max_message_size(NOISE_PLAINTEXT_MAX_SIZE + 1);
+        assert_eq!(
+            server_transport.max_message_size(),
+            NOISE_PLAINTEXT_MAX_SIZE
+        );
+
+        server_transport.set_max_message_size(100);
+        client_transport.write(&[1u8; 100]).unwrap();
+        assert_eq!(server_transport.read().unwrap(), vec![1u8; 100]);
+
+        // The announced size is rejected before reading the body
+        client_transport.write(&[1u8; 10_000]).unwrap();
+        match server_transport.read() {
+            Err(Error::Noise(NoiseError::InvalidCiphertext)) => {}
+            e => panic!("Unexpected result: {:?}", e),
+        }
+        assert!(server_transport.read_buf.capacity() < 1_000);
+    }
+
+    #[test]
+    fn test_transport_socket_options() {
+        let (client_transport, server_transport) = transport_pair();
+
+        assert_eq!(
+            server_transport.peer_addr().unwrap().unwrap().ip(),
+            client_transport.peer_addr().unwrap().unwrap().ip()
+        );
+
+        // Nagle's algorithm is disabled on both ends by default
+        assert!(client_transport.nodelay().unwrap());
+        assert!(server_transport.nodelay().unwrap());
+        client_transport.set_nodelay(false).unwrap();
+        assert!(!client_transport.nodelay().unwrap());
+
+        assert!(!client_transport.keepalive().unwrap());
+        client_transport
+            .set_keepalive(Some(Duration::from_secs(60)))
+            .unwrap();
+        assert!(client_transport.keepalive().unwrap());
+        client_transport.set_keepalive(None).unwrap();
+        assert!(!client_transport.keepalive().unwrap());
+    }
+
+    #[test]
+    fn test_transport_shutdown() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+
+        client_transport.write(b"Last words").unwrap();
+        client_transport.shutdown().unwrap();
+
+        // The messages sent before closing are still received
+        assert_eq!(server_transport.read().unwrap(), b"Last words".to_vec());
+        let err = server_transport.read().unwrap_err();
+        assert!(err.is_closed(), "{:?}", err);
+        // Nothing left on the stream afterwards
+        assert!(matches!(
+            server_transport.read().unwrap_err(),
+            Error::CleanEof
+        ));
+    }
+
+    #[test]
+    fn test_transport_eof() {
+        // The peer leaves right after a message
+        let (mut client_transport, mut server_transport) = transport_pair();
+        client_transport.write(b"Last words").unwrap();
+        drop(client_transport);
+        assert_eq!(server_transport.read().unwrap(), b"Last words".to_vec());
+        let err = server_transport.read().unwrap_err();
+        assert!(matches!(err, Error::CleanEof), "{:?}", err);
+        assert!(err.is_eof() && !err.is_io());
+
+        // The peer leaves after sending only the header of a message
+        let (mut client_transport, mut server_transport) = transport_pair();
+        let msg = client_transport
+            .channel
+            .encrypt_message(&[FRAME_DATA, 42])
+            .unwrap();
+        client_transport
+            .stream
+            .write_all(&msg.0[..NOISE_MESSAGE_HEADER_SIZE])
+            .unwrap();
+        drop(client_transport);
+        let err = server_transport.read().unwrap_err();
+        assert!(matches!(err, Error::TruncatedMessage), "{:?}", err);
+        assert!(err.is_eof());
+
+        // Or only part of the header
+        let (mut client_transport, mut server_transport) = transport_pair();
+        client_transport.stream.write_all(&msg.0[..3]).unwrap();
+        drop(client_transport);
+        assert!(matches!(
+            server_transport.read().unwrap_err(),
+            Error::TruncatedMessage
+        ));
+    }
+
+    #[test]
+    fn test_transport_close_write() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+
+        client_transport.write(b"Request").unwrap();
+        client_transport.close_write().unwrap();
+        client_transport.set_retry_policy(RetryPolicy::no_retry());
+        client_transport.write(b"Too late").unwrap_err();
+
+        // The other end can still answer once it noticed we are done
+        assert_eq!(server_transport.read().unwrap(), b"Request".to_vec());
+        assert!(server_transport.read().unwrap_err().is_closed());
+        server_transport.write(b"Response").unwrap();
+        server_transport.shutdown().unwrap();
+
+        assert_eq!(client_transport.read().unwrap(), b"Response".to_vec());
+        assert!(client_transport.read().unwrap_err().is_closed());
+    }
+
+    #[test]
+    fn test_transport_accept_with_auth() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_thread = thread::spawn(move || {
+            // A known peer, but revoked
+            let err = KKTransport::accept_with_auth(
+                &listener,
+                &server_privkey,
+                &[client_pubkey],
+                |pubkey| {
+                    assert_eq!(*pubkey, client_pubkey);
+                    false
+                },
+            )
+            .unwrap_err();
+            assert!(matches!(err, Error::Rejected));
+
+            let transport = KKTransport::accept_with_auth(
+                &listener,
+                &server_privkey,
+                &[client_pubkey],
+                |pubkey| *pubkey == client_pubkey,
+            )
+            .unwrap();
+            assert_eq!(transport.remote_static(), client_pubkey);
+        });
+
+        let err = KKTransport::connect(addr, &client_privkey, &server_pubkey).unwrap_err();
+        assert!(err.is_io());
+        KKTransport::connect(addr, &client_privkey, &server_pubkey).unwrap();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_reconnecting_transport() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let (_, other_privkey) = generate_keypair();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut transport =
+                KKTransport::accept(&listener, &server_privkey, &[client_pubkey]).unwrap();
+            let msg = transport.read().unwrap();
+            transport.write(&msg).unwrap();
+            // Drop the connection
+            drop(transport);
+
+            let mut transport =
+                KKTransport::accept(&listener, &server_privkey, &[client_pubkey]).unwrap();
+            transport.write(b"Welcome back").unwrap();
+            assert_eq!(transport.read().unwrap(), b"Still there?".to_vec());
+            drop(transport);
+
+            // Someone else took over the server address
+            KKTransport::accept(&listener, &other_privkey, &[client_pubkey]).unwrap_err();
+        });
+
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(10),
+            exponential: true,
+        };
+        let mut transport =
+            ReconnectingKKTransport::connect(addr, &client_privkey, &server_pubkey, policy)
+                .unwrap();
+        transport.write(b"Ping").unwrap();
+        assert_eq!(transport.read().unwrap(), b"Ping".to_vec());
+
+        // The server dropped the connection, we transparently read from a new one
+        assert_eq!(transport.read().unwrap(), b"Welcome back".to_vec());
+        transport.write(b"Still there?").unwrap();
+
+        // We don't accept to talk to another key. It can't complete the handshake and drops
+        // the connection, until we give up.
+        let err = transport.read().unwrap_err();
+        assert!(err.is_io(), "{:?}", err);
+        assert!(!transport.is_connected());
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_reconnecting_rotate_key() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let (new_client_pubkey, new_client_privkey) = generate_keypair();
+        let (_, unknown_privkey) = generate_keypair();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_thread = thread::spawn(move || {
+            let client_keys = [client_pubkey, new_client_pubkey];
+            let mut old_transport =
+                KKTransport::accept(&listener, &server_privkey, &client_keys).unwrap();
+            assert_eq!(old_transport.read().unwrap(), b"before".to_vec());
+            old_transport.write(b"in flight").unwrap();
+
+            let mut transport =
+                KKTransport::accept(&listener, &server_privkey, &client_keys).unwrap();
+            assert_eq!(transport.remote_static(), new_client_pubkey);
+            // The old connection is closed once we got all its messages
+            assert_eq!(old_transport.read().unwrap(), b"last one".to_vec());
+            assert!(old_transport.read().unwrap_err().is_closed());
+            drop(old_transport);
+            assert_eq!(transport.read().unwrap(), b"after".to_vec());
+            transport.write(b"reply").unwrap();
+
+            // A key we don't know is refused
+            KKTransport::accept(&listener, &server_privkey, &client_keys).unwrap_err();
+            assert_eq!(transport.read().unwrap(), b"still there".to_vec());
+        });
+
+        let policy = RetryPolicy::no_retry();
+        let mut transport =
+            ReconnectingKKTransport::connect(addr, &client_privkey, &server_pubkey, policy)
+                .unwrap();
+        transport.write(b"before").unwrap();
+        transport.write(b"last one").unwrap();
+        transport.rotate_key(&new_client_privkey).unwrap();
+        // Nothing was lost across the switch
+        assert_eq!(transport.read().unwrap(), b"in flight".to_vec());
+        transport.write(b"after").unwrap();
+        assert_eq!(transport.read().unwrap(), b"reply".to_vec());
+
+        match transport.rotate_key(&unknown_privkey) {
+            Err(ConnectError::HandshakeRejected(_)) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(transport.is_connected());
+        transport.write(b"still there").unwrap();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_server_serve() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let (_, stranger_privkey) = generate_keypair();
+
+        let server = Arc::new(
+            KKServer::bind(
+                "127.0.0.1:0".parse().unwrap(),
+                server_privkey,
+                vec![client_pubkey],
+            )
+            .unwrap(),
+        );
+        let addr = server.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let serv = server.clone();
+        thread::spawn(move || {
+            serv.serve(|mut transport| {
+                let msg = transport.read().unwrap();
+                sender.lock().unwrap().send(msg).unwrap();
+            })
+            .unwrap();
+        });
+
+        KKTransport::connect(addr, &client_privkey, &server_pubkey)
+            .unwrap()
+            .write(b"First client")
+            .unwrap();
+        assert_eq!(receiver.recv().unwrap(), b"First client".to_vec());
+
+        // An unknown peer doesn't stop the server
+        KKTransport::connect(addr, &stranger_privkey, &server_pubkey).unwrap_err();
+
+        KKTransport::connect(addr, &client_privkey, &server_pubkey)
+            .unwrap()
+            .write(b"Second client")
+            .unwrap();
+        assert_eq!(receiver.recv().unwrap(), b"Second client".to_vec());
+        assert_eq!(server.failed_handshakes(), 1);
+    }
+
+    #[test]
+    fn test_server_serve_concurrently() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+
+        let server = Arc::new(
+            KKServer::bind(
+                "127.0.0.1:0".parse().unwrap(),
+                server_privkey,
+                vec![client_pubkey],
+            )
+            .unwrap(),
+        );
+        let addr = server.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let serv = server.clone();
+        thread::spawn(move || {
+            serv.serve(|mut transport| {
+                let msg = transport.read().unwrap();
+                sender.lock().unwrap().send(msg).unwrap();
+            })
+            .unwrap();
+        });
+
+        // A peer which connects but never sends its handshake message doesn't hold back the
+        // next one
+        let _idle = TcpStream::connect(addr).unwrap();
+        KKTransport::connect(addr, &client_privkey, &server_pubkey)
+            .unwrap()
+            .write(b"Not blocked")
+            .unwrap();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            b"Not blocked".to_vec()
+        );
+        assert_eq!(server.failed_handshakes(), 0);
+    }
+
+    #[test]
+    fn test_server_update_peers() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let (other_pubkey, other_privkey) = generate_keypair();
+
+        let server =
+            KKServer::bind("127.0.0.1:0".parse().unwrap(), server_privkey, vec![]).unwrap();
+        let addr = server.local_addr().unwrap();
+        let connect = |privkey: &SecretKey| {
+            let privkey = privkey.clone();
+            thread::spawn(move || KKTransport::connect(addr, &privkey, &server_pubkey))
+        };
+
+        let cli_thread = connect(&client_privkey);
+        assert!(server.accept().unwrap_err().is_unknown_peer());
+        cli_thread.join().unwrap().unwrap_err();
+
+        server.add_peer(client_pubkey);
+        server.add_peer(client_pubkey);
+        assert_eq!(server.peers(), vec![client_pubkey]);
+        let cli_thread = connect(&client_privkey);
+        let mut server_transport = server.accept().unwrap();
+        let mut client_transport = cli_thread.join().unwrap().unwrap();
+
+        // Removing it doesn't affect the established connection, but the next one fails
+        assert!(server.remove_peer(&client_pubkey));
+        assert!(!server.remove_peer(&client_pubkey));
+        client_transport.write(b"Still there").unwrap();
+        assert_eq!(server_transport.read().unwrap(), b"Still there".to_vec());
+        assert_eq!(server_transport.remote_static(), client_pubkey);
+        let cli_thread = connect(&client_privkey);
+        assert!(server.accept().unwrap_err().is_unknown_peer());
+        cli_thread.join().unwrap().unwrap_err();
+
+        server.replace_peers(vec![other_pubkey]);
+        let cli_thread = connect(&other_privkey);
+        server.accept().unwrap();
+        cli_thread.join().unwrap().unwrap();
+        assert_eq!(server.failed_handshakes(), 2);
+    }
+
+    #[test]
+    fn test_mock_transport() {
+        let (mut client, mut server) = MockTransport::pair();
+
+        let client_thread = thread::spawn(move || {
+            for _ in 0..3 {
+                client.write(b"ping").unwrap();
+                assert_eq!(client.read().unwrap(), b"pong".to_vec());
+            }
+        });
+        assert_eq!(pong(&mut server).unwrap(), 3);
+        client_thread.join().unwrap();
+
+        // Writing to a dropped end fails
+        server.write(b"ping").unwrap_err();
+
+        // The same handler works with a real transport
+        let (mut client_transport, mut server_transport) = transport_pair();
+        let server_thread = thread::spawn(move || pong(&mut server_transport).unwrap());
+        client_transport.write(b"ping").unwrap();
+        assert_eq!(client_transport.read().unwrap(), b"pong".to_vec());
+        client_transport.shutdown().unwrap();
+        assert_eq!(server_thread.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_transport_connect_timeout() {
+        let ((_, client_privkey), (server_pubkey, _)) = (generate_keypair(), generate_keypair());
+
+        // Unroutable address
+        let start = std::time::Instant::now();
+        KKTransport::connect_with_timeout(
+            "10.255.255.1:9".parse().unwrap(),
+            &client_privkey,
+            &server_pubkey,
+            Duration::from_millis(100),
+        )
+        .unwrap_err();
+        assert!(start.elapsed() < DEFAULT_CONNECT_TIMEOUT / 2);
+
+        let (client_transport, _) = transport_pair();
+        assert_eq!(client_transport.config(), TransportConfig::default());
+    }
+
+    #[test]
+    fn test_transport_connect_to_host() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+
+        // 'localhost' may resolve to '::1' first, on which nobody listens
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server_thread = thread::spawn(move || {
+            let mut transport =
+                KKTransport::accept(&listener, &server_privkey, &[client_pubkey]).unwrap();
+            let msg = transport.read().unwrap();
+            transport.write(&msg).unwrap();
+        });
+
+        let mut transport =
+            KKTransport::connect_to_host("localhost", port, &client_privkey, &server_pubkey)
+                .unwrap();
+        transport.write(b"Ping").unwrap();
+        assert_eq!(transport.read().unwrap(), b"Ping".to_vec());
+        server_thread.join().unwrap();
+
+        // Nobody listens anymore, we get the error for the last address
+        let err = KKTransport::connect_to_host("localhost", port, &client_privkey, &server_pubkey)
+            .unwrap_err();
+        assert!(err.is_io());
+
+        // Invalid host
+        KKTransport::connect_to_host("", port, &client_privkey, &server_pubkey).unwrap_err();
+    }
+
+    #[test]
+    fn test_transport_retry_policy() {
+        let policy = RetryPolicy {
+            max_attempts: 4,
+            backoff: Duration::from_millis(100),
+            exponential: true,
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert!(policy.delay(1000) > policy.delay(3));
+        let policy = RetryPolicy {
+            exponential: false,
+            ..policy
+        };
+        assert_eq!(policy.delay(3), Duration::from_millis(100));
+
+        // With a single attempt we don't sleep before failing
+        let (_client_transport, mut server_transport) = transport_pair();
+        server_transport.set_retry_policy(RetryPolicy {
+            max_attempts: 1,
+            backoff: Duration::from_secs(10),
+            exponential: false,
+        });
+        server_transport
+            .stream
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let start = std::time::Instant::now();
+        assert!(server_transport.read().unwrap_err().is_io());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // With more attempts, we sleep between each of them
+        server_transport.set_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(50),
+            exponential: true,
+        });
+        let start = std::time::Instant::now();
+        assert!(server_transport.read().unwrap_err().is_io());
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(
+            server_transport.config().retry,
+            RetryPolicy {
+                max_attempts: 3,
+                backoff: Duration::from_millis(50),
+                exponential: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_transport_flush() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+
+        for i in 0..10u8 {
+            client_transport.write(&[i; 100]).unwrap();
+            client_transport.flush().unwrap();
+            assert_eq!(server_transport.read().unwrap(), vec![i; 100]);
+        }
+    }
+
+    #[test]
+    fn test_transport_messages() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+
+        let msgs = vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()];
+        for msg in &msgs {
+            client_transport.write(msg).unwrap();
+        }
+        // Ends on unclean EOF
+        drop(client_transport);
+        let received: Vec<Vec<u8>> = server_transport
+            .messages()
+            .map(|msg| msg.unwrap())
+            .collect();
+        assert_eq!(received, msgs);
+
+        // And on graceful close
+        let (mut client_transport, mut server_transport) = transport_pair();
+        for msg in &msgs {
+            client_transport.write(msg).unwrap();
+        }
+        client_transport.shutdown().unwrap();
+        let mut messages = server_transport.messages();
+        for msg in &msgs {
+            assert_eq!(&messages.next().unwrap().unwrap(), msg);
+        }
+        assert!(messages.next().is_none());
+        assert!(messages.next().is_none());
+    }
+
+    #[test]
+    fn test_bind_listener() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), 1).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cli_thread = thread::spawn(move || {
+            let mut transport = KKTransport::connect(addr, &client_privkey, &server_pubkey)?;
+            transport.read()
+        });
+        let server_transport =
+            KKTransport::accept(&listener, &server_privkey, &[client_pubkey]).unwrap();
+        // Close from the server side first, so its end of the connection is in TIME_WAIT
+        server_transport.shutdown().unwrap();
+        assert!(cli_thread.join().unwrap().unwrap_err().is_closed());
+        drop(listener);
+
+        bind_listener(addr, DEFAULT_BACKLOG).unwrap();
+    }
+
+    #[test]
+    fn test_transport_read_until() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+
+        // The client doesn't send anything
+        let start = Instant::now();
+        let err = server_transport
+            .read_until(start + Duration::from_millis(50))
+            .unwrap_err();
+        match err {
+            Error::Transport(e) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_secs(1));
+        // The previous timeout was restored
+        assert_eq!(server_transport.stream.read_timeout().unwrap(), None);
+
+        // A partially received message is completed by the next read
+        let msg = client_transport
+            .channel
+            .encrypt_message(&[FRAME_DATA, 42])
+            .unwrap();
+        client_transport.stream.write_all(&msg.0[..10]).unwrap();
+        server_transport
+            .read_until(Instant::now() + Duration::from_millis(50))
+            .unwrap_err();
+        client_transport.stream.write_all(&msg.0[10..]).unwrap();
+        assert_eq!(
+            server_transport
+                .read_until(Instant::now() + Duration::from_secs(10))
+                .unwrap(),
+            vec![42]
+        );
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+        let config = TransportConfig {
+            sequence_numbers: true,
+            ..TransportConfig::default()
+        };
+        client_transport.set_config(config);
+        server_transport.set_config(config);
+
+        client_transport.write(b"first").unwrap();
+        client_transport.write(b"second").unwrap();
+        assert_eq!(server_transport.read().unwrap(), b"first".to_vec());
+        assert_eq!(server_transport.read().unwrap(), b"second".to_vec());
+        assert_eq!(server_transport.stats().plaintext_bytes_recv, 11);
+
+        // A message with an already seen sequence number is refused
+        let mut plaintext = vec![FRAME_DATA];
+        plaintext.extend_from_slice(&1u64.to_be_bytes());
+        plaintext.extend_from_slice(b"second");
+        let msg = client_transport
+            .channel
+            .encrypt_message(&plaintext)
+            .unwrap();
+        client_transport.stream.write_all(&msg.0).unwrap();
+        match server_transport.read() {
+            Err(Error::ReplayDetected) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        // A message without a sequence number is invalid
+        let msg = client_transport
+            .channel
+            .encrypt_message(&[FRAME_DATA, 42])
+            .unwrap();
+        client_transport.stream.write_all(&msg.0).unwrap();
+        match server_transport.read() {
+            Err(Error::InvalidFrame) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_write_acked() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+        let config = TransportConfig {
+            acks: true,
+            sequence_numbers: true,
+            ..TransportConfig::default()
+        };
+        client_transport.set_config(config);
+        server_transport.set_config(config);
+
+        let server_thread = thread::spawn(move || {
+            assert_eq!(server_transport.read().unwrap(), b"acked".to_vec());
+            // A message sent while the other end is waiting for the ack is kept
+            server_transport.write(b"concurrent").unwrap();
+            assert_eq!(server_transport.read().unwrap(), b"second".to_vec());
+            assert_eq!(server_transport.stats().msgs_recv, 2);
+            server_transport
+        });
+        client_transport.write_acked(b"acked").unwrap();
+        client_transport.write_acked(b"second").unwrap();
+        assert_eq!(client_transport.read().unwrap(), b"concurrent".to_vec());
+        let mut server_transport = server_thread.join().unwrap();
+
+        // A message kept while waiting for a control message is only acknowledged once the
+        // application reads it
+        client_transport
+            .write_frame(FRAME_DATA_ACKED, b"kept")
+            .unwrap();
+        client_transport.write_frame(FRAME_PING, &[]).unwrap();
+        assert_eq!(server_transport.read_control_frame().unwrap().0, FRAME_PING);
+        let mut buf = [0u8; 1];
+        client_transport.stream.set_nonblocking(true).unwrap();
+        match client_transport.stream.read(&mut buf) {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::WouldBlock),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        client_transport.stream.set_nonblocking(false).unwrap();
+        assert_eq!(server_transport.read().unwrap(), b"kept".to_vec());
+        assert_eq!(client_transport.read_control_frame().unwrap().0, FRAME_ACK);
+
+        // The other end must enable them
+        server_transport.set_config(TransportConfig::default());
+        let server_thread = thread::spawn(move || {
+            assert!(matches!(server_transport.read(), Err(Error::InvalidFrame)));
+        });
+        client_transport.write_acked(b"refused").unwrap_err();
+        server_thread.join().unwrap();
+
+        // The message is delivered even if the acknowledgement can't be written
+        let (mut client_transport, mut server_transport) = transport_pair();
+        server_transport.set_config(config);
+        client_transport.set_config(config);
+        client_transport
+            .write_frame(FRAME_DATA_ACKED, b"delivered")
+            .unwrap();
+        server_transport.stream.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(server_transport.read().unwrap(), b"delivered".to_vec());
+        assert!(!server_transport.write_pending.is_empty());
+    }
+
+    #[test]
+    fn test_raw_stream() {
+        let (mut client_transport, server_transport) = transport_pair();
+
+        let client_addr = client_transport
+            .as_raw_stream()
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        assert_eq!(
+            server_transport
+                .as_raw_stream()
+                .unwrap()
+                .peer_addr()
+                .unwrap(),
+            client_addr
+        );
+        assert_eq!(
+            client_transport
+                .raw_stream_mut()
+                .unwrap()
+                .peer_addr()
+                .unwrap(),
+            server_transport
+                .as_raw_stream()
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            client_transport.as_raw_fd(),
+            client_transport.as_raw_stream().unwrap().as_raw_fd()
+        );
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let mut server = KKServer::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            server_privkey,
+            vec![client_pubkey],
+        )
+        .unwrap();
+        server.set_handshake_timeout(Duration::from_millis(300));
+        let addr = server.local_addr().unwrap();
+
+        // A peer dribbling the first handshake message is cut off, even though it sends
+        // something more often than the timeout
+        let (_, msg_1) = KKHandshakeActOne::initiator(
+            &client_privkey,
+            &server_pubkey,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .unwrap();
+        let client_thread = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            for byte in msg_1.0.iter() {
+                if stream.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        let start = Instant::now();
+        match server.accept().unwrap_err() {
+            Error::Transport(e) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(300) && elapsed < Duration::from_secs(2));
+        assert_eq!(server.failed_handshakes(), 1);
+        client_thread.join().unwrap();
+
+        // A well-behaved peer is not affected, and the stream doesn't keep the timeouts
+        let client_thread = thread::spawn(move || {
+            KKTransport::connect(addr, &client_privkey, &server_pubkey).unwrap()
+        });
+        let server_transport = server.accept().unwrap();
+        assert_eq!(server_transport.stream.read_timeout().unwrap(), None);
+        let client_transport = client_thread.join().unwrap();
+        assert_eq!(client_transport.stream.read_timeout().unwrap(), None);
+    }
+
+    #[test]
+    fn test_plain_handshake_timeout() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let timeout = Duration::from_millis(300);
+
+        // A client which never sends its first message
+        let client_thread = thread::spawn(move || {
+            let _stream = TcpStream::connect(addr).unwrap();
+            thread::sleep(Duration::from_secs(1));
+        });
+        let start = Instant::now();
+        match KKTransport::accept_with_timeout(
+            &listener,
+            &server_privkey,
+            &[client_pubkey],
+            timeout,
+        )
+        .unwrap_err()
+        {
+            Error::Transport(e) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        client_thread.join().unwrap();
+
+        // A server which never answers
+        let server_thread = thread::spawn(move || {
+            let _stream = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(1));
+        });
+        let start = Instant::now();
+        match KKTransport::connect_with_timeout(addr, &client_privkey, &server_pubkey, timeout)
+            .unwrap_err()
+        {
+            Error::Transport(e) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        server_thread.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_handshake_deadline() {
+        let ((client_pubkey, _), (_, server_privkey)) = (generate_keypair(), generate_keypair());
+        let (stream, _peer) = UnixStream::pair().unwrap();
+
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(300);
+        match with_deadline(&stream, deadline, None, |stream| {
+            handshake_responder(stream, &server_privkey, &[client_pubkey])
+        })
+        .unwrap_err()
+        {
+            Error::Transport(e) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(stream.read_timeout().unwrap(), None);
+    }
+
+    #[test]
+    fn test_transport_prologue() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A client without the server's prologue can't connect
+        let client_thread = thread::spawn(move || {
+            KKTransport::connect(addr, &client_privkey, &server_pubkey).unwrap_err();
+            KKTransportBuilder::new()
+                .prologue(b"mainnet")
+                .connect(addr, &client_privkey, &server_pubkey)
+                .unwrap()
+        });
+        let builder = KKTransportBuilder::new().prologue(b"mainnet");
+        assert!(builder
+            .accept(&listener, &server_privkey, &[client_pubkey])
+            .unwrap_err()
+            .is_unknown_peer());
+        let mut server_transport = builder
+            .accept(&listener, &server_privkey, &[client_pubkey])
+            .unwrap();
+        client_thread.join().unwrap().write(b"Hello").unwrap();
+        assert_eq!(server_transport.read().unwrap(), b"Hello".to_vec());
+    }
+
+    #[test]
+    fn test_transport_builder() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let policy = RetryPolicy::no_retry();
+
+        let server_thread = thread::spawn(move || {
+            KKTransportBuilder::new()
+                .prologue(b"builder")
+                .max_message_size(1024)
+                .accept(&listener, &server_privkey, &[client_pubkey])
+                .unwrap()
+        });
+        let mut client_transport = KKTransportBuilder::new()
+            .connect_timeout(Duration::from_secs(1))
+            .read_timeout(Some(Duration::from_secs(5)))
+            .write_timeout(Some(Duration::from_secs(6)))
+            .nodelay(false)
+            .keepalive(Some(Duration::from_secs(60)))
+            .retry_policy(policy)
+            .prologue(b"builder")
+            .connect(addr, &client_privkey, &server_pubkey)
+            .unwrap();
+        let mut server_transport = server_thread.join().unwrap();
+
+        let client_stream = client_transport.as_raw_stream().unwrap();
+        assert_eq!(
+            client_stream.read_timeout().unwrap(),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            client_stream.write_timeout().unwrap(),
+            Some(Duration::from_secs(6))
+        );
+        assert!(!client_transport.nodelay().unwrap());
+        assert!(client_transport.keepalive().unwrap());
+        assert_eq!(client_transport.config().retry, policy);
+        assert_eq!(
+            client_transport.max_message_size(),
+            NOISE_PLAINTEXT_MAX_SIZE
+        );
+
+        // The defaults are untouched on the server side
+        let server_stream = server_transport.as_raw_stream().unwrap();
+        assert_eq!(server_stream.read_timeout().unwrap(), None);
+        assert!(server_transport.nodelay().unwrap());
+        assert!(!server_transport.keepalive().unwrap());
+        assert_eq!(server_transport.config(), TransportConfig::default());
+        assert_eq!(server_transport.max_message_size(), 1024);
+
+        client_transport.write(b"hello").unwrap();
+        assert_eq!(server_transport.read().unwrap(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn test_transport_ping() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+        client_transport.set_config(TransportConfig {
+            ping: Some(PingPolicy {
+                interval: Duration::from_millis(50),
+                timeout: Duration::from_millis(200),
+            }),
+            ..TransportConfig::default()
+        });
+
+        // The server answers the pings sent while the client waits for a message
+        let server_thread = thread::spawn(move || {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(300) {
+                server_transport
+                    .read_until(Instant::now() + Duration::from_millis(10))
+                    .unwrap_err();
+            }
+            server_transport.write(b"late").unwrap();
+            server_transport
+        });
+        assert_eq!(client_transport.read().unwrap(), b"late".to_vec());
+        let server_transport = server_thread.join().unwrap();
+        // Several pings went through
+        assert!(
+            client_transport.stats().bytes_sent >= 3 * encrypted_msg_size(FRAME_TYPE_SIZE) as u64
+        );
+        assert_eq!(client_transport.stats().msgs_sent, 0);
+
+        // A peer which doesn't answer is considered dead
+        let start = Instant::now();
+        match client_transport.read().unwrap_err() {
+            Error::Transport(e) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_secs(2));
+        assert_eq!(client_transport.stream.read_timeout().unwrap(), None);
+        drop(server_transport);
+    }
+
+    #[test]
+    fn test_try_accept() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let (_, unknown_privkey) = generate_keypair();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // An unknown peer fails at the key matching, after sending the whole first message
+        let client_thread = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            let client_addr = stream.local_addr().unwrap();
+            KKTransport::from_stream_initiator(stream, &unknown_privkey, &server_pubkey)
+                .unwrap_err();
+            client_addr
+        });
+        let failure =
+            KKTransport::try_accept(&listener, &server_privkey, &[client_pubkey]).unwrap_err();
+        let client_addr = client_thread.join().unwrap();
+        assert_eq!(failure.stage, HandshakeStage::KeyMatch);
+        assert_eq!(failure.bytes_read, KK_MSG_1_SIZE);
+        assert_eq!(failure.peer_addr, Some(client_addr));
+        assert!(failure.error.is_unknown_peer());
+        assert!(failure.to_string().contains("KeyMatch"));
+
+        // A peer leaving in the middle of the first message
+        let client_thread = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(&[0u8; 10]).unwrap();
+        });
+        let failure =
+            KKTransport::try_accept(&listener, &server_privkey, &[client_pubkey]).unwrap_err();
+        client_thread.join().unwrap();
+        assert_eq!(failure.stage, HandshakeStage::ActOneRead);
+        assert_eq!(failure.bytes_read, 10);
+        assert!(failure.error.is_io());
+
+        // A valid one
+        let client_thread = thread::spawn(move || {
+            KKTransport::connect(addr, &client_privkey, &server_pubkey).unwrap();
+        });
+        KKTransport::try_accept(&listener, &server_privkey, &[client_pubkey]).unwrap();
+        client_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_kk_responder_accept() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let (_, unknown_privkey) = generate_keypair();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responder = KKResponder::new(
+            &server_privkey,
+            &[client_pubkey],
+            PROTOCOL_VERSIONS,
+            &[],
+            CipherSuite::default(),
+        )
+        .unwrap();
+
+        let client_thread = thread::spawn(move || {
+            for i in 0..3u8 {
+                let mut transport =
+                    KKTransport::connect(addr, &client_privkey, &server_pubkey).unwrap();
+                transport.write(&[i]).unwrap();
+            }
+            KKTransport::connect(addr, &unknown_privkey, &server_pubkey).unwrap_err();
+        });
+
+        for i in 0..3u8 {
+            let (stream, _) = listener.accept().unwrap();
+            let mut transport = responder.accept(stream).unwrap();
+            assert_eq!(transport.remote_static(), client_pubkey);
+            assert_eq!(transport.read().unwrap(), vec![i]);
+        }
+        let (stream, _) = listener.accept().unwrap();
+        assert!(responder.accept(stream).unwrap_err().is_unknown_peer());
+        client_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_accept_with_cancel() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+
+        // Not cancelled
+        let client_thread = thread::spawn(move || {
+            KKTransport::connect(addr, &client_privkey, &server_pubkey).unwrap();
+        });
+        KKTransport::accept_with_cancel(&listener, &server_privkey, &[client_pubkey], &cancel)
+            .unwrap();
+        client_thread.join().unwrap();
+
+        // Cancelled while waiting for a connection
+        let cancel_thread = {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                cancel.store(true, Ordering::Relaxed);
+            })
+        };
+        let err =
+            KKTransport::accept_with_cancel(&listener, &server_privkey, &[client_pubkey], &cancel)
+                .unwrap_err();
+        assert!(matches!(err, Error::Cancelled));
+        cancel_thread.join().unwrap();
+
+        // Cancelled while the handshake is pending
+        cancel.store(false, Ordering::Relaxed);
+        let cancel_thread = {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                stream.write_all(&[0u8; 10]).unwrap();
+                thread::sleep(Duration::from_millis(200));
+                cancel.store(true, Ordering::Relaxed);
+                stream
+            })
+        };
+        let start = Instant::now();
+        let err =
+            KKTransport::accept_with_cancel(&listener, &server_privkey, &[client_pubkey], &cancel)
+                .unwrap_err();
+        assert!(matches!(err, Error::Cancelled));
+        assert!(start.elapsed() < DEFAULT_HANDSHAKE_TIMEOUT);
+        cancel_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_local_static() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let (other_server_pubkey, other_server_privkey) = generate_keypair();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client_thread = thread::spawn(move || {
+            let transport = KKTransport::connect(addr, &client_privkey, &server_pubkey).unwrap();
+            assert_eq!(transport.local_static(), client_pubkey);
+            assert_eq!(transport.remote_static(), server_pubkey);
+            client_privkey
+        });
+        let transport = KKTransport::accept(&listener, &server_privkey, &[client_pubkey]).unwrap();
+        assert_eq!(transport.local_static(), server_pubkey);
+        let client_privkey = client_thread.join().unwrap();
+
+        // A server with several identities can tell which one was used
+        let client_thread = thread::spawn(move || {
+            KKTransport::connect(addr, &client_privkey, &other_server_pubkey).unwrap();
+        });
+        let transport =
+            KKTransport::accept(&listener, &other_server_privkey, &[client_pubkey]).unwrap();
+        assert_eq!(transport.local_static(), other_server_pubkey);
+        client_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_try_connect() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+        let (_, unknown_privkey) = generate_keypair();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let timeout = Duration::from_secs(5);
+
+        // A peer answering garbage to our first message
+        let server_thread = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut [0u8; KK_MSG_1_SIZE]).unwrap();
+            stream.write_all(&[0x42; KK_VERSIONED_MSG_2_SIZE]).unwrap();
+            listener
+        });
+        match KKTransport::try_connect(addr, &client_privkey, &server_pubkey, timeout) {
+            Err(ConnectError::HandshakeRejected(Error::Handshake(_))) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        let listener = server_thread.join().unwrap();
+
+        // A peer which doesn't know our key
+        let server_thread = thread::spawn(move || {
+            KKTransport::accept(&listener, &server_privkey, &[client_pubkey]).unwrap_err();
+            KKTransport::accept(&listener, &server_privkey, &[client_pubkey]).unwrap();
+        });
+        let err =
+            KKTransport::try_connect(addr, &unknown_privkey, &server_pubkey, timeout).unwrap_err();
+        assert!(matches!(err, ConnectError::HandshakeRejected(_)));
+        assert!(err.to_string().starts_with("Handshake rejected"));
+        KKTransport::try_connect(addr, &client_privkey, &server_pubkey, timeout).unwrap();
+        server_thread.join().unwrap();
+
+        // Nobody listening anymore
+        let err =
+            KKTransport::try_connect(addr, &client_privkey, &server_pubkey, timeout).unwrap_err();
+        assert!(matches!(err, ConnectError::Tcp(_)));
+        assert!(Error::from(err).is_io());
+    }
+
+    // Once the accept queue of a listener is full, Linux drops the connection attempts so they
+    // time out as for an unreachable peer.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_try_connect_timeout() {
+        let ((_, client_privkey), (server_pubkey, _)) = (generate_keypair(), generate_keypair());
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), 0).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let timeout = Duration::from_millis(300);
+
+        // Connected, but never accepted hence never answered
+        match KKTransport::try_connect(addr, &client_privkey, &server_pubkey, timeout) {
+            Err(ConnectError::HandshakeReadTimeout) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        // Now the queue is full
+        match KKTransport::try_connect(addr, &client_privkey, &server_pubkey, timeout) {
+            Err(ConnectError::TcpTimeout) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        drop(listener);
+    }
+
+    #[test]
+    fn test_transport_pubkey() {
+        let (mut client_transport, mut server_transport) = transport_pair();
+        let (ephemeral_pubkey, _) = generate_keypair();
+
+        client_transport.write_pubkey(&ephemeral_pubkey).unwrap();
+        assert_eq!(server_transport.read_pubkey().unwrap(), ephemeral_pubkey);
+
+        // A message of the wrong size is refused, but the transport is still usable
+        for msg in [
+            &[0x42u8; KEY_SIZE - 1][..],
+            &[0x42u8; KEY_SIZE + 1][..],
+            &[],
+        ] {
+            client_transport.write(msg).unwrap();
+            match server_transport.read_pubkey() {
+                Err(Error::Noise(NoiseError::InvalidKey)) => {}
+                r => panic!("Unexpected result: {:?}", r),
+            }
+        }
+        client_transport.write_pubkey(&ephemeral_pubkey).unwrap();
+        assert_eq!(server_transport.read_pubkey().unwrap(), ephemeral_pubkey);
+    }
+
+    #[test]
+    fn test_transport_role() {
+        let (client_transport, server_transport) = transport_pair();
+        assert!(client_transport.is_initiator());
+        assert!(!server_transport.is_initiator());
+    }
 
     #[test]
     fn test_transport_kk() {
         let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
-            (gen_keypair(), gen_keypair());
+            (generate_keypair(), generate_keypair());
 
         let listener = TcpListener::bind("127.0.0.1:0").unwrap();
         let addr = listener.local_addr().unwrap();
@@ -171,7 +5743,7 @@ mod tests {
                 KKTransport::connect(addr, &my_noise_privkey, &their_noise_pubkey)
                     .expect("Client channel connecting");
             let msg = "Test message".as_bytes();
-            cli_channel.write(&msg).expect("Sending test message");
+            cli_channel.write(msg).expect("Sending test message");
             msg
         });
 
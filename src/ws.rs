@@ -0,0 +1,231 @@
+//! WebSocket transport API
+//!
+//! This module provides the same authenticated and encrypted channel as [crate::transport],
+//! over a WebSocket connection (eg for peers behind an HTTP reverse proxy).
+//! Each Noise message, including its encrypted length prefix, is sent as a single binary
+//! WebSocket message. The length prefix is redundant with the WebSocket framing but we keep
+//! it so that the encryption and the nonces usage are identical to the TCP transport.
+//! Control messages are left to the WebSocket protocol (ping, pong and close frames) so, unlike
+//! the TCP transport, the plaintexts are not prefixed with a frame type.
+
+use crate::{
+    error::Error,
+    noise::{
+        CipherSuite, KKChannel, KKHandshakeActOne, KKHandshakeActTwo, KKMessageActOne,
+        KKMessageActTwo, PublicKey, SecretKey, PROTOCOL_VERSIONS,
+    },
+};
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+use tungstenite::{Message, WebSocket};
+
+/// Wrapper type for a WebSocket and KKChannel that automatically enforces authenticated and
+/// encrypted channels when communicating
+#[derive(Debug)]
+pub struct KKWsTransport<S: Read + Write = TcpStream> {
+    socket: WebSocket<S>,
+    channel: KKChannel,
+}
+
+// Convert an error from the WebSocket library
+fn ws_error(error: tungstenite::Error) -> Error {
+    match error {
+        tungstenite::Error::Io(e) => Error::Transport(e),
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            Error::ConnectionClosed
+        }
+        e => Error::Transport(io::Error::other(e)),
+    }
+}
+
+// Convert an error from the WebSocket opening handshake
+fn ws_handshake_error<E: std::fmt::Display>(error: E) -> Error {
+    Error::Transport(io::Error::other(format!(
+        "WebSocket handshake failed: {}",
+        error
+    )))
+}
+
+impl KKWsTransport<TcpStream> {
+    /// Connect to the WebSocket server at given address, and enact Noise handshake with
+    /// given private key.
+    pub fn connect(
+        addr: SocketAddr,
+        my_noise_privkey: &SecretKey,
+        their_noise_pubkey: &PublicKey,
+    ) -> Result<KKWsTransport, Error> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let url = format!("ws://{}/", addr);
+        KKWsTransport::from_stream_initiator(stream, &url, my_noise_privkey, their_noise_pubkey)
+    }
+
+    /// Accept an incoming WebSocket connection and immediately perform the noise KK
+    /// handshake as a responder with our single private key and a set of possible public
+    /// key for them.
+    pub fn accept(
+        listener: &TcpListener,
+        my_noise_privkey: &SecretKey,
+        their_possible_pubkeys: &[PublicKey],
+    ) -> Result<KKWsTransport, Error> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        KKWsTransport::from_stream_responder(stream, my_noise_privkey, their_possible_pubkeys)
+    }
+}
+
+impl<S: Read + Write> KKWsTransport<S> {
+    /// Open a WebSocket to the given `url` over an already connected stream, then enact the
+    /// Noise handshake as an initiator with given private key.
+    pub fn from_stream_initiator(
+        stream: S,
+        url: &str,
+        my_noise_privkey: &SecretKey,
+        their_noise_pubkey: &PublicKey,
+    ) -> Result<KKWsTransport<S>, Error> {
+        let (mut socket, _) = tungstenite::client(url, stream).map_err(ws_handshake_error)?;
+
+        let (cli_act_1, msg_1) = KKHandshakeActOne::initiator(
+            my_noise_privkey,
+            their_noise_pubkey,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .map_err(Error::Handshake)?;
+        socket
+            .send(Message::Binary(msg_1.0.to_vec()))
+            .map_err(ws_error)?;
+
+        let msg_2 =
+            KKMessageActTwo::from_bytes(&read_binary(&mut socket)?).map_err(Error::Handshake)?;
+        let cli_act_2 =
+            KKHandshakeActTwo::initiator(cli_act_1, &msg_2).map_err(Error::Handshake)?;
+        let channel = KKChannel::from_handshake(cli_act_2).map_err(Error::Handshake)?;
+
+        Ok(KKWsTransport { socket, channel })
+    }
+
+    /// Accept a WebSocket over an already accepted stream, then perform the noise KK
+    /// handshake as a responder with our single private key and a set of possible public key
+    /// for them.
+    pub fn from_stream_responder(
+        stream: S,
+        my_noise_privkey: &SecretKey,
+        their_possible_pubkeys: &[PublicKey],
+    ) -> Result<KKWsTransport<S>, Error> {
+        let mut socket = tungstenite::accept(stream).map_err(ws_handshake_error)?;
+
+        let msg_1 =
+            KKMessageActOne::from_bytes(&read_binary(&mut socket)?).map_err(Error::Handshake)?;
+        let serv_act_1 = KKHandshakeActOne::responder(
+            my_noise_privkey,
+            their_possible_pubkeys,
+            &msg_1,
+            PROTOCOL_VERSIONS,
+            CipherSuite::default(),
+        )
+        .map_err(Error::Handshake)?;
+        let (serv_act_2, msg_2) =
+            KKHandshakeActTwo::responder(serv_act_1).map_err(Error::Handshake)?;
+        let channel = KKChannel::from_handshake(serv_act_2).map_err(Error::Handshake)?;
+        socket
+            .send(Message::Binary(msg_2.0.to_vec()))
+            .map_err(ws_error)?;
+
+        Ok(KKWsTransport { socket, channel })
+    }
+
+    /// Write a message to the other end of the encrypted communication channel, as a single
+    /// WebSocket message.
+    pub fn write(&mut self, msg: &[u8]) -> Result<(), Error> {
+        let encrypted_msg = self.channel.encrypt_message(msg)?;
+        self.socket
+            .send(Message::Binary(encrypted_msg.0))
+            .map_err(ws_error)
+    }
+
+    /// Read a message from the other end of the encrypted communication channel. Returns
+    /// [Error::ConnectionClosed] once the peer closed the WebSocket.
+    pub fn read(&mut self) -> Result<Vec<u8>, Error> {
+        let msg = read_binary(&mut self.socket)?;
+        self.channel.decrypt_framed(&msg).map_err(|e| e.into())
+    }
+
+    /// Close the WebSocket. The other end gets an [Error::ConnectionClosed] on its next read.
+    /// This blocks until the other end acknowledged it and closed the connection.
+    pub fn close(mut self) -> Result<(), Error> {
+        self.socket.close(None).map_err(ws_error)?;
+        // Wait for the peer to acknowledge it
+        loop {
+            match self.socket.read() {
+                Ok(_) => continue,
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+                Err(e) => return Err(ws_error(e)),
+            }
+        }
+    }
+
+    /// Get the static public key of the peer
+    pub fn remote_static(&self) -> PublicKey {
+        self.channel.remote_static()
+    }
+}
+
+// Read the next binary WebSocket message, skipping the control ones
+fn read_binary<S: Read + Write>(socket: &mut WebSocket<S>) -> Result<Vec<u8>, Error> {
+    loop {
+        match socket.read().map_err(ws_error)? {
+            Message::Binary(msg) => return Ok(msg),
+            // Pings are answered by the library
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+            Message::Close(_) => {
+                // Send our reply to complete the closing handshake. The connection is
+                // closed anyways so this may fail.
+                let _ = socket.flush();
+                return Err(Error::ConnectionClosed);
+            }
+            Message::Text(_) => return Err(Error::InvalidFrame),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::generate_keypair;
+    use std::thread;
+
+    #[test]
+    fn test_ws_transport() {
+        let ((client_pubkey, client_privkey), (server_pubkey, server_privkey)) =
+            (generate_keypair(), generate_keypair());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut transport =
+                KKWsTransport::accept(&listener, &server_privkey, &[client_pubkey]).unwrap();
+            assert_eq!(transport.remote_static(), client_pubkey);
+            let msg = transport.read().unwrap();
+            transport.write(&msg).unwrap();
+            transport.write(&[0u8; 1024]).unwrap();
+            assert!(transport.read().unwrap_err().is_closed());
+            drop(transport);
+
+            // An unknown peer is refused
+            let err = KKWsTransport::accept(&listener, &server_privkey, &[]).unwrap_err();
+            assert!(err.is_unknown_peer());
+        });
+
+        let mut transport = KKWsTransport::connect(addr, &client_privkey, &server_pubkey).unwrap();
+        transport.write(b"Echo").unwrap();
+        assert_eq!(transport.read().unwrap(), b"Echo".to_vec());
+        assert_eq!(transport.read().unwrap(), vec![0u8; 1024]);
+        transport.close().unwrap();
+
+        KKWsTransport::connect(addr, &client_privkey, &server_pubkey).unwrap_err();
+        server_thread.join().unwrap();
+    }
+}
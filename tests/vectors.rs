@@ -0,0 +1,194 @@
+//! Test vectors of the KK handshake and transport messages, to catch any unintended change to
+//! the wire format. Other implementations of the Noise specification
+//! (`Noise_KK_25519_ChaChaPoly_SHA256`) may check theirs against them.
+//! Only the first protocol version is offered, so the first handshake message's payload is
+//! [revault_net::noise::HANDSHAKE_MESSAGE] and the second one's is empty, as before the version
+//! negotiation. Transport messages are the encrypted big-endian length of the body followed by
+//! the encrypted body.
+//! These vectors are this crate's own output, they are not taken from another implementation.
+//!
+//! The static and ephemeral keys are fixed, so that every byte exchanged is reproducible. This
+//! needs the `fuzzing` feature: `cargo test --features fuzzing --test vectors`.
+//!
+//! To regenerate the vectors after an intended change, run
+//! `cargo test --features fuzzing --test vectors -- --ignored --nocapture`
+//! and paste the output below.
+
+use revault_net::noise::{
+    public_from_secret, CipherSuite, KKChannel, KKHandshakeActOne, KKHandshakeActTwo,
+    KKMessageActOne, KKMessageActTwo, SecretKey, PROTOCOL_VERSIONS,
+};
+
+const INITIATOR_STATIC: [u8; 32] = [1; 32];
+const RESPONDER_STATIC: [u8; 32] = [2; 32];
+const INITIATOR_EPHEMERAL: [u8; 32] = [3; 32];
+const RESPONDER_EPHEMERAL: [u8; 32] = [4; 32];
+const PROLOGUE: &[u8] = b"revault_net test vectors";
+
+const INITIATOR_PLAINTEXT: &[u8] = b"Hello from the initiator";
+const RESPONDER_PLAINTEXT: &[u8] = b"Hello from the responder";
+
+const INITIATOR_PUBKEY: &str = "a4e09292b651c278b9772c569f5fa9bb13d906b46ab68c9df9dc2b4409f8a209";
+const RESPONDER_PUBKEY: &str = "ce8d3ad1ccb633ec7b70c17814a5c76ecd029685050d344745ba05870e587d59";
+const MSG_ACT_ONE: &str = "5dfedd3b6bd47f6fa28ee15d969d5bb0ea53774d488bdaf9df1c6e0124b3ef22da57c18536b4b2fb74f744566e213e1590bcf2c5b2ff750dbdd664f3c1b1c365be57a4";
+const MSG_ACT_TWO: &str = "ac01b2209e86354fb853237b5de0f4fab13c7fcbf433a61c019369617fecf10b8031b9a4bfbdef833d7304f38ff0d352";
+const HANDSHAKE_HASH: &str = "47ae5c8dbe888561a7f37352206bd5b8a4b2fc38692a82596065be91026d0315";
+const INITIATOR_MESSAGE: &str = "e410c8b184e4a2aa6a8b31d0bd098ed96839ee89f94f32253fa1b170264cd08eb3a4daf19695c03b912d40cb3d673a46f2e7326029eb97ddeda3";
+const RESPONDER_MESSAGE: &str = "1e09a191b070e12063e31dec138c8a88c92ebd3535d4e0bdf267504e3f8edde2471755ab08e9c1ac574ba43bce382fe8805c2f799b144b732aea";
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+struct Vectors {
+    initiator_pubkey: Vec<u8>,
+    responder_pubkey: Vec<u8>,
+    msg_act_one: Vec<u8>,
+    msg_act_two: Vec<u8>,
+    handshake_hash: Vec<u8>,
+    initiator_message: Vec<u8>,
+    responder_message: Vec<u8>,
+}
+
+// Run the handshake and exchange a message in each direction, checking both ends agree
+fn generate() -> Vectors {
+    let (init_privkey, resp_privkey) = (SecretKey(INITIATOR_STATIC), SecretKey(RESPONDER_STATIC));
+    let (init_pubkey, resp_pubkey) = (
+        public_from_secret(&init_privkey),
+        public_from_secret(&resp_privkey),
+    );
+
+    let (init_act_1, msg_1) = KKHandshakeActOne::initiator_with_ephemeral(
+        &init_privkey,
+        &resp_pubkey,
+        &INITIATOR_EPHEMERAL,
+        &[0],
+        PROLOGUE,
+        CipherSuite::default(),
+    )
+    .unwrap();
+    let msg_act_one = msg_1.as_bytes().to_vec();
+    let resp_act_1 = KKHandshakeActOne::responder_with_ephemeral(
+        &resp_privkey,
+        &init_pubkey,
+        &KKMessageActOne::from_bytes(&msg_act_one).unwrap(),
+        &RESPONDER_EPHEMERAL,
+        PROTOCOL_VERSIONS,
+        PROLOGUE,
+        CipherSuite::default(),
+    )
+    .unwrap();
+    let (resp_act_2, msg_2) = KKHandshakeActTwo::responder(resp_act_1).unwrap();
+    let msg_act_two = msg_2.as_bytes().to_vec();
+    let init_act_2 = KKHandshakeActTwo::initiator(
+        init_act_1,
+        &KKMessageActTwo::from_bytes(&msg_act_two).unwrap(),
+    )
+    .unwrap();
+
+    let mut initiator = KKChannel::from_handshake(init_act_2).unwrap();
+    let mut responder = KKChannel::from_handshake(resp_act_2).unwrap();
+    assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+
+    let initiator_message = initiator.encrypt_message(INITIATOR_PLAINTEXT).unwrap().0;
+    assert_eq!(
+        responder.decrypt_framed(&initiator_message).unwrap(),
+        INITIATOR_PLAINTEXT
+    );
+    let responder_message = responder.encrypt_message(RESPONDER_PLAINTEXT).unwrap().0;
+    assert_eq!(
+        initiator.decrypt_framed(&responder_message).unwrap(),
+        RESPONDER_PLAINTEXT
+    );
+
+    Vectors {
+        initiator_pubkey: init_pubkey.0.to_vec(),
+        responder_pubkey: resp_pubkey.0.to_vec(),
+        msg_act_one,
+        msg_act_two,
+        handshake_hash: initiator.handshake_hash().to_vec(),
+        initiator_message,
+        responder_message,
+    }
+}
+
+#[test]
+fn kk_test_vectors() {
+    let vectors = generate();
+
+    assert_eq!(to_hex(&vectors.initiator_pubkey), INITIATOR_PUBKEY);
+    assert_eq!(to_hex(&vectors.responder_pubkey), RESPONDER_PUBKEY);
+    assert_eq!(to_hex(&vectors.msg_act_one), MSG_ACT_ONE);
+    assert_eq!(to_hex(&vectors.msg_act_two), MSG_ACT_TWO);
+    assert_eq!(to_hex(&vectors.handshake_hash), HANDSHAKE_HASH);
+    assert_eq!(to_hex(&vectors.initiator_message), INITIATOR_MESSAGE);
+    assert_eq!(to_hex(&vectors.responder_message), RESPONDER_MESSAGE);
+}
+
+// The golden messages must be accepted by a fresh responder too
+#[test]
+fn kk_test_vectors_responder() {
+    let resp_act_1 = KKHandshakeActOne::responder_with_ephemeral(
+        &SecretKey(RESPONDER_STATIC),
+        &public_from_secret(&SecretKey(INITIATOR_STATIC)),
+        &KKMessageActOne::from_bytes(&from_hex(MSG_ACT_ONE)).unwrap(),
+        &RESPONDER_EPHEMERAL,
+        PROTOCOL_VERSIONS,
+        PROLOGUE,
+        CipherSuite::default(),
+    )
+    .unwrap();
+    let (resp_act_2, msg_2) = KKHandshakeActTwo::responder(resp_act_1).unwrap();
+    assert_eq!(to_hex(msg_2.as_bytes()), MSG_ACT_TWO);
+
+    let mut responder = KKChannel::from_handshake(resp_act_2).unwrap();
+    assert_eq!(to_hex(&responder.handshake_hash()), HANDSHAKE_HASH);
+    assert_eq!(
+        responder
+            .decrypt_framed(&from_hex(INITIATOR_MESSAGE))
+            .unwrap(),
+        INITIATOR_PLAINTEXT
+    );
+}
+
+#[test]
+#[ignore]
+fn print_test_vectors() {
+    let vectors = generate();
+
+    println!(
+        "const INITIATOR_PUBKEY: &str = \"{}\";",
+        to_hex(&vectors.initiator_pubkey)
+    );
+    println!(
+        "const RESPONDER_PUBKEY: &str = \"{}\";",
+        to_hex(&vectors.responder_pubkey)
+    );
+    println!(
+        "const MSG_ACT_ONE: &str = \"{}\";",
+        to_hex(&vectors.msg_act_one)
+    );
+    println!(
+        "const MSG_ACT_TWO: &str = \"{}\";",
+        to_hex(&vectors.msg_act_two)
+    );
+    println!(
+        "const HANDSHAKE_HASH: &str = \"{}\";",
+        to_hex(&vectors.handshake_hash)
+    );
+    println!(
+        "const INITIATOR_MESSAGE: &str = \"{}\";",
+        to_hex(&vectors.initiator_message)
+    );
+    println!(
+        "const RESPONDER_MESSAGE: &str = \"{}\";",
+        to_hex(&vectors.responder_message)
+    );
+}